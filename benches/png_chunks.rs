@@ -0,0 +1,106 @@
+//! PNG files with the image data split over many small IDAT chunks, the time per file should grow linearly with the
+//! number of chunks.
+//!
+//! Run with `cargo bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use png_decoder::{decode_png, tokenise_png_into, TokenStats};
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 256;
+
+fn crc_32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { 0xEDB88320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler_32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn push_chunk(png: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let crc_start = png.len();
+    png.extend(chunk_type);
+    png.extend(data);
+    let crc = crc_32(&png[crc_start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // stored deflate blocks, so the file is mostly chunk overhead to read rather than compressed data
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = data.chunks(65535).collect();
+    for (index, block) in blocks.iter().enumerate() {
+        zlib.push((index == blocks.len() - 1) as u8);
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend(*block);
+    }
+    zlib.extend(adler_32(data).to_be_bytes());
+    zlib
+}
+
+fn many_idat_png(idat_length: usize) -> Vec<u8> {
+    // 8 bit greyscale gradient, each row has filter type 0
+    let mut scanlines = Vec::with_capacity((WIDTH + 1) * HEIGHT);
+    for y in 0..HEIGHT {
+        scanlines.push(0);
+        scanlines.extend((0..WIDTH).map(|x| (x + y) as u8));
+    }
+
+    let mut png = vec![137, 80, 78, 71, 13, 10, 26, 10];
+    let mut ihdr = Vec::new();
+    ihdr.extend((WIDTH as u32).to_be_bytes());
+    ihdr.extend((HEIGHT as u32).to_be_bytes());
+    ihdr.extend([8, 0, 0, 0, 0]);
+    push_chunk(&mut png, b"IHDR", &ihdr);
+    for idat in zlib_stored(&scanlines).chunks(idat_length) {
+        push_chunk(&mut png, b"IDAT", idat);
+    }
+    push_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn bench_many_chunks(c: &mut Criterion) {
+    let mut decode = c.benchmark_group("decode_png");
+    for idat_length in [4096, 256, 16] {
+        let png = many_idat_png(idat_length);
+        let chunks = png.len() / (idat_length + 12);
+        decode.throughput(Throughput::Bytes(png.len() as u64));
+        decode.bench_with_input(BenchmarkId::new("idat_chunks", chunks), &png, |b, png| {
+            b.iter(|| decode_png(black_box(png)).unwrap())
+        });
+    }
+    decode.finish();
+
+    let mut tokenise = c.benchmark_group("tokenise_png_into");
+    tokenise.sample_size(10);
+    for idat_length in [4096, 256, 16] {
+        let png = many_idat_png(idat_length);
+        let chunks = png.len() / (idat_length + 12);
+        tokenise.throughput(Throughput::Bytes(png.len() as u64));
+        tokenise.bench_with_input(BenchmarkId::new("idat_chunks", chunks), &png, |b, png| {
+            b.iter(|| {
+                let mut stats = TokenStats::new();
+                tokenise_png_into(black_box(png), &mut stats).unwrap();
+                stats.total()
+            })
+        });
+    }
+    tokenise.finish();
+}
+
+criterion_group!(benches, bench_many_chunks);
+criterion_main!(benches);
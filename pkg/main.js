@@ -15,22 +15,60 @@ function load_png_data(evt) {
 }
 
 function get_bytes(id) {
+  // bits of the token and everything below it, which come straight after it in depth first order
+  let last_id = id + window.flat_tokens[id].size;
   let token_bits = document.getElementsByClassName("token-bits");
   let valid = [];
   for (let token of token_bits) {
-    if (token.getAttribute("data-token") == id) {
+    let owner = Number(token.getAttribute("data-token"));
+    if (owner >= id && owner < last_id) {
       valid.push(token);
     }
   }
   return valid;
 }
 
+function flatten_tokens(token, depth, flat) {
+  // depth first list of the token tree, returns the height of the token (0 for tokens without children)
+  let index = flat.length;
+  let entry = {token: token, depth: depth, size: 1, height: 0, collapsed: false, row: null};
+  flat.push(entry);
+  for (let child of token.children) {
+    let child_height = flatten_tokens(child, depth + 1, flat);
+    entry.height = Math.max(entry.height, child_height + 1);
+  }
+  entry.size = flat.length - index;
+  return entry.height;
+}
+
+function toggle_token(id) {
+  window.flat_tokens[id].collapsed = !window.flat_tokens[id].collapsed;
+
+  let flat = window.flat_tokens;
+  let i = 0;
+  while (i < flat.length) {
+    flat[i].row.style.display = "";
+    flat[i].row.querySelector(".token-toggle").innerText = flat[i].size == 1 ? "" : (flat[i].collapsed ? "\u25B8 " : "\u25BE ");
+    if (flat[i].collapsed) {
+      for (let j = i + 1; j < i + flat[i].size; j++) {
+        flat[j].row.style.display = "none";
+      }
+      i += flat[i].size;
+    } else {
+      i += 1;
+    }
+  }
+}
+
 function token_hover_over(id) {
   let tokens = get_bytes(id);
   for (let token of tokens) {
     token.classList.add("highlighted-token")
   }
-  moveByteToView(tokens[0]);
+  if (tokens.length > 0) {
+    // tokens that only mark a position have no bits
+    moveByteToView(tokens[0]);
+  }
 }
 
 function token_hover_out(id) {
@@ -61,7 +99,8 @@ function decode_data(evt) {
   }
 }
 
-function construct_token_row(token, i) {
+function construct_token_row(entry, i) {
+  let token = entry.token;
   let tr = document.createElement("tr");
   let td = document.createElement("td");
 
@@ -73,7 +112,19 @@ function construct_token_row(token, i) {
     token_hover_out(i)
   });
   divParent.classList.add("token-row");
-  divParent.classList.add("token-row-nest-" + token.nest_level);
+  // tokens without children are most nested, groups of groups are least
+  divParent.classList.add("token-row-nest-" + Math.min(entry.height, 2));
+  divParent.style.marginLeft = (entry.depth * 12) + "px";
+  if (entry.size > 1) {
+    divParent.classList.add("token-group");
+    divParent.addEventListener("click", (evt) => {
+      toggle_token(i)
+    });
+  }
+  if (token.token_type == "error") {
+    // decoding stopped here, the token covers the rest of the input
+    divParent.classList.add("token-row-error");
+  }
 
   let tokenTypeDiv = document.createElement("div");
   tokenTypeDiv.classList.add("token-type-parent");
@@ -81,7 +132,11 @@ function construct_token_row(token, i) {
   let tokenTypeText = document.createElement("div");
   tokenTypeText.classList.add("token-type");
   tokenTypeText.classList.add("tooltip");
-  tokenTypeText.innerText = token.token_type;
+  let toggle = document.createElement("span");
+  toggle.classList.add("token-toggle");
+  toggle.innerText = entry.size > 1 ? "\u25BE " : "";
+  tokenTypeText.appendChild(toggle);
+  tokenTypeText.appendChild(document.createTextNode(token.token_type));
 
   let tooltipText = document.createElement("span");
   tooltipText.classList.add("tooltiptext");
@@ -106,24 +161,9 @@ function construct_token_row(token, i) {
   tr.appendChild(td);
 
   return tr
-
-    /*
-  `<tr>
-      <td>
-        <div onmouseover="token_hover_over(${i})" onmouseout="token_hover_out(${i})" class="token-row token-row-nest-${token.nest_level}">
-          <div class="token-type-parent">
-            <text class="token-type">${token.token_type}</text>
-          </div>
-          <div class="token-data-parent">
-            <text class="token-data">${token.data}</text>
-          </div>
-        </div>
-      </td>
-    </tr>`; 
-    */
 }
 
-function decoded_data_callback(decoded_tokens) {
+function decoded_data_callback(root_token) {
   document.getElementById("tables-body").style.visibility = "visible";
 
   let token_table = document.createElement("tbody");
@@ -144,46 +184,48 @@ function decoded_data_callback(decoded_tokens) {
 
   </tr>`;
 
-  var remaining_in_byte = 0;
-  var byte_col = 0;
-  var current_byte_span = null;
+  let flat = [];
+  flatten_tokens(root_token, 0, flat);
+  window.flat_tokens = flat;
 
-  for (const [index, token] of decoded_tokens.entries()) {
-    let html = construct_token_row(token, index);
+  for (const [index, entry] of flat.entries()) {
+    let html = construct_token_row(entry, index);
+    entry.row = html;
     token_table.appendChild(html);
+  }
 
-    if (token.using_bytes) {
-      var bits = bytes_to_bits(token.bits);
-    } else {
-      var bits = token.bits;
+  // each bit of the input belongs to the smallest token covering it, so tokens inside a larger range
+  // (like an IDAT chunk header in the middle of a split deflate symbol) are still shown
+  let input = window.inputted_bytes;
+  // groups only own bits that none of their children cover
+  let owners = new Array(input.length * 8).fill(-1);
+  let by_length = [...flat.keys()].sort((a, b) => (flat[a].token.bit_length - flat[b].token.bit_length) || (flat[a].height - flat[b].height));
+  for (let index of by_length) {
+    let token = flat[index].token;
+    let end = Math.min(token.start_bit + token.bit_length, owners.length);
+    for (let bit = token.start_bit; bit < end; bit++) {
+      if (owners[bit] == -1) {
+        owners[bit] = index;
+      }
     }
+  }
 
-    var current_token;
-    if (current_byte_span != null) {
-      current_token = add_token_span(index, current_byte_span);
+  for (let byte_index = 0; byte_index < input.length; byte_index++) {
+    if (byte_index > 0 && byte_index % 8 == 0) {
+      bytes_table.appendChild(document.createElement("tr"));
     }
-
-    for (let bit of bits) {
-      if (remaining_in_byte == 0) {
-        // end of byte
-        current_byte_span = add_new_byte_span(bytes_table);
-        current_token = add_token_span(index, current_byte_span);
-        remaining_in_byte = 8;
-        byte_col += 1;
-
-        if (byte_col == 8) {
-          bytes_table.appendChild(document.createElement("tr"));
-          byte_col = 0;
-        }
-      }
-
-      // bytes should be read the other way, as they are meant to be interpreted from the msb in the stream, but read from lsb
-      if (token.using_bytes) {
-        current_token.innerText = current_token.innerText + bit;
-      } else {
-        current_token.innerText = bit + current_token.innerText;
+    let byte_span = add_new_byte_span(bytes_table);
+
+    // bits are shown most significant first, bit 0 is the first bit in a deflate stream
+    var current_token = null;
+    var current_owner = null;
+    for (let bit = 7; bit >= 0; bit--) {
+      let owner = owners[byte_index * 8 + bit];
+      if (current_token == null || owner != current_owner) {
+        current_token = add_token_span(owner, byte_span);
+        current_owner = owner;
       }
-      remaining_in_byte -= 1;
+      current_token.innerText = current_token.innerText + ((input[byte_index] >> bit) & 1);
     }
   }
   let token_table_element = document.getElementById("token-table");
@@ -213,24 +255,11 @@ function add_token_span(id, current_byte_span) {
   span.classList.add("token-bits");
   span.setAttribute("data-token", id);
 
-  current_byte_span.prepend(span);
+  current_byte_span.append(span);
 
   return span
 }
 
-function one_byte_to_bits(byte) {
-  return [byte&1, (byte&2)>>1, (byte&4)>>2, (byte&8)>>3, (byte&16)>>4, (byte&32)>>5, (byte&64)>>6, (byte&128)>>7].reverse()
-}
-
-function bytes_to_bits(bytes) {
-  let all_bits = [];
-  for (let byte of bytes) {
-    let bits = one_byte_to_bits(byte);
-    all_bits = all_bits.concat(bits);
-  }
-  return all_bits
-}
-
 function moveByteToView(byte) {
   let bytesTable = document.getElementById("bytes-table")
 
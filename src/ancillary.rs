@@ -0,0 +1,826 @@
+use crate::chunk_reader::RawChunk;
+use crate::error::DecodeError;
+use crate::icc::{rendering_intent_name, IccHeader};
+use crate::image_data::ColorType;
+use crate::png_parser::PNGMetadata;
+use crate::token::{group_token, ignored_error_token, shift_tokens, Token, TokenKind};
+use crate::zlib::{decode_zlib, new_parse_zlib};
+
+// ANCILLARY CHUNKS, PNG specification 11.3
+// These describe the image without changing how its pixels are decoded, except tRNS which is handled with PLTE.
+
+/// The standard ancillary chunks of a PNG file, with the values decoded from their fields.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AncillaryChunks {
+    pub gamma: Option<u32>, // gAMA, image gamma times 100000
+    pub chromaticities: Option<Chromaticities>, // cHRM
+    pub srgb_intent: Option<u8>, // sRGB rendering intent
+    pub icc_profile: Option<IccProfile>, // iCCP
+    pub significant_bits: Option<Vec<u8>>, // sBIT, for each channel of the colour type, red, green and blue for indexed-colour
+    pub background: Option<Background>, // bKGD
+    pub histogram: Option<Vec<u16>>, // hIST, approximate number of pixels using each palette entry
+    pub physical_dimensions: Option<PhysicalDimensions>, // pHYs
+    pub suggested_palettes: Vec<SuggestedPalette>, // sPLT, there can be more than one
+    pub last_modified: Option<Time>, // tIME
+    pub text: Vec<TextChunk>, // tEXt, zTXt and iTXt, in the order they are in the file
+    pub exif: Option<Vec<u8>>, // eXIf, the Exif data starting with its byte order
+}
+
+/// CIE 1931 x, y chromaticities of the white point and primaries, each times 100000.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chromaticities {
+    pub white_point: (u32, u32),
+    pub red: (u32, u32),
+    pub green: (u32, u32),
+    pub blue: (u32, u32),
+}
+
+/// An embedded ICC profile, with both the compressed and inflated bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IccProfile {
+    pub name: String,
+    pub compression_method: u8,
+    pub compressed_profile: Vec<u8>,
+    pub profile: Result<Vec<u8>, DecodeError>, // the raw ICC profile, as a colour management library would load it, or why it didn't inflate
+}
+
+impl IccProfile {
+    /// The profile's header fields and tag table, None if it didn't inflate or is too short to have them.
+    pub fn header(&self) -> Option<IccHeader> {
+        IccHeader::parse(self.profile.as_ref().ok()?)
+    }
+}
+
+/// Default background colour, in the same form as the image's pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    PaletteIndex(u8),
+    Greyscale(u16),
+    Truecolor([u16; 3]),
+}
+
+/// Pixel size or aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalDimensions {
+    pub pixels_per_unit_x: u32,
+    pub pixels_per_unit_y: u32,
+    pub unit: u8, // 0 if only the aspect ratio is known, 1 for metres
+}
+
+/// A palette suggested for displays that can't show all of the image's colours.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuggestedPalette {
+    pub name: String,
+    pub sample_depth: u8, // 8 or 16
+    pub entries: Vec<SuggestedPaletteEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SuggestedPaletteEntry {
+    pub rgba: [u16; 4],
+    pub frequency: u16,
+}
+
+/// Time the image was last changed, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// A text chunk, keywords and text are Latin-1 except in iTXt. Compressed text has been inflated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextChunk {
+    Text { keyword: String, text: String }, // tEXt
+    Compressed { keyword: String, compression_method: u8, text: Result<String, UndecodedText> }, // zTXt
+    International { // iTXt, the text is UTF-8, and was compressed if compressed is set
+        keyword: String,
+        compressed: bool,
+        compression_method: u8,
+        language_tag: String,
+        translated_keyword: String,
+        text: Result<String, UndecodedText>,
+    },
+}
+
+/// Text that doesn't inflate, or isn't UTF-8 in iTXt, kept as the bytes from the chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndecodedText {
+    pub bytes: Vec<u8>,
+    pub error: DecodeError,
+}
+
+/// The keyword and text of a tEXt, zTXt or iTXt chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextEntry<'a> {
+    pub keyword: &'a str,
+    pub text: &'a str,
+    pub language_tag: Option<&'a str>, // only iTXt chunks have a language tag and translated keyword, either can be empty
+    pub translated_keyword: Option<&'a str>,
+    pub compressed: bool,
+}
+
+impl TextChunk {
+    /// The keyword and text, None if the text couldn't be decoded.
+    pub fn entry(&self) -> Option<TextEntry<'_>> {
+        match self {
+            Self::Text { keyword, text } => Some(TextEntry { keyword, text, language_tag: None, translated_keyword: None, compressed: false }),
+            Self::Compressed { keyword, text, .. } => Some(TextEntry {
+                keyword,
+                text: text.as_ref().ok()?,
+                language_tag: None,
+                translated_keyword: None,
+                compressed: true,
+            }),
+            Self::International { keyword, compressed, language_tag, translated_keyword, text, .. } => Some(TextEntry {
+                keyword,
+                text: text.as_ref().ok()?,
+                language_tag: Some(language_tag),
+                translated_keyword: Some(translated_keyword),
+                compressed: *compressed,
+            }),
+        }
+    }
+}
+
+impl AncillaryChunks {
+    /// The keyword and text of every text chunk that could be decoded, in the order they are in the file.
+    ///
+    /// ```
+    /// # let data = [137, 80, 78, 71, 13, 10, 26, 10, 0, 0, 0, 13, 73, 72, 68, 82, 0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0, 58, 126, 155, 85, 0, 0, 0, 9, 116, 69, 88, 116, 84, 105, 116, 108, 101, 0, 68, 111, 116, 244, 97, 6, 108, 0, 0, 0, 10, 73, 68, 65, 84, 120, 218, 99, 96, 0, 0, 0, 2, 0, 1, 229, 39, 222, 252, 0, 0, 0, 0, 73, 69, 78, 68, 174, 66, 96, 130];
+    /// let (metadata, _) = png_decoder::decode_png(&data).unwrap();
+    /// let entries = metadata.ancillary.text_entries();
+    /// assert_eq!((entries[0].keyword, entries[0].text), ("Title", "Dot"));
+    /// ```
+    pub fn text_entries(&self) -> Vec<TextEntry<'_>> {
+        self.text.iter().filter_map(TextChunk::entry).collect()
+    }
+
+    pub(crate) fn add(&mut self, chunk: AncillaryChunk) {
+        match chunk {
+            AncillaryChunk::Gamma(gamma) => self.gamma = Some(gamma),
+            AncillaryChunk::Chromaticities(chromaticities) => self.chromaticities = Some(chromaticities),
+            AncillaryChunk::Srgb(intent) => self.srgb_intent = Some(intent),
+            AncillaryChunk::IccProfile(profile) => self.icc_profile = Some(profile),
+            AncillaryChunk::SignificantBits(bits) => self.significant_bits = Some(bits),
+            AncillaryChunk::Background(background) => self.background = Some(background),
+            AncillaryChunk::Histogram(histogram) => self.histogram = Some(histogram),
+            AncillaryChunk::PhysicalDimensions(dimensions) => self.physical_dimensions = Some(dimensions),
+            AncillaryChunk::SuggestedPalette(palette) => self.suggested_palettes.push(palette),
+            AncillaryChunk::LastModified(time) => self.last_modified = Some(time),
+            AncillaryChunk::Text(text) => self.text.push(text),
+            AncillaryChunk::Exif(exif) => self.exif = Some(exif),
+        }
+    }
+}
+
+
+// One decoded ancillary chunk, before it is added to the AncillaryChunks.
+pub(crate) enum AncillaryChunk {
+    Gamma(u32),
+    Chromaticities(Chromaticities),
+    Srgb(u8),
+    IccProfile(IccProfile),
+    SignificantBits(Vec<u8>),
+    Background(Background),
+    Histogram(Vec<u16>),
+    PhysicalDimensions(PhysicalDimensions),
+    SuggestedPalette(SuggestedPalette),
+    LastModified(Time),
+    Text(TextChunk),
+    Exif(Vec<u8>),
+}
+
+pub(crate) fn chunk_error(byte_offset: usize, reason: String) -> DecodeError {
+    DecodeError::PngChunk { byte_offset, reason }
+}
+
+pub(crate) fn u16_at(data: &[u8], index: usize) -> u16 {
+    u16::from_be_bytes([data[index], data[index+1]])
+}
+
+pub(crate) fn u32_at(data: &[u8], index: usize) -> u32 {
+    u32::from_be_bytes([data[index], data[index+1], data[index+2], data[index+3]])
+}
+
+fn latin_1(bytes: &[u8]) -> String {
+    // every Latin-1 byte is the unicode code point with the same value
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
+fn null_position(data: &[u8], start: usize) -> Option<usize> {
+    data[start.min(data.len())..].iter().position(|&byte| byte == 0).map(|position| start + position)
+}
+
+fn read_keyword(chunk: &RawChunk) -> Result<(String, usize), DecodeError> {
+    // keyword and the index of the null separator after it
+    let data = chunk.data();
+    let null = null_position(data, 0)
+        .ok_or(chunk_error(chunk.data_offset(), format!("{} keyword has no null separator", chunk.chunk_type)))?;
+    if !(1..=79).contains(&null) {
+        return Err(chunk_error(chunk.data_offset(), format!("{} keyword is {} bytes, should be 1 to 79", chunk.chunk_type, null)))
+    }
+    Ok((latin_1(&data[..null]), null))
+}
+
+fn inflate(chunk: &RawChunk, start: usize, make_tokens: bool) -> (Result<Vec<u8>, DecodeError>, Option<Token>) {
+    // compressed fields are zlib streams that go to the end of the chunk, errors and tokens are moved to their position in the file
+    // the zlib_stream token is only kept if the whole stream could be decoded
+    let byte_offset = chunk.data_offset() + start;
+    if !make_tokens {
+        return (decode_zlib(&chunk.data()[start..]).map_err(|error| error.shifted(byte_offset)), None)
+    }
+    match new_parse_zlib(&chunk.data()[start..]) {
+        Ok((mut zlib_stream, inflated)) => {
+            shift_tokens(std::slice::from_mut(&mut zlib_stream), byte_offset*8);
+            (Ok(inflated), Some(zlib_stream))
+        },
+        Err(error) => (Err(error.shifted(byte_offset)), None),
+    }
+}
+
+fn byte_after(chunk: &RawChunk, index: usize, field: &str) -> Result<u8, DecodeError> {
+    chunk.data().get(index).copied()
+        .ok_or(chunk_error(chunk.data_offset() + index, format!("{} chunk ends before its {}", chunk.chunk_type, field)))
+}
+
+pub(crate) fn check_length(chunk: &RawChunk, length: usize) -> Result<(), DecodeError> {
+    if chunk.data().len() != length {
+        return Err(chunk_error(chunk.offset, format!("{} chunk length is {}, should be {}", chunk.chunk_type, chunk.data().len(), length)))
+    }
+    Ok(())
+}
+
+pub(crate) fn check_range(chunk: &RawChunk, index: usize, field: &str, range: std::ops::RangeInclusive<u8>) -> Result<u8, DecodeError> {
+    let value = chunk.data()[index];
+    if !range.contains(&value) {
+        return Err(chunk_error(
+            chunk.data_offset() + index,
+            format!("Invalid {} {} in {} chunk, should be {} to {}", field, value, chunk.chunk_type, range.start(), range.end())
+        ))
+    }
+    Ok(value)
+}
+
+impl AncillaryChunk {
+    /// Decodes a standard ancillary chunk, None for any other chunk.
+    ///
+    /// sBIT and bKGD depend on the colour type so must come after IHDR, and hIST must come after PLTE.
+    /// With make_tokens, the zlib_stream token of a compressed field is made while inflating it, to be passed on to tokens.
+    pub fn parse(
+        chunk: &RawChunk, metadata: Option<&PNGMetadata>, palette_len: Option<usize>, make_tokens: bool
+    ) -> Result<Option<(Self, Option<Token>)>, DecodeError> {
+        let data = chunk.data();
+        let mut zlib_stream = None;
+        let needs_ihdr = || metadata.ok_or(chunk_error(chunk.offset, format!("{} chunk must come after IHDR", chunk.chunk_type)));
+
+        let ancillary_chunk = match chunk.chunk_type.as_str() {
+            "gAMA" => {
+                check_length(chunk, 4)?;
+                Self::Gamma(u32_at(data, 0))
+            },
+            "cHRM" => {
+                check_length(chunk, 32)?;
+                let point = |index: usize| (u32_at(data, index*8), u32_at(data, index*8 + 4));
+                Self::Chromaticities(Chromaticities { white_point: point(0), red: point(1), green: point(2), blue: point(3) })
+            },
+            "sRGB" => {
+                check_length(chunk, 1)?;
+                Self::Srgb(check_range(chunk, 0, "rendering intent", 0..=3)?)
+            },
+            "iCCP" => {
+                let (name, null) = read_keyword(chunk)?;
+                byte_after(chunk, null+1, "compression method")?;
+                let compression_method = check_range(chunk, null+1, "compression method", 0..=0)?;
+                // a profile that doesn't inflate is kept compressed, so the image is still decoded without it
+                let (profile, stream) = inflate(chunk, null+2, make_tokens);
+                zlib_stream = stream;
+                Self::IccProfile(IccProfile { name, compression_method, compressed_profile: data[null+2..].to_vec(), profile })
+            },
+            "sBIT" => {
+                let metadata = needs_ihdr()?;
+                let (channels, max_bits) = match metadata.color_type {
+                    ColorType::Indexed => (3, 8),
+                    color_type => (color_type.channels(), metadata.bit_depth),
+                };
+                check_length(chunk, channels)?;
+                for index in 0..channels {
+                    check_range(chunk, index, "significant bits", 1..=max_bits)?;
+                }
+                Self::SignificantBits(data.to_vec())
+            },
+            "bKGD" => {
+                let metadata = needs_ihdr()?;
+                let background = match (metadata.color_type, data.len()) {
+                    (ColorType::Indexed, 1) => Background::PaletteIndex(data[0]),
+                    (ColorType::Greyscale | ColorType::GreyscaleAlpha, 2) => Background::Greyscale(u16_at(data, 0)),
+                    (ColorType::Truecolor | ColorType::TruecolorAlpha, 6) => Background::Truecolor([u16_at(data, 0), u16_at(data, 2), u16_at(data, 4)]),
+                    _ => return Err(chunk_error(chunk.offset, format!("bKGD chunk of length {} is invalid for {} images", data.len(), metadata.color_type)))
+                };
+                if let (Background::PaletteIndex(index), Some(palette_len)) = (background, palette_len) {
+                    if index as usize >= palette_len {
+                        return Err(chunk_error(chunk.data_offset(), format!("Background palette index {} is past the end of the {} entry palette", index, palette_len)))
+                    }
+                }
+                Self::Background(background)
+            },
+            "hIST" => {
+                let palette_len = palette_len.ok_or(chunk_error(chunk.offset, "hIST chunk must come after PLTE".to_string()))?;
+                check_length(chunk, palette_len*2)?;
+                Self::Histogram(data.chunks(2).map(|frequency| u16_at(frequency, 0)).collect())
+            },
+            "pHYs" => {
+                check_length(chunk, 9)?;
+                Self::PhysicalDimensions(PhysicalDimensions {
+                    pixels_per_unit_x: u32_at(data, 0),
+                    pixels_per_unit_y: u32_at(data, 4),
+                    unit: check_range(chunk, 8, "unit", 0..=1)?,
+                })
+            },
+            "sPLT" => {
+                let (name, null) = read_keyword(chunk)?;
+                let sample_depth = byte_after(chunk, null+1, "sample depth")?;
+                if sample_depth != 8 && sample_depth != 16 {
+                    return Err(chunk_error(chunk.data_offset() + null+1, format!("Invalid sample depth {} in sPLT chunk, should be 8 or 16", sample_depth)))
+                }
+                let entry_len = if sample_depth == 8 { 6 } else { 10 };
+                let entries = &data[null+2..];
+                if !entries.len().is_multiple_of(entry_len) {
+                    return Err(chunk_error(chunk.data_offset() + null+2, format!("sPLT entries are {} bytes, not a multiple of {}", entries.len(), entry_len)))
+                }
+                let entries = entries.chunks(entry_len).map(|entry| {
+                    let sample = |index: usize| if sample_depth == 8 { entry[index] as u16 } else { u16_at(entry, index*2) };
+                    SuggestedPaletteEntry { rgba: [sample(0), sample(1), sample(2), sample(3)], frequency: u16_at(entry, entry_len-2) }
+                }).collect();
+                Self::SuggestedPalette(SuggestedPalette { name, sample_depth, entries })
+            },
+            "tIME" => {
+                check_length(chunk, 7)?;
+                Self::LastModified(Time {
+                    year: u16_at(data, 0),
+                    month: check_range(chunk, 2, "month", 1..=12)?,
+                    day: check_range(chunk, 3, "day", 1..=31)?,
+                    hour: check_range(chunk, 4, "hour", 0..=23)?,
+                    minute: check_range(chunk, 5, "minute", 0..=59)?,
+                    second: check_range(chunk, 6, "second", 0..=60)?, // 60 for leap seconds
+                })
+            },
+            "tEXt" => {
+                let (keyword, null) = read_keyword(chunk)?;
+                Self::Text(TextChunk::Text { keyword, text: latin_1(&data[null+1..]) })
+            },
+            "zTXt" => {
+                let (keyword, null) = read_keyword(chunk)?;
+                byte_after(chunk, null+1, "compression method")?;
+                let compression_method = check_range(chunk, null+1, "compression method", 0..=0)?;
+                // text that doesn't inflate is kept compressed, the same as an unknown chunk
+                let (text, stream) = inflate(chunk, null+2, make_tokens);
+                zlib_stream = stream;
+                let text = text
+                    .map(|text| latin_1(&text))
+                    .map_err(|error| UndecodedText { bytes: data[null+2..].to_vec(), error });
+                Self::Text(TextChunk::Compressed { keyword, compression_method, text })
+            },
+            "iTXt" => {
+                let (keyword, null) = read_keyword(chunk)?;
+                byte_after(chunk, null+2, "compression method")?;
+                let compressed = check_range(chunk, null+1, "compression flag", 0..=1)? == 1;
+                let compression_method = check_range(chunk, null+2, "compression method", 0..=0)?;
+                let language_end = null_position(data, null+3)
+                    .ok_or(chunk_error(chunk.data_offset() + null+3, "iTXt language tag has no null separator".to_string()))?;
+                let translated_end = null_position(data, language_end+1)
+                    .ok_or(chunk_error(chunk.data_offset() + language_end+1, "iTXt translated keyword has no null separator".to_string()))?;
+                let translated_keyword = String::from_utf8(data[language_end+1..translated_end].to_vec())
+                    .map_err(|_| chunk_error(chunk.data_offset() + language_end+1, "iTXt translated keyword is not UTF-8".to_string()))?;
+                let text_start = translated_end+1;
+                let text = if compressed {
+                    let (text, stream) = inflate(chunk, text_start, make_tokens);
+                    zlib_stream = stream;
+                    text
+                } else {
+                    Ok(data[text_start..].to_vec())
+                };
+                let text = text
+                    .and_then(|text| String::from_utf8(text)
+                        .map_err(|_| chunk_error(chunk.data_offset() + text_start, "iTXt text is not UTF-8".to_string())))
+                    .map_err(|error| UndecodedText { bytes: data[text_start..].to_vec(), error });
+                Self::Text(TextChunk::International {
+                    keyword,
+                    compressed,
+                    compression_method,
+                    language_tag: latin_1(&data[null+3..language_end]),
+                    translated_keyword,
+                    text,
+                })
+            },
+            "eXIf" => Self::Exif(data.to_vec()),
+            _ => return Ok(None),
+        };
+        Ok(Some((ancillary_chunk, zlib_stream)))
+    }
+
+    /// Tokens for the chunk's fields, shown instead of the chunk data, with the zlib_stream made by parse.
+    pub fn tokens(&self, data: &[u8], data_offset: usize, zlib_stream: Option<Token>) -> Vec<Token> {
+        let field = |kind: TokenKind, start: usize, end: usize, value: String, description: &str| Token {
+            bits: data[start..end].to_vec(),
+            using_bytes: true,
+            start_bit: (data_offset + start)*8,
+            bit_length: (end - start)*8,
+            children: vec![],
+            data: value,
+            kind,
+            description: description.to_string(),
+        };
+        let keyword_tokens = |keyword: &str, null: usize| vec![
+            field(TokenKind::Keyword(keyword.to_string()), 0, null, keyword.to_string(), "Keyword, 1 to 79 Latin-1 characters"),
+            field(TokenKind::NullSeparator, null, null+1, "null".to_string(), "Ends the keyword"),
+        ];
+        let inflated_start = (data_offset + data.len())*8;
+        let compressed_tokens = |start: usize, inflated: Token| {
+            // the zlib stream is broken down like the image data, followed by what it inflates to, which has no bits in the file
+            let zlib_stream = zlib_stream.unwrap_or_else(|| field(TokenKind::CompressedData, start, data.len(), format!("{} bytes", data.len() - start), "Compressed data"));
+            vec![zlib_stream, inflated]
+        };
+        let inflated_text = |text: &str, description: &str| Token {
+            bits: vec![],
+            using_bytes: false,
+            start_bit: inflated_start,
+            bit_length: 0,
+            children: vec![],
+            data: text.to_string(),
+            kind: TokenKind::Text(text.to_string()),
+            description: description.to_string(),
+        };
+        let decoded_text = |text: &Result<String, UndecodedText>, description: &str| match text {
+            Ok(text) => inflated_text(text, description),
+            Err(undecoded) => ignored_error_token(&undecoded.error),
+        };
+        let null = data.iter().position(|&byte| byte == 0).unwrap_or(0);
+
+        match self {
+            Self::Gamma(gamma) => vec![
+                field(TokenKind::Gamma(*gamma), 0, 4, format!("{:.5}", *gamma as f64 / 100000.0), "Image gamma times 100000"),
+            ],
+            Self::Chromaticities(_) => {
+                let names = ["white point x", "white point y", "red x", "red y", "green x", "green y", "blue x", "blue y"];
+                names.iter().enumerate().map(|(index, name)| {
+                    let value = u32_at(data, index*4);
+                    field(TokenKind::Chromaticity(value), index*4, index*4 + 4, format!("{}: {:.5}", name, value as f64 / 100000.0), "CIE 1931 chromaticity times 100000")
+                }).collect()
+            },
+            Self::Srgb(intent) => vec![
+                field(TokenKind::RenderingIntent(*intent as u32), 0, 1, rendering_intent_name(*intent as u32).to_string(), "Rendering intent for the sRGB colour space"),
+            ],
+            Self::IccProfile(profile) => {
+                let mut tokens = keyword_tokens(&profile.name, null);
+                tokens.push(field(TokenKind::CompressionMethod(profile.compression_method), null+1, null+2, format!("{}", profile.compression_method), "Compression method, 0 is zlib"));
+                let inflated = match (&profile.profile, profile.header()) {
+                    (Err(error), _) => ignored_error_token(error),
+                    (Ok(inflated), Some(header)) => header.tokens(inflated, inflated_start),
+                    (Ok(inflated), None) => group_token(
+                        TokenKind::IccProfile, format!("{} bytes", inflated.len()), "The inflated ICC profile, too short for its header and tag table", inflated_start
+                    ),
+                };
+                tokens.extend(compressed_tokens(null+2, inflated));
+                tokens
+            },
+            Self::SignificantBits(bits) => bits.iter().enumerate().map(|(index, bits)| {
+                field(TokenKind::SignificantBits(*bits), index, index+1, format!("{}", bits), "Significant bits of a channel in the original image")
+            }).collect(),
+            Self::Background(background) => {
+                let value = match background {
+                    Background::PaletteIndex(index) => format!("palette index {}", index),
+                    Background::Greyscale(grey) => format!("grey {}", grey),
+                    Background::Truecolor([r, g, b]) => format!("rgb({}, {}, {})", r, g, b),
+                };
+                vec![field(TokenKind::BackgroundColour(*background), 0, data.len(), value, "Default background colour")]
+            },
+            Self::Histogram(histogram) => histogram.iter().enumerate().map(|(index, frequency)| {
+                field(TokenKind::HistogramEntry { index, frequency: *frequency }, index*2, index*2 + 2, format!("{}: {}", index, frequency), "Approximate usage of the palette entry at this index")
+            }).collect(),
+            Self::PhysicalDimensions(dimensions) => vec![
+                field(TokenKind::PixelsPerUnit(dimensions.pixels_per_unit_x), 0, 4, format!("x: {}", dimensions.pixels_per_unit_x), "Pixels per unit, horizontally"),
+                field(TokenKind::PixelsPerUnit(dimensions.pixels_per_unit_y), 4, 8, format!("y: {}", dimensions.pixels_per_unit_y), "Pixels per unit, vertically"),
+                field(TokenKind::Unit(dimensions.unit), 8, 9, (if dimensions.unit == 1 { "metre" } else { "unknown" }).to_string(), "Unit, either unknown (aspect ratio only) or metre"),
+            ],
+            Self::SuggestedPalette(palette) => {
+                let mut tokens = keyword_tokens(&palette.name, null);
+                tokens.push(field(TokenKind::SampleDepth(palette.sample_depth), null+1, null+2, format!("{}", palette.sample_depth), "Bits per sample of the palette entries"));
+                let entry_len = if palette.sample_depth == 8 { 6 } else { 10 };
+                for (index, entry) in palette.entries.iter().enumerate() {
+                    let start = null+2 + index*entry_len;
+                    let [r, g, b, a] = entry.rgba;
+                    tokens.push(field(
+                        TokenKind::SuggestedPaletteEntry(*entry), start, start + entry_len,
+                        format!("{}: rgba({}, {}, {}, {}), frequency {}", index, r, g, b, a, entry.frequency),
+                        "Suggested palette entry and how often it is used"
+                    ));
+                }
+                tokens
+            },
+            Self::LastModified(time) => vec![
+                field(TokenKind::Year(time.year), 0, 2, format!("{}", time.year), "Year"),
+                field(TokenKind::Month(time.month), 2, 3, format!("{}", time.month), "Month, 1 to 12"),
+                field(TokenKind::Day(time.day), 3, 4, format!("{}", time.day), "Day, 1 to 31"),
+                field(TokenKind::Hour(time.hour), 4, 5, format!("{}", time.hour), "Hour, 0 to 23"),
+                field(TokenKind::Minute(time.minute), 5, 6, format!("{}", time.minute), "Minute, 0 to 59"),
+                field(TokenKind::Second(time.second), 6, 7, format!("{}", time.second), "Second, 0 to 60 for leap seconds"),
+            ],
+            Self::Text(TextChunk::Text { keyword, text }) => {
+                let mut tokens = keyword_tokens(keyword, null);
+                if null+1 < data.len() {
+                    tokens.push(field(TokenKind::Text(text.clone()), null+1, data.len(), text.clone(), "Latin-1 text"));
+                }
+                tokens
+            },
+            Self::Text(TextChunk::Compressed { keyword, compression_method, text }) => {
+                let mut tokens = keyword_tokens(keyword, null);
+                tokens.push(field(TokenKind::CompressionMethod(*compression_method), null+1, null+2, format!("{}", compression_method), "Compression method, 0 is zlib"));
+                tokens.extend(compressed_tokens(null+2, decoded_text(text, "Inflated Latin-1 text")));
+                tokens
+            },
+            Self::Text(TextChunk::International { keyword, compressed, compression_method, language_tag, translated_keyword, text }) => {
+                let language_end = null+3 + language_tag.chars().count();
+                let translated_end = language_end+1 + translated_keyword.len();
+                let mut tokens = keyword_tokens(keyword, null);
+                tokens.push(field(TokenKind::CompressionFlag(*compressed), null+1, null+2, format!("{}", compressed), "Whether the text is compressed"));
+                tokens.push(field(TokenKind::CompressionMethod(*compression_method), null+2, null+3, format!("{}", compression_method), "Compression method, 0 is zlib"));
+                if !language_tag.is_empty() {
+                    tokens.push(field(TokenKind::LanguageTag(language_tag.clone()), null+3, language_end, language_tag.clone(), "Language of the text, such as en-GB"));
+                }
+                tokens.push(field(TokenKind::NullSeparator, language_end, language_end+1, "null".to_string(), "Ends the language tag"));
+                if !translated_keyword.is_empty() {
+                    tokens.push(field(TokenKind::TranslatedKeyword(translated_keyword.clone()), language_end+1, translated_end, translated_keyword.clone(), "Keyword in the language of the text"));
+                }
+                tokens.push(field(TokenKind::NullSeparator, translated_end, translated_end+1, "null".to_string(), "Ends the translated keyword"));
+                match (compressed, text) {
+                    (true, text) => tokens.extend(compressed_tokens(translated_end+1, decoded_text(text, "Inflated UTF-8 text"))),
+                    (false, Ok(text)) if text.is_empty() => (),
+                    (false, Ok(text)) => tokens.push(field(TokenKind::Text(text.clone()), translated_end+1, data.len(), text.clone(), "UTF-8 text")),
+                    (false, Err(undecoded)) => {
+                        tokens.push(field(TokenKind::ChunkData, translated_end+1, data.len(), format!("{} bytes", undecoded.bytes.len()), "Text that isn't UTF-8"));
+                        tokens.push(ignored_error_token(&undecoded.error));
+                    },
+                }
+                tokens
+            },
+            Self::Exif(exif) if exif.is_empty() => vec![],
+            Self::Exif(exif) => {
+                let byte_order = match exif.get(0..2) {
+                    Some(b"MM") => "big endian",
+                    Some(b"II") => "little endian",
+                    _ => "unknown byte order",
+                };
+                vec![field(TokenKind::ExifData, 0, data.len(), format!("{} bytes, {}", exif.len(), byte_order), "Exif metadata")]
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk_reader::ChunkReader;
+    use crate::test_fixtures::{chunk, stored_zlib};
+
+    fn parse(chunk_type: &str, data: &[u8], metadata: Option<&PNGMetadata>, palette_len: Option<usize>) -> Result<Option<AncillaryChunk>, DecodeError> {
+        // the chunk is placed at byte 33, straight after the IHDR chunk
+        let mut file = vec![0; 33];
+        file.extend(chunk(chunk_type, data));
+        let chunk = ChunkReader::new(&file, 33).next().unwrap().unwrap();
+        AncillaryChunk::parse(&chunk, metadata, palette_len, false).map(|parsed| parsed.map(|(ancillary_chunk, _)| ancillary_chunk))
+    }
+
+    fn chunk_tokens(chunk_type: &str, data: &[u8]) -> Vec<Token> {
+        // tokens of a chunk at byte 33, its data starting at byte 41
+        let mut file = vec![0; 33];
+        file.extend(chunk(chunk_type, data));
+        let chunk = ChunkReader::new(&file, 33).next().unwrap().unwrap();
+        let (ancillary_chunk, zlib_stream) = AncillaryChunk::parse(&chunk, None, None, true).unwrap().unwrap();
+        ancillary_chunk.tokens(data, 41, zlib_stream)
+    }
+
+    fn parse_all(chunks: &[(&str, &[u8])], metadata: Option<&PNGMetadata>, palette_len: Option<usize>) -> AncillaryChunks {
+        let mut ancillary = AncillaryChunks::default();
+        for (chunk_type, data) in chunks {
+            ancillary.add(parse(chunk_type, data, metadata, palette_len).unwrap().unwrap());
+        }
+        ancillary
+    }
+
+    fn indexed_metadata() -> PNGMetadata {
+        PNGMetadata {
+            bit_depth: 2,
+            width: 3,
+            height: 1,
+            color_type: ColorType::Indexed,
+            interlace_method: 0,
+            filesize: 0,
+            ancillary: AncillaryChunks::default(),
+            animation: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_ancillary_chunks() {
+        let ancillary = parse_all(&[
+            ("gAMA", &[0, 0, 177, 143]),
+            ("cHRM", &[0, 0, 122, 38, 0, 0, 128, 132, 0, 0, 250, 0, 0, 0, 128, 232, 0, 0, 117, 48, 0, 0, 234, 96, 0, 0, 58, 152, 0, 0, 23, 112]),
+            ("sRGB", &[0]),
+            ("pHYs", &[0, 0, 11, 19, 0, 0, 11, 19, 1]),
+            ("tIME", &[7, 232, 2, 29, 12, 30, 60]),
+            ("tEXt", b"Author\0Jos\xe9"),
+            ("zTXt", &[b'C', b'o', b'm', b'm', b'e', b'n', b't', 0, 0, 120, 218, 3, 0, 0, 0, 0, 1]),
+            ("iTXt", "Title\0\0\0en\0Titel\0héllo".as_bytes()),
+            ("sPLT", &[b'p', b'a', b'l', 0, 8, 255, 0, 0, 255, 0, 10, 0, 0, 255, 128, 0, 5]),
+            ("iCCP", &[b's', b'R', b'G', b'B', 0, 0, 120, 218, 3, 0, 0, 0, 0, 1]),
+            ("eXIf", b"MM\0*"),
+        ], None, None);
+
+        assert_eq!(ancillary.gamma, Some(45455));
+        assert_eq!(ancillary.chromaticities.unwrap().white_point, (31270, 32900));
+        assert_eq!(ancillary.srgb_intent, Some(0));
+        assert_eq!(ancillary.physical_dimensions, Some(PhysicalDimensions { pixels_per_unit_x: 2835, pixels_per_unit_y: 2835, unit: 1 }));
+        assert_eq!(ancillary.last_modified, Some(Time { year: 2024, month: 2, day: 29, hour: 12, minute: 30, second: 60 }));
+        assert_eq!(ancillary.text, vec![
+            TextChunk::Text { keyword: "Author".to_string(), text: "José".to_string() },
+            TextChunk::Compressed { keyword: "Comment".to_string(), compression_method: 0, text: Ok(String::new()) },
+            TextChunk::International {
+                keyword: "Title".to_string(),
+                compressed: false,
+                compression_method: 0,
+                language_tag: "en".to_string(),
+                translated_keyword: "Titel".to_string(),
+                text: Ok("héllo".to_string()),
+            },
+        ]);
+        assert_eq!(ancillary.suggested_palettes, vec![SuggestedPalette {
+            name: "pal".to_string(),
+            sample_depth: 8,
+            entries: vec![
+                SuggestedPaletteEntry { rgba: [255, 0, 0, 255], frequency: 10 },
+                SuggestedPaletteEntry { rgba: [0, 0, 255, 128], frequency: 5 },
+            ],
+        }]);
+        assert_eq!(ancillary.icc_profile.unwrap().name, "sRGB");
+        assert_eq!(ancillary.exif, Some(b"MM\0*".to_vec()));
+
+        // chunks that depend on the colour type and palette
+        let metadata = indexed_metadata();
+        let ancillary = parse_all(&[("sBIT", &[5, 6, 5]), ("bKGD", &[2]), ("hIST", &[0, 1, 0, 2, 0, 0])], Some(&metadata), Some(3));
+        assert_eq!(ancillary.significant_bits, Some(vec![5, 6, 5]));
+        assert_eq!(ancillary.background, Some(Background::PaletteIndex(2)));
+        assert_eq!(ancillary.histogram, Some(vec![1, 2, 0]));
+
+        // other chunks aren't decoded
+        assert!(parse("prVt", &[1, 2, 3], None, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_ancillary_chunks() {
+        let metadata = indexed_metadata();
+        let error = |chunk_type: &str, data: &[u8], metadata: Option<&PNGMetadata>| parse(chunk_type, data, metadata, Some(3)).err().unwrap();
+
+        assert_eq!(error("gAMA", &[0, 0, 1], None), DecodeError::PngChunk { byte_offset: 33, reason: "gAMA chunk length is 3, should be 4".to_string() });
+        assert_eq!(error("sRGB", &[4], None), DecodeError::PngChunk { byte_offset: 41, reason: "Invalid rendering intent 4 in sRGB chunk, should be 0 to 3".to_string() });
+        assert_eq!(error("tIME", &[7, 232, 13, 1, 0, 0, 0], None), DecodeError::PngChunk { byte_offset: 43, reason: "Invalid month 13 in tIME chunk, should be 1 to 12".to_string() });
+        assert_eq!(error("tEXt", b"Author", None), DecodeError::PngChunk { byte_offset: 41, reason: "tEXt keyword has no null separator".to_string() });
+        assert_eq!(error("tEXt", b"\0text", None), DecodeError::PngChunk { byte_offset: 41, reason: "tEXt keyword is 0 bytes, should be 1 to 79".to_string() });
+        assert_eq!(error("zTXt", b"Comment\0", None), DecodeError::PngChunk { byte_offset: 49, reason: "zTXt chunk ends before its compression method".to_string() });
+        assert_eq!(error("iTXt", b"Title\0\x01\0en", None), DecodeError::PngChunk { byte_offset: 49, reason: "iTXt language tag has no null separator".to_string() });
+        assert_eq!(error("sPLT", b"pal\0\x04", None), DecodeError::PngChunk { byte_offset: 45, reason: "Invalid sample depth 4 in sPLT chunk, should be 8 or 16".to_string() });
+
+        assert_eq!(error("bKGD", &[2], None), DecodeError::PngChunk { byte_offset: 33, reason: "bKGD chunk must come after IHDR".to_string() });
+        assert_eq!(error("bKGD", &[3], Some(&metadata)), DecodeError::PngChunk { byte_offset: 41, reason: "Background palette index 3 is past the end of the 3 entry palette".to_string() });
+        assert_eq!(error("bKGD", &[0, 3], Some(&metadata)), DecodeError::PngChunk { byte_offset: 33, reason: "bKGD chunk of length 2 is invalid for indexed-colour images".to_string() });
+        assert_eq!(error("sBIT", &[5, 9, 5], Some(&metadata)), DecodeError::PngChunk { byte_offset: 42, reason: "Invalid significant bits 9 in sBIT chunk, should be 1 to 8".to_string() });
+        assert_eq!(error("hIST", &[0, 1], Some(&metadata)), DecodeError::PngChunk { byte_offset: 33, reason: "hIST chunk length is 2, should be 6".to_string() });
+        assert_eq!(parse("hIST", &[0, 1], None, None).err().unwrap().to_string(), "PNG chunk error at byte 33: hIST chunk must come after PLTE");
+    }
+
+    #[test]
+    fn test_ancillary_tokens() {
+        // each field is a token, covering all of the chunk data
+        let data = "Title\0\0\0en\0\0héllo".as_bytes();
+        let tokens = chunk_tokens("iTXt", data);
+        let token_types: Vec<&str> = tokens.iter().map(|token| token.kind.name()).collect();
+        assert_eq!(token_types, vec![
+            "keyword", "null_separator", "compression_flag", "compression_method", "language_tag",
+            "null_separator", "null_separator", "text"
+        ]);
+        assert_eq!(tokens[0].kind, TokenKind::Keyword("Title".to_string()));
+        assert_eq!((tokens[4].start_bit, tokens[4].bit_length, tokens[4].data.as_str()), (49*8, 16, "en"));
+        assert_eq!(tokens.iter().map(|token| token.bit_length).sum::<usize>(), data.len()*8);
+
+        let data = [7, 232, 2, 29, 12, 30, 0];
+        let tokens = chunk_tokens("tIME", &data);
+        let values: Vec<&str> = tokens.iter().map(|token| token.data.as_str()).collect();
+        assert_eq!(values, vec!["2024", "2", "29", "12", "30", "0"]);
+        assert_eq!(tokens[0].kind, TokenKind::Year(2024));
+
+        let data = [0, 0, 11, 19, 0, 0, 11, 19, 1];
+        let tokens = chunk_tokens("pHYs", &data);
+        assert_eq!(tokens[2].data, "metre");
+        assert_eq!(tokens[1].start_bit, 45*8);
+    }
+
+    #[test]
+    fn test_compressed_text() {
+        // "Hello Hello" and "héllo" as zlib streams
+        let hello = [120, 218, 243, 72, 205, 201, 201, 87, 240, 0, 145, 0, 23, 145, 4, 9];
+        let hello_utf8 = [120, 218, 203, 56, 188, 50, 39, 39, 31, 0, 11, 116, 3, 28];
+        let ztxt = [&b"Comment\0\0"[..], &hello].concat();
+        let itxt = [&b"Title\0\x01\0fr\0Titre\0"[..], &hello_utf8].concat();
+        let ancillary = parse_all(&[("tEXt", b"Author\0Jo"), ("zTXt", &ztxt), ("iTXt", &itxt)], None, None);
+
+        let entries = ancillary.text_entries();
+        assert_eq!(entries[0], TextEntry { keyword: "Author", text: "Jo", language_tag: None, translated_keyword: None, compressed: false });
+        assert_eq!(entries[1], TextEntry { keyword: "Comment", text: "Hello Hello", language_tag: None, translated_keyword: None, compressed: true });
+        assert_eq!(entries[2], TextEntry { keyword: "Title", text: "héllo", language_tag: Some("fr"), translated_keyword: Some("Titre"), compressed: true });
+
+        // the zlib stream is broken down at its position in the file, followed by the inflated text
+        let tokens = chunk_tokens("zTXt", &ztxt);
+        let token_types: Vec<&str> = tokens.iter().map(|token| token.kind.name()).collect();
+        assert_eq!(token_types, vec!["keyword", "null_separator", "compression_method", "zlib_stream", "text"]);
+        assert_eq!((tokens[3].start_bit, tokens[3].bit_length), ((41 + 9)*8, hello.len()*8));
+        assert!(tokens[3].flatten().iter().any(|token| token.kind == TokenKind::Literal(b'H')));
+        assert_eq!((tokens[4].data.as_str(), tokens[4].start_bit, tokens[4].bit_length), ("Hello Hello", (41 + ztxt.len())*8, 0));
+
+        let tokens = chunk_tokens("iTXt", &itxt);
+        assert_eq!(tokens.last().unwrap().kind, TokenKind::Text("héllo".to_string()));
+
+        // text that can't be decoded is kept with its error, given as a file offset, and left out of the entries
+        let mut damaged = ztxt.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        let error = DecodeError::ZlibChecksum { byte_offset: 41 + damaged.len()-4, stored: 0x17910408, calculated: 0x17910409 };
+        let undecoded = TextChunk::Compressed {
+            keyword: "Comment".to_string(),
+            compression_method: 0,
+            text: Err(UndecodedText { bytes: damaged[9..].to_vec(), error: error.clone() }),
+        };
+        let ancillary = parse_all(&[("zTXt", &damaged), ("tEXt", b"Author\0Jo")], None, None);
+        assert_eq!(ancillary.text[0], undecoded);
+        assert_eq!(ancillary.text_entries().len(), 1);
+        let tokens = chunk_tokens("zTXt", &damaged);
+        assert_eq!(tokens.last().unwrap().kind, TokenKind::Error(error));
+
+        let not_utf8 = b"Title\0\0\0\0\0\xe9";
+        let chunk = parse("iTXt", not_utf8, None, None).unwrap().unwrap();
+        let AncillaryChunk::Text(TextChunk::International { text: Err(undecoded), .. }) = &chunk else { panic!("iTXt text should be undecoded") };
+        assert_eq!((undecoded.bytes.as_slice(), undecoded.error.to_string().as_str()), (&b"\xe9"[..], "PNG chunk error at byte 51: iTXt text is not UTF-8"));
+        let token_types: Vec<&str> = chunk_tokens("iTXt", not_utf8).iter().map(|token| token.kind.name()).collect();
+        assert_eq!(token_types[token_types.len()-2..], ["chunk_data", "error"]);
+    }
+
+    #[test]
+    fn test_icc_profile() {
+        // a display profile with one tag, in a stored deflate block
+        let mut profile = vec![0; 128];
+        profile[0..4].copy_from_slice(&[0, 0, 0, 144]);
+        profile[8] = 2;
+        profile[12..24].copy_from_slice(b"mntrRGB XYZ ");
+        profile.extend([0, 0, 0, 1]);
+        profile.extend(b"wtpt");
+        profile.extend([0, 0, 0, 0, 0, 0, 0, 0]);
+        let iccp = [&b"Display\0\0"[..], &stored_zlib(&profile)].concat();
+
+        let ancillary = parse_all(&[("iCCP", &iccp)], None, None);
+        let icc_profile = ancillary.icc_profile.unwrap();
+        assert_eq!((icc_profile.name.as_str(), icc_profile.compressed_profile.len()), ("Display", iccp.len() - 9));
+        assert_eq!(icc_profile.profile, Ok(profile.clone()));
+        let header = icc_profile.header().unwrap();
+        assert_eq!((header.size, header.version, header.device_class.as_str()), (144, (2, 0, 0), "mntr"));
+        assert_eq!(header.tags[0].signature, "wtpt");
+
+        // the zlib stream is broken down, followed by the header and tag table from the inflated profile
+        let tokens = chunk_tokens("iCCP", &iccp);
+        let token_types: Vec<&str> = tokens.iter().map(|token| token.kind.name()).collect();
+        assert_eq!(token_types, vec!["keyword", "null_separator", "compression_method", "zlib_stream", "icc_profile"]);
+        assert_eq!((tokens[3].start_bit, tokens[3].bit_length), (50*8, (iccp.len() - 9)*8));
+        let icc_tokens = tokens[4].flatten();
+        assert!(icc_tokens.iter().all(|token| token.start_bit == (41 + iccp.len())*8 && token.bit_length == 0));
+        assert!(icc_tokens.iter().any(|token| token.kind == TokenKind::DeviceClass("mntr".to_string())));
+        assert!(icc_tokens.iter().any(|token| token.kind.name() == "icc_tag" && token.data == "wtpt: 0 bytes at 0"));
+
+        // a profile too short for its header is still shown, as a single token
+        let iccp = [&b"Short\0\0"[..], &stored_zlib(&profile[..64])].concat();
+        let tokens = chunk_tokens("iCCP", &iccp);
+        let last = tokens.last().unwrap();
+        assert_eq!((last.kind.name(), last.data.as_str(), last.children.len()), ("icc_profile", "64 bytes", 0));
+        assert_eq!((last.bits.len(), last.bit_length), (0, 0));
+
+        // a damaged profile is kept compressed, with its error at its position in the file
+        let mut damaged = [&b"Display\0\0"[..], &stored_zlib(&profile)].concat();
+        *damaged.last_mut().unwrap() ^= 1;
+        let chunk = parse("iCCP", &damaged, None, None).unwrap().unwrap();
+        let AncillaryChunk::IccProfile(icc_profile) = &chunk else { panic!("iCCP should be decoded") };
+        assert!(matches!(icc_profile.profile, Err(DecodeError::ZlibChecksum { byte_offset, .. }) if byte_offset == 41 + damaged.len()-4));
+        assert_eq!((icc_profile.compressed_profile.as_slice(), icc_profile.header()), (&damaged[9..], None));
+        assert_eq!(chunk_tokens("iCCP", &damaged).last().unwrap().kind, TokenKind::Error(icc_profile.profile.clone().unwrap_err()));
+    }
+}
@@ -0,0 +1,445 @@
+use std::time::Duration;
+use crate::ancillary::{check_length, check_range, chunk_error, u16_at, u32_at};
+use crate::chunk_reader::RawChunk;
+use crate::error::DecodeError;
+use crate::image_data::{ColorType, ImageData};
+use crate::png_parser::PNGMetadata;
+use crate::token::{Token, TokenKind};
+
+// ANIMATED PNG, PNG specification 11.3.6
+// acTL marks the file as animated. Each frame has an fcTL chunk, and its image data is in fdAT chunks after the IDAT chunks.
+// fcTL and fdAT share a sequence number, starting at 0, so reordered chunks can be detected.
+// The IDAT image is the first frame if its fcTL comes before the IDAT chunks, otherwise it is only shown by decoders without APNG support.
+
+/// Number of frames and how many times to play them, from acTL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnimationControl {
+    pub num_frames: u32,
+    pub num_plays: u32, // 0 to loop forever
+}
+
+/// What happens to a frame's region of the canvas before the next frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisposeOp {
+    None, // left as it is
+    Background, // cleared to transparent black
+    Previous, // put back to how it was before the frame
+}
+
+/// How a frame is drawn onto the canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendOp {
+    Source, // replaces the region, including its alpha
+    Over, // alpha composited over the region
+}
+
+/// Size, position, delay and compositing of a frame, from fcTL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameControl {
+    pub sequence_number: u32,
+    pub width: usize,
+    pub height: usize,
+    pub x_offset: usize,
+    pub y_offset: usize,
+    pub delay_numerator: u16,
+    pub delay_denominator: u16, // 0 means 100, so the delay is in hundredths of a second
+    pub dispose_op: DisposeOp,
+    pub blend_op: BlendOp,
+}
+
+impl FrameControl {
+    pub fn delay(&self) -> Duration {
+        let denominator = if self.delay_denominator == 0 { 100 } else { self.delay_denominator as u64 };
+        Duration::from_nanos(self.delay_numerator as u64 * 1_000_000_000 / denominator)
+    }
+}
+
+/// A frame as it is stored in the file, covering only its region of the canvas.
+pub struct AnimationFrame {
+    pub control: FrameControl,
+    pub image: ImageData,
+}
+
+/// The frames of an animated PNG, before they are composited.
+pub struct Animation {
+    pub control: AnimationControl,
+    pub width: usize, // canvas size, the same as the IHDR image
+    pub height: usize,
+    pub frames: Vec<AnimationFrame>,
+    pub default_image_is_first_frame: bool,
+}
+
+impl Animation {
+    /// Composites each frame onto the canvas in turn, giving the images to show and how long to show them.
+    pub fn composited(&self) -> Frames<'_> {
+        let bit_depth = if self.frames.iter().any(|frame| frame.image.bit_depth == 16) { 16 } else { 8 };
+        Frames {
+            frames: self.frames.iter(),
+            canvas: ImageData {
+                width: self.width,
+                height: self.height,
+                color_type: ColorType::TruecolorAlpha,
+                bit_depth,
+                data: vec![0; self.width * self.height * 4 * (bit_depth as usize / 8)],
+            },
+        }
+    }
+}
+
+/// A frame composited onto the canvas, as it should be shown.
+pub struct Frame {
+    pub image: ImageData, // the whole canvas, truecolour with alpha
+    pub delay: Duration,
+    pub control: FrameControl,
+}
+
+/// Iterator over the composited frames of an animation, made by [`Animation::composited`].
+pub struct Frames<'a> {
+    frames: std::slice::Iter<'a, AnimationFrame>,
+    canvas: ImageData,
+}
+
+fn blend_over(dest: &mut [u8], source: &[u8], bytes_per_sample: usize) {
+    // PNG specification 11.3.6.2, samples are widened to u64 so 16 bit products don't overflow
+    let read = |pixel: &[u8], channel: usize| match bytes_per_sample {
+        2 => u16::from_be_bytes([pixel[channel*2], pixel[channel*2 + 1]]) as u64,
+        _ => pixel[channel] as u64,
+    };
+    let max = if bytes_per_sample == 2 { 65535 } else { 255 };
+
+    let source_alpha = read(source, 3);
+    if source_alpha == max {
+        dest.copy_from_slice(source);
+        return
+    } else if source_alpha == 0 {
+        return
+    }
+
+    let dest_alpha = read(dest, 3);
+    let dest_weight = dest_alpha * (max - source_alpha) / max;
+    let alpha = source_alpha + dest_weight;
+    let mut samples = [0; 4];
+    for (channel, sample) in samples.iter_mut().enumerate().take(3) {
+        *sample = (read(source, channel)*source_alpha + read(dest, channel)*dest_weight) / alpha;
+    }
+    samples[3] = alpha;
+
+    for (channel, sample) in samples.iter().enumerate() {
+        if bytes_per_sample == 2 {
+            dest[channel*2..channel*2 + 2].copy_from_slice(&(*sample as u16).to_be_bytes());
+        } else {
+            dest[channel] = *sample as u8;
+        }
+    }
+}
+
+impl Iterator for Frames<'_> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        let frame = self.frames.next()?;
+        let control = frame.control;
+        let previous = (control.dispose_op == DisposeOp::Previous).then(|| self.canvas.data.clone());
+
+        let image = frame.image.to_rgba();
+        let (bytes_per_pixel, bytes_per_sample) = (self.canvas.bytes_per_pixel(), self.canvas.bytes_per_sample());
+        let row_start = |canvas: &ImageData, y: usize| ((control.y_offset + y)*canvas.width + control.x_offset) * bytes_per_pixel;
+        for y in 0..control.height {
+            for x in 0..control.width {
+                let start = row_start(&self.canvas, y) + x*bytes_per_pixel;
+                let dest = &mut self.canvas.data[start..start+bytes_per_pixel];
+                match control.blend_op {
+                    BlendOp::Source => dest.copy_from_slice(image.pixel(x, y)),
+                    BlendOp::Over => blend_over(dest, image.pixel(x, y), bytes_per_sample),
+                }
+            }
+        }
+
+        let shown = Frame {
+            image: ImageData { data: self.canvas.data.clone(), ..self.canvas },
+            delay: control.delay(),
+            control,
+        };
+
+        match (control.dispose_op, previous) {
+            (DisposeOp::Background, _) => {
+                for y in 0..control.height {
+                    let start = row_start(&self.canvas, y);
+                    self.canvas.data[start..start + control.width*bytes_per_pixel].fill(0);
+                }
+            },
+            (DisposeOp::Previous, Some(previous)) => self.canvas.data = previous,
+            _ => {},
+        }
+        Some(shown)
+    }
+}
+
+impl AnimationControl {
+    pub(crate) fn parse(chunk: &RawChunk) -> Result<Self, DecodeError> {
+        check_length(chunk, 8)?;
+        let num_frames = u32_at(chunk.data(), 0);
+        if num_frames == 0 {
+            return Err(chunk_error(chunk.data_offset(), "acTL chunk has 0 frames".to_string()))
+        }
+        Ok(Self { num_frames, num_plays: u32_at(chunk.data(), 4) })
+    }
+
+    pub(crate) fn tokens(&self, data: &[u8], data_offset: usize) -> Vec<Token> {
+        let plays = if self.num_plays == 0 { "loops forever".to_string() } else { format!("{} plays", self.num_plays) };
+        vec![
+            field(data, data_offset, TokenKind::FrameCount(self.num_frames), 0, 4, format!("{} frames", self.num_frames), "Number of frames, each with an fcTL chunk"),
+            field(data, data_offset, TokenKind::PlayCount(self.num_plays), 4, 8, plays, "Number of times to play the animation, 0 to loop forever"),
+        ]
+    }
+}
+
+impl FrameControl {
+    pub(crate) fn parse(chunk: &RawChunk, metadata: &PNGMetadata) -> Result<Self, DecodeError> {
+        check_length(chunk, 26)?;
+        let data = chunk.data();
+        let dispose_op = match check_range(chunk, 24, "dispose op", 0..=2)? {
+            0 => DisposeOp::None,
+            1 => DisposeOp::Background,
+            _ => DisposeOp::Previous,
+        };
+        let blend_op = if check_range(chunk, 25, "blend op", 0..=1)? == 0 { BlendOp::Source } else { BlendOp::Over };
+        let control = Self {
+            sequence_number: u32_at(data, 0),
+            width: u32_at(data, 4) as usize,
+            height: u32_at(data, 8) as usize,
+            x_offset: u32_at(data, 12) as usize,
+            y_offset: u32_at(data, 16) as usize,
+            delay_numerator: u16_at(data, 20),
+            delay_denominator: u16_at(data, 22),
+            dispose_op,
+            blend_op,
+        };
+
+        if control.width == 0 || control.height == 0 {
+            return Err(chunk_error(chunk.data_offset() + 4, format!("Frame size {}x{} is empty", control.width, control.height)))
+        }
+        let past_edge = |offset: usize, size: usize, image_size: usize| offset.checked_add(size).is_none_or(|end| end > image_size);
+        if past_edge(control.x_offset, control.width, metadata.width) || past_edge(control.y_offset, control.height, metadata.height) {
+            return Err(chunk_error(chunk.data_offset() + 4, format!(
+                "Frame of {}x{} at ({}, {}) doesn't fit in the {}x{} image",
+                control.width, control.height, control.x_offset, control.y_offset, metadata.width, metadata.height
+            )))
+        }
+        Ok(control)
+    }
+
+    pub(crate) fn tokens(&self, data: &[u8], data_offset: usize) -> Vec<Token> {
+        let seconds = self.delay().as_secs_f64();
+        vec![
+            sequence_number_token(data, data_offset),
+            field(data, data_offset, TokenKind::Width(self.width as u32), 4, 8, format!("{}", self.width), "Frame width"),
+            field(data, data_offset, TokenKind::Height(self.height as u32), 8, 12, format!("{}", self.height), "Frame height"),
+            field(data, data_offset, TokenKind::FrameOffset(self.x_offset as u32), 12, 16, format!("x: {}", self.x_offset), "Position of the frame's left edge on the canvas"),
+            field(data, data_offset, TokenKind::FrameOffset(self.y_offset as u32), 16, 20, format!("y: {}", self.y_offset), "Position of the frame's top edge on the canvas"),
+            field(
+                data, data_offset, TokenKind::FrameDelay { numerator: self.delay_numerator, denominator: self.delay_denominator }, 20, 24,
+                format!("{}/{} = {:.3} s", self.delay_numerator, self.delay_denominator, seconds), "How long to show the frame, a fraction of a second, a denominator of 0 is 100"
+            ),
+            field(data, data_offset, TokenKind::DisposeOp(self.dispose_op), 24, 25, format!("{:?}", self.dispose_op).to_lowercase(), "What happens to the frame's region before the next frame"),
+            field(data, data_offset, TokenKind::BlendOp(self.blend_op), 25, 26, format!("{:?}", self.blend_op).to_lowercase(), "Whether the frame replaces its region, or is composited over it"),
+        ]
+    }
+}
+
+fn field(data: &[u8], data_offset: usize, kind: TokenKind, start: usize, end: usize, value: String, description: &str) -> Token {
+    Token {
+        bits: data[start..end].to_vec(),
+        using_bytes: true,
+        start_bit: (data_offset + start)*8,
+        bit_length: (end - start)*8,
+        children: vec![],
+        data: value,
+        kind,
+        description: description.to_string(),
+    }
+}
+
+pub(crate) fn sequence_number_token(data: &[u8], data_offset: usize) -> Token {
+    let sequence_number = u32_at(data, 0);
+    field(data, data_offset, TokenKind::SequenceNumber(sequence_number), 0, 4, format!("{}", sequence_number), "Position of the chunk in the animation's fcTL and fdAT chunks")
+}
+
+
+// decompressed fdAT data of a frame
+type FrameData = (FrameControl, Vec<u8>);
+
+// Follows the fcTL and fdAT chunks of an animated file, checking their order and collecting each frame.
+// The fdAT data is combined and decompressed by the parser the same way as IDAT, then passed back with end_frame.
+pub(crate) struct AnimationReader {
+    control: AnimationControl,
+    control_offset: usize, // byte offset of the acTL chunk
+    next_sequence_number: u32,
+    frame_count: usize, // fcTL chunks read so far
+    pending: Option<FrameControl>, // fcTL whose image data hasn't started yet
+    reading: Option<FrameControl>, // frame whose fdAT chunks are being read
+    idat_seen: bool,
+    first_frame: Option<FrameControl>, // fcTL of the IDAT image, if it is part of the animation
+    frames: Vec<FrameData>,
+}
+
+impl AnimationReader {
+    pub fn new(chunk: &RawChunk, idat_seen: bool) -> Result<Self, DecodeError> {
+        if idat_seen {
+            return Err(chunk_error(chunk.offset, "acTL chunk must come before the IDAT chunks".to_string()))
+        }
+        Ok(Self {
+            control: AnimationControl::parse(chunk)?,
+            control_offset: chunk.offset,
+            next_sequence_number: 0,
+            frame_count: 0,
+            pending: None,
+            reading: None,
+            idat_seen,
+            first_frame: None,
+            frames: Vec::new(),
+        })
+    }
+
+    pub fn control(&self) -> AnimationControl {
+        self.control
+    }
+
+    fn check_sequence_number(&mut self, chunk: &RawChunk) -> Result<(), DecodeError> {
+        let sequence_number = u32_at(chunk.data(), 0);
+        if sequence_number != self.next_sequence_number {
+            return Err(chunk_error(chunk.data_offset(), format!(
+                "{} sequence number is {}, should be {}", chunk.chunk_type, sequence_number, self.next_sequence_number
+            )))
+        }
+        self.next_sequence_number += 1;
+        Ok(())
+    }
+
+    pub fn frame_control(&mut self, chunk: &RawChunk, metadata: Option<&PNGMetadata>) -> Result<FrameControl, DecodeError> {
+        let metadata = metadata.ok_or(chunk_error(chunk.offset, "fcTL chunk must come after IHDR".to_string()))?;
+        let control = FrameControl::parse(chunk, metadata)?;
+        self.check_sequence_number(chunk)?;
+        if self.pending.is_some() {
+            return Err(chunk_error(chunk.offset, "fcTL chunk follows another fcTL with no image data between them".to_string()))
+        }
+        if !self.idat_seen && (control.x_offset, control.y_offset, control.width, control.height) != (0, 0, metadata.width, metadata.height) {
+            return Err(chunk_error(chunk.data_offset() + 4, format!(
+                "First frame of {}x{} at ({}, {}) must cover the whole {}x{} image",
+                control.width, control.height, control.x_offset, control.y_offset, metadata.width, metadata.height
+            )))
+        }
+        self.frame_count += 1;
+        self.pending = Some(control);
+        Ok(control)
+    }
+
+    pub fn idat(&mut self) {
+        // an fcTL before the IDAT chunks makes the IDAT image the first frame
+        if !self.idat_seen {
+            self.idat_seen = true;
+            self.first_frame = self.pending.take();
+        }
+    }
+
+    pub fn frame_data(&mut self, chunk: &RawChunk) -> Result<usize, DecodeError> {
+        // checks an fdAT chunk and returns the number of its frame, the data after the sequence number is part of the frame's zlib stream
+        if chunk.data().len() < 4 {
+            return Err(chunk_error(chunk.offset, format!("fdAT chunk length is {}, should be at least 4", chunk.data().len())))
+        }
+        if !self.idat_seen {
+            return Err(chunk_error(chunk.offset, "fdAT chunk must come after the IDAT chunks".to_string()))
+        }
+        self.check_sequence_number(chunk)?;
+        if self.reading.is_none() {
+            self.reading = Some(self.pending.take().ok_or(chunk_error(chunk.offset, "fdAT chunk has no fcTL before it".to_string()))?);
+        }
+        Ok(self.frame_count - 1)
+    }
+
+    pub fn end_frame(&mut self, decompressed: Vec<u8>) {
+        if let Some(control) = self.reading.take() {
+            self.frames.push((control, decompressed));
+        }
+    }
+
+    pub fn finish(self) -> Result<(AnimationControl, Option<FrameControl>, Vec<FrameData>), DecodeError> {
+        if self.pending.is_some() {
+            return Err(chunk_error(self.control_offset, format!("Frame {} has an fcTL chunk but no image data", self.frame_count - 1)))
+        }
+        if self.frame_count != self.control.num_frames as usize {
+            return Err(chunk_error(self.control_offset, format!(
+                "acTL chunk has {} frames, but there are {} fcTL chunks", self.control.num_frames, self.frame_count
+            )))
+        }
+        Ok((self.control, self.first_frame, self.frames))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::fctl;
+
+    fn frame(width: usize, height: usize, x_offset: usize, y_offset: usize, dispose_op: DisposeOp, blend_op: BlendOp, rgba: &[u8]) -> AnimationFrame {
+        AnimationFrame {
+            control: FrameControl { sequence_number: 0, width, height, x_offset, y_offset, delay_numerator: 1, delay_denominator: 0, dispose_op, blend_op },
+            image: ImageData { width, height, color_type: ColorType::TruecolorAlpha, bit_depth: 8, data: rgba.repeat(width*height) },
+        }
+    }
+
+    #[test]
+    fn test_compositing() {
+        // a 2x1 canvas, red on the left, then half transparent blue over all of it, then green on the right
+        let animation = Animation {
+            control: AnimationControl { num_frames: 3, num_plays: 0 },
+            width: 2,
+            height: 1,
+            frames: vec![
+                frame(1, 1, 0, 0, DisposeOp::None, BlendOp::Source, &[255, 0, 0, 255]),
+                frame(2, 1, 0, 0, DisposeOp::Previous, BlendOp::Over, &[0, 0, 255, 128]),
+                frame(1, 1, 1, 0, DisposeOp::Background, BlendOp::Over, &[0, 255, 0, 255]),
+            ],
+            default_image_is_first_frame: false,
+        };
+
+        let frames: Vec<Frame> = animation.composited().collect();
+        assert_eq!(frames[0].image.data, vec![255, 0, 0, 255, 0, 0, 0, 0]);
+        assert_eq!(frames[1].image.data, vec![127, 0, 128, 255, 0, 0, 255, 128]);
+        // the blue frame is undone, so only the red and green are left
+        assert_eq!(frames[2].image.data, vec![255, 0, 0, 255, 0, 255, 0, 255]);
+        assert_eq!(frames[2].delay, Duration::from_millis(10));
+
+        // the canvas is cleared after the last frame, so a replay would start from the red frame again
+        let mut frames = animation.composited();
+        frames.nth(2);
+        assert_eq!(frames.canvas.data, vec![255, 0, 0, 255, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_frame_bounds() {
+        // a 1x1 frame in a 2x2 image
+        let metadata = PNGMetadata {
+            bit_depth: 8,
+            width: 2,
+            height: 2,
+            color_type: ColorType::TruecolorAlpha,
+            interlace_method: 0,
+            filesize: 0,
+            ancillary: Default::default(),
+            animation: None,
+        };
+        let parse = |x_offset: u32| {
+            let mut file = vec![0; 33];
+            file.extend(fctl(0, (1, 1), (x_offset, 0), 1, 0, 0));
+            let chunk = crate::chunk_reader::ChunkReader::new(&file, 33).next().unwrap().unwrap();
+            FrameControl::parse(&chunk, &metadata)
+        };
+
+        assert_eq!(parse(1).unwrap().x_offset, 1);
+        assert_eq!(parse(2).err().unwrap().to_string(), "PNG chunk error at byte 45: Frame of 1x1 at (2, 0) doesn't fit in the 2x2 image");
+        // an offset that overflows when the width is added
+        assert!(parse(u32::MAX).is_err());
+    }
+}
@@ -1,110 +1,174 @@
-use crate::low_level_functions::bits_to_byte;
-
-// Bit stream that takes a Vec of u8 bytes and can be iterated over, returning the bits, big endian or little endian.
-pub struct BitStream {
-    pub bytes: Vec<u8>,
-    pub byte_position: usize,
-    pub bit_position: u8,  // big endian bit position (0 is 128 value, 7 is 1)
-    big_endian: bool,
+use std::borrow::Cow;
+use crate::error::DecodeError;
+
+// Reads the bits of a deflate stream, where the first bit is the lsb of the first byte.
+// Bytes are loaded into a 64 bit buffer up to 8 at a time, so reading n bits is a shift and a mask.
+// The bytes are borrowed when the whole stream is there, or owned when it is pushed in pieces.
+pub struct BitStream<'a> {
+    pub bytes: Cow<'a, [u8]>,
+    next_byte: usize, // next byte to load into the buffer
+    buffer: u64, // loaded bits that haven't been read, the next bit is the lsb
+    buffer_bits: u32, // number of bits in the buffer, bits above these are either 0 or the bits that come next
+    ran_out: bool, // a read needed more bits than there are
 }
 
 
-impl BitStream {
-    pub fn new(data: Vec<u8>, big_endian: bool) -> Self {
+impl<'a> BitStream<'a> {
+    pub fn new(data: impl Into<Cow<'a, [u8]>>) -> Self {
         Self {
-            bytes: data,
-            byte_position: 0,
-            bit_position: if big_endian {7} else {0},
-            big_endian,
+            bytes: data.into(),
+            next_byte: 0,
+            buffer: 0,
+            buffer_bits: 0,
+            ran_out: false,
         }
     }
 
-    pub fn slice(&self, start: usize, end: usize) -> &[u8] {
-        &self.bytes[start..end]
+    fn refill(&mut self) {
+        if self.next_byte + 8 <= self.bytes.len() {
+            // a whole word, only the bytes that fit are counted but the rest are the right bits for the next refill
+            let word = u64::from_le_bytes(self.bytes[self.next_byte..self.next_byte+8].try_into().unwrap());
+            self.buffer |= word << self.buffer_bits;
+            let loaded_bytes = (63 - self.buffer_bits) / 8;
+            self.next_byte += loaded_bytes as usize;
+            self.buffer_bits += loaded_bytes * 8;
+        } else {
+            // near the end of the data
+            while self.buffer_bits <= 56 && self.next_byte < self.bytes.len() {
+                self.buffer |= (self.bytes[self.next_byte] as u64) << self.buffer_bits;
+                self.next_byte += 1;
+                self.buffer_bits += 8;
+            }
+        }
     }
 
-    pub fn reset_bit_position(&mut self) {
-        self.bit_position = 0;
-        self.byte_position = 0;
+    pub fn current_abs_bit_position(&self) -> usize {
+        self.next_byte*8 - self.buffer_bits as usize
     }
 
-    pub fn advance_bit_counter(&mut self, n: i16) {
-        if self.big_endian {
-            self.bit_position = 7 - self.bit_position;
+    pub fn seek(&mut self, bit_position: usize) {
+        // moves to any bit of the data, and clears ran_out
+        self.next_byte = (bit_position / 8).min(self.bytes.len());
+        self.buffer = 0;
+        self.buffer_bits = 0;
+        self.ran_out = false;
+        if !bit_position.is_multiple_of(8) {
+            self.refill();
+            self.consume((bit_position % 8) as u32);
         }
+    }
 
-        // find bit pos relative to entire stream
-        let abs_bit_pos = self.current_abs_bit_position();
+    pub fn ran_out(&self) -> bool {
+        self.ran_out
+    }
 
-        // calculate new bit pos, either added or subtracted
-        let new_bit_pos: usize;
-        if n < 0 {
-            new_bit_pos = abs_bit_pos - (n.abs() as usize);
-        } else {
-            new_bit_pos = abs_bit_pos + (n as usize);
+    pub fn peek(&mut self, n: u32) -> u32 {
+        // next n (up to 32) bits without moving, bits past the end of the data are 0
+        if self.buffer_bits < n {
+            self.refill();
         }
+        (self.buffer & ((1u64 << n) - 1)) as u32
+    }
 
-        self.byte_position = new_bit_pos / 8;
-        self.bit_position = (new_bit_pos % 8) as u8;
+    pub fn consume(&mut self, n: u32) {
+        // skips bits that have been peeked
+        assert!(n <= self.buffer_bits);
+        self.buffer >>= n;
+        self.buffer_bits -= n;
+    }
 
-        if self.big_endian {
-            self.bit_position = 7 - self.bit_position;
+    pub fn bits(&mut self, n: u32) -> Result<u32, DecodeError> {
+        // next n (up to 32) bits as a number, the first bit is the lsb
+        // errors are reported at the start of the n bits
+        let value = self.peek(n);
+        if self.buffer_bits < n {
+            self.ran_out = true;
+            return Err(DecodeError::DeflateBlock {
+                bit_offset: self.current_abs_bit_position(),
+                reason: format!("Unexpected end of data, {} bits needed", n)
+            })
         }
+        self.consume(n);
+        Ok(value)
     }
 
-    pub fn move_to_next_byte(&mut self) {
-        self.byte_position += 1;
-        self.bit_position = if self.big_endian {7} else {0};
+    pub fn next_bit(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bits(1)? as u8)
     }
 
-    pub fn current_abs_bit_position(&self) -> usize {
-        match self.big_endian {
-            false => (self.byte_position << 3) | (self.bit_position as usize),
-            true => (self.byte_position << 3) | (7-self.bit_position as usize)
-        }
+    pub fn next_byte(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bits(8)? as u8)
     }
 
-    pub fn next_n(&mut self, n: usize) -> Vec<u8> {
-        let mut return_val = Vec::new();
-        for _i in 0..n {
-            return_val.push(self.next().expect("Bit not found in next_n"))
-        }
-        return_val
+    pub fn align_to_byte(&mut self) -> usize {
+        // skips to the start of the next byte, returns the number of bits skipped
+        let skipped = self.buffer_bits % 8;
+        self.consume(skipped);
+        skipped as usize
     }
 
-    pub fn next_byte(&mut self) -> u8 {
-        bits_to_byte(&self.next_n(8), false)
+    pub fn aligned_bytes(&mut self, max: usize) -> &[u8] {
+        // up to max bytes from a byte boundary, fewer if the data ends first
+        assert!(self.current_abs_bit_position().is_multiple_of(8));
+        let start = self.current_abs_bit_position() / 8;
+        let end = (start + max).min(self.bytes.len());
+        self.seek(end*8);
+        &self.bytes[start..end]
     }
 
-    pub fn next_n_bytes(&mut self, n: usize) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        for _byte_n in 0..n {
-            bytes.push(self.next_byte());
-        }
-        bytes
+    pub fn drop_read_bytes(&mut self) -> usize {
+        // removes bytes before the current one from the front of the data, returns the number removed
+        let read_bytes = self.current_abs_bit_position() / 8;
+        self.bytes.to_mut().drain(..read_bytes);
+        self.next_byte -= read_bytes;
+        read_bytes
     }
-}
 
+    // slow path for tokens, which show each bit
 
-impl Iterator for BitStream {
-    type Item = u8;
-    fn next(&mut self) -> Option<Self::Item> {
-        assert!(self.bit_position < 8);
-        let return_val = match self.bytes.get(self.byte_position) {
-            // get bit at bit_position of current_byte
-            Some(current_byte) => Some((current_byte >> self.bit_position) & 1u8),
-            None => None,
-        };
+    pub fn bits_from(&self, start_bit: usize) -> Vec<u8> {
+        // bits from start_bit up to the current position, in the order they were read
+        (start_bit..self.current_abs_bit_position()).map(|bit| (self.bytes[bit/8] >> (bit%8)) & 1).collect()
+    }
 
-        // increment/decrement bit positions and increment byte position
-        self.advance_bit_counter(1);
-        return_val
+    pub fn next_n(&mut self, n: u32) -> Result<Vec<u8>, DecodeError> {
+        let start_bit = self.current_abs_bit_position();
+        self.bits(n)?;
+        Ok(self.bits_from(start_bit))
     }
+}
 
-    fn nth(&mut self, n: usize) -> Option<Self::Item> {
-        match self.bytes.get(n) {
-            Some(byte) => Some(*byte),
-            None => None
-        }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bits() {
+        // 10011010 11011111 00010111, then 8 bytes so a whole word is loaded
+        let mut bs = BitStream::new(vec![154, 223, 23, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(bs.bits(1).unwrap(), 0);
+        assert_eq!(bs.bits(3).unwrap(), 0b101);
+        assert_eq!(bs.next_n(6).unwrap(), vec![1, 0, 0, 1, 1, 1]);
+        assert_eq!(bs.current_abs_bit_position(), 10);
+
+        assert_eq!(bs.align_to_byte(), 6);
+        assert_eq!(bs.bits(32).unwrap(), u32::from_le_bytes([23, 1, 2, 3]));
+        assert_eq!(bs.aligned_bytes(2), &[4, 5]);
+        assert_eq!(bs.next_byte().unwrap(), 6);
+
+        // not enough bits, nothing is read
+        assert!(bs.bits(17).is_err());
+        assert!(bs.ran_out());
+        assert_eq!(bs.current_abs_bit_position(), 72);
+
+        bs.seek(3);
+        assert!(!bs.ran_out());
+        assert_eq!(bs.peek(5), 0b10011);
+        assert_eq!(bs.drop_read_bytes(), 0);
+        bs.seek(17);
+        assert_eq!(bs.drop_read_bytes(), 2);
+        assert_eq!(bs.current_abs_bit_position(), 1);
+        assert_eq!(bs.bits(7).unwrap(), 23 >> 1);
     }
 }
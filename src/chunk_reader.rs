@@ -0,0 +1,147 @@
+use crate::error::DecodeError;
+use crate::low_level_functions::{bytes_vec_to_single, crc_32};
+
+
+// A chunk as it is stored in the file, borrowed from the file data.
+pub struct RawChunk<'a> {
+    pub offset: usize, // byte offset of the chunk length in the file
+    pub length: u32,
+    pub chunk_type: String,
+    bytes: &'a [u8], // the whole chunk, from the length to the crc
+}
+
+impl<'a> RawChunk<'a> {
+    pub fn length_bytes(&self) -> &'a [u8] {
+        &self.bytes[0..4]
+    }
+
+    pub fn type_bytes(&self) -> &'a [u8] {
+        &self.bytes[4..8]
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        &self.bytes[8..self.bytes.len()-4]
+    }
+
+    pub fn crc_bytes(&self) -> &'a [u8] {
+        &self.bytes[self.bytes.len()-4..]
+    }
+
+    pub fn data_offset(&self) -> usize {
+        self.offset + 8
+    }
+
+    pub fn crc_offset(&self) -> usize {
+        self.offset + self.bytes.len() - 4
+    }
+
+    pub fn stored_crc(&self) -> u32 {
+        bytes_vec_to_single(&self.crc_bytes().to_vec())
+    }
+
+    pub fn calculated_crc(&self) -> u32 {
+        // crc-32 is calculated over the chunk type and chunk data
+        crc_32(&self.bytes[4..self.bytes.len()-4])
+    }
+
+    pub fn check_crc(&self) -> Result<(), DecodeError> {
+        let (stored_crc, calculated_crc) = (self.stored_crc(), self.calculated_crc());
+        if stored_crc != calculated_crc {
+            return Err(DecodeError::PngChunk {
+                byte_offset: self.crc_offset(),
+                reason: format!("CRC mismatch in {} chunk, stored {:#010x}, calculated {:#010x}", self.chunk_type, stored_crc, calculated_crc)
+            })
+        }
+        Ok(())
+    }
+}
+
+
+// Reads the chunks after the PNG signature in order, without copying them.
+// Each chunk is checked to fit in the file, and reading stops at the first one that doesn't.
+pub struct ChunkReader<'a> {
+    data: &'a [u8],
+    offset: usize, // byte offset of the next chunk
+}
+
+impl<'a> ChunkReader<'a> {
+    pub fn new(data: &'a [u8], offset: usize) -> Self {
+        Self { data, offset }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn read_chunk(&self) -> Result<RawChunk<'a>, DecodeError> {
+        let chunk = &self.data[self.offset..];
+        if chunk.len() < 12 {
+            return Err(DecodeError::PngChunk {
+                byte_offset: self.offset,
+                reason: format!("Chunk is truncated, only {} bytes left", chunk.len())
+            })
+        }
+
+        let length = bytes_vec_to_single(&chunk[0..4].to_vec());
+        let chunk_type: String = chunk[4..8].iter().map(|x| *x as char).collect();
+        if ((chunk.len() - 12) as u64) < length as u64 {
+            return Err(DecodeError::PngChunk {
+                byte_offset: self.offset,
+                reason: format!("{} chunk is truncated, length is {} but only {} bytes are left", chunk_type, length, chunk.len()-8)
+            })
+        }
+
+        Ok(RawChunk {
+            offset: self.offset,
+            length,
+            chunk_type,
+            bytes: &chunk[..12 + length as usize],
+        })
+    }
+}
+
+impl<'a> Iterator for ChunkReader<'a> {
+    type Item = Result<RawChunk<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.data.len() {
+            return None
+        }
+
+        let chunk = self.read_chunk();
+        match &chunk {
+            Ok(chunk) => self.offset += chunk.bytes.len(),
+            Err(_) => self.offset = self.data.len(),
+        }
+        Some(chunk)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_reader() {
+        // signature, IEND, then a chunk cut off after its type
+        let data = [137, 80, 78, 71, 13, 10, 26, 10, 0, 0, 0, 0, 73, 69, 78, 68, 174, 66, 96, 130, 0, 0, 1, 0, 116, 69, 88, 116, 1, 2, 3, 4];
+        let mut chunks = ChunkReader::new(&data, 8);
+
+        let iend = chunks.next().unwrap().unwrap();
+        assert_eq!((iend.offset, iend.length, iend.chunk_type.as_str()), (8, 0, "IEND"));
+        assert_eq!((iend.data(), iend.crc_bytes(), iend.crc_offset()), (&[][..], &[174, 66, 96, 130][..], 16));
+        assert!(iend.check_crc().is_ok());
+        assert_eq!(chunks.offset(), 20);
+
+        assert_eq!(
+            chunks.next().unwrap().err(),
+            Some(DecodeError::PngChunk { byte_offset: 20, reason: "tEXt chunk is truncated, length is 256 but only 4 bytes are left".to_string() })
+        );
+        assert!(chunks.next().is_none());
+
+        // the first chunk is too short for a length, type and crc
+        let error = ChunkReader::new(&data[..18], 8).next().unwrap().err().unwrap();
+        assert_eq!(error, DecodeError::PngChunk { byte_offset: 8, reason: "Chunk is truncated, only 10 bytes left".to_string() });
+    }
+}
@@ -1,467 +1,386 @@
 use crate::bitstream::BitStream;
+use crate::error::DecodeError;
 use crate::huffman_coding::*;
-use crate::low_level_functions::{bytes_vec_to_single, bits_to_byte};
-use crate::token::{Token, literal_token, reference_token};
+use crate::inflater::Window;
+use crate::low_level_functions::bytes_vec_to_single;
+use crate::sink::{TokenCollector, TokenSink};
+use crate::token::{Alphabet, Token, TokenKind, decode_group, group_token, literal_token, reference_token};
 
 
-fn parse_next_block(data: &mut BitStream, symbol_buffer: &mut Vec<u8>) -> (bool, Vec<Token>) {
-    // given all the remaining bits, add to symbol buffer, return bfinal and tokens
-    let bfinal_byte = data.next().unwrap();
+fn parse_next_block(data: &mut BitStream, window: &mut Window, output: &mut Vec<u8>, tokens: &mut dyn TokenSink) -> Result<bool, DecodeError> {
+    // given all the remaining bits, add to the output and tokens, return bfinal
+    let bfinal_bit_offset = data.current_abs_bit_position();
+    let bfinal_byte = data.next_bit()?;
     let bfinal = bfinal_byte == 1;
 
-    let bfinal_token = Token {
-        bits: vec![bfinal_byte],
-        using_bytes: false,
-        nest_level: 0,
-        data: bfinal_byte.to_string(),
-        token_type: "bfinal".to_string(),
-        description: if bfinal {"final block".to_string()} else {"not final block".to_string()}
-    };
+    tokens.push(
+        Token {
+            bits: vec![bfinal_byte],
+            using_bytes: false,
+            start_bit: bfinal_bit_offset,
+            bit_length: 1,
+            children: vec![],
+            data: bfinal_byte.to_string(),
+            kind: TokenKind::BlockFinal(bfinal),
+            description: if bfinal {"final block".to_string()} else {"not final block".to_string()}
+        }
+    );
 
-    let btype = (data.next().unwrap(), data.next().unwrap());
-    let btype_token = Token {
-        bits: vec![btype.0, btype.1],
-        using_bytes: false,
-        nest_level: 0,
-        data: if btype == (0, 0) {"uncompressed".to_string()} else if btype == (1, 0) {"fixed huffman".to_string()} else if btype == (0, 1) {"dynamic huffman".to_string()} else {"invalid".to_string()},
-        token_type: "btype".to_string(),
-        description: "specifies block compression type".to_string()
-    };
+    let btype_bit_offset = data.current_abs_bit_position();
+    let btype = data.bits(2)? as u8;
+    if btype == 3 {
+        return Err(DecodeError::DeflateBlock {
+            bit_offset: btype_bit_offset,
+            reason: "BTYPE has reserved value (11)".to_string()
+        })
+    }
 
-    let mut decompressing_tokens = match btype {
-        (0, 0) => deflate_uncompressed_block(data, symbol_buffer),
-        (1, 0) => deflate_fixed_huffman_block(data, symbol_buffer),
-        (0, 1) => deflate_dynamic_huffman_block(data, symbol_buffer),
-        (1, 1) => panic!("BTYPE has reserved value (11)"),
-        _ => panic!("Invalid BTYPE")
-    };
+    tokens.push(
+        Token {
+            bits: data.bits_from(btype_bit_offset),
+            using_bytes: false,
+            start_bit: btype_bit_offset,
+            bit_length: 2,
+            children: vec![],
+            data: if btype == 0 {"uncompressed".to_string()} else if btype == 1 {"fixed huffman".to_string()} else {"dynamic huffman".to_string()},
+            kind: TokenKind::BlockType(btype),
+            description: "specifies block compression type".to_string()
+        }
+    );
 
-    decompressing_tokens.insert(0, bfinal_token);
-    decompressing_tokens.insert(1, btype_token);
+    match btype {
+        0 => deflate_uncompressed_block(data, window, output, tokens)?,
+        1 => deflate_fixed_huffman_block(data, window, output, tokens)?,
+        _ => deflate_dynamic_huffman_block(data, window, output, tokens)?,
+    };
 
-    (bfinal, decompressing_tokens)
+    Ok(bfinal)
 }
 
-fn deflate_uncompressed_block(data: &mut BitStream, symbol_buffer: &mut Vec<u8>) -> Vec<Token> {
-    let mut tokens: Vec<Token> = Vec::new();
-    
-    if data.bit_position != 0 {
+fn deflate_uncompressed_block(data: &mut BitStream, window: &mut Window, output: &mut Vec<u8>, tokens: &mut dyn TokenSink) -> Result<(), DecodeError> {
+    let padding_start = data.current_abs_bit_position();
+    let padding = data.align_to_byte();
+    if padding > 0 {
         let padding_token = Token {
-            bits: vec![0; (8-data.bit_position) as usize],
+            bits: vec![0; padding],
             using_bytes: false,
-            nest_level: 0,
+            start_bit: padding_start,
+            bit_length: padding,
+            children: vec![],
             data: "padding".to_string(),
-            token_type: "padding".to_string(),
+            kind: TokenKind::Padding,
             description: "padding to next byte".to_string(),
         };
 
         tokens.push(padding_token);
-    
-        data.move_to_next_byte();
     }
     
     // next 16 bits (2 bytes) are length
-    let length_bytes = ( data.next_byte(), data.next_byte() );
+    let length_bit_offset = data.current_abs_bit_position();
+    let length_bytes = ( data.next_byte()?, data.next_byte()? );
 
     // check against backup length (next 2 bytes), which is the bitwise NOT of len
-    let compliment_bytes = ( data.next_byte(), data.next_byte() );
+    let compliment_bytes = ( data.next_byte()?, data.next_byte()? );
 
-    assert_eq!(length_bytes, (!compliment_bytes.0, !compliment_bytes.1));
+    if length_bytes != (!compliment_bytes.0, !compliment_bytes.1) {
+        return Err(DecodeError::DeflateBlock {
+            bit_offset: length_bit_offset,
+            reason: format!("LEN {:?} is not the complement of NLEN {:?}", length_bytes, compliment_bytes)
+        })
+    }
 
     let length = bytes_vec_to_single(&vec![length_bytes.1, length_bytes.0]) as usize;
 
     let length_token = Token {
         bits: vec![length_bytes.0, length_bytes.1],
         using_bytes: true,
-        nest_level: 0,
+        start_bit: length_bit_offset,
+        bit_length: 16,
+        children: vec![],
         data: length.to_string(),
-        token_type: "bytes length".to_string(),
+        kind: TokenKind::StoredLength(length as u16),
         description: "number of bytes to read from block".to_string(),
     };
 
     let length_compliment_token = Token {
         bits: vec![compliment_bytes.0, compliment_bytes.1],
         using_bytes: true,
-        nest_level: 0,
+        start_bit: length_bit_offset + 16,
+        bit_length: 16,
+        children: vec![],
         data: "n/a".to_string(),
-        token_type: "complement bytes".to_string(),
+        kind: TokenKind::StoredLengthComplement(!(length as u16)),
         description: "bytes length with flipped bits".to_string(),
     };
 
     tokens.push(length_token);
     tokens.push(length_compliment_token);
 
-    for _l in 0..length {
-        let next_byte = data.next_byte();
-
-        let token = literal_token(next_byte, None, 0);
-        tokens.push(token);
-
-        symbol_buffer.push(next_byte)
-    }
-
-    tokens
-}
-
-
-fn deflate_fixed_huffman_block(data: &mut BitStream, symbol_buffer: &mut Vec<u8>) -> Vec<Token> {
-    let mut tokens: Vec<Token> = Vec::new();
-    loop {
-        let (symbol, bits) = next_fixed_huffman_symbol(data);
-        if symbol > 256 {
-            let (extra_length_bits, length) = decode_length(data, symbol);
-            let distance_symbol_bits = data.next_n(5);
-            let distance_symbol = bits_to_byte(&distance_symbol_bits, true);
-            let (extra_distance_bits, distance) = decode_distance(data, distance_symbol);
-
-            let duplicate_values = decode_duplicate_reference(symbol_buffer, length, distance);
-
-            // bits + extra_length_bits + distance_symbol_bits + extra_distance_bits
-            tokens.push(reference_token([bits, extra_length_bits, distance_symbol_bits, extra_distance_bits].concat(), distance, length, 0));
+    let symbols = group_token(TokenKind::Symbols, format!("{} bytes", length), "Bytes stored without compression", data.current_abs_bit_position());
+    decode_group(tokens, symbols, |tokens| {
+        for _l in 0..length {
+            let start_bit = data.current_abs_bit_position();
+            let next_byte = data.next_byte()?;
 
-            symbol_buffer.extend(duplicate_values);
-        }
-        else if symbol == 256 {
-            tokens.push(
-                Token {
-                    bits,
-                    using_bytes: false,
-                    nest_level: 0,
-                    data: "256".to_string(),
-                    token_type: "end of block".to_string(),
-                    description: "All data from block has been decoded".to_string()
-                }
-            );
-            break
-        } else {
-            let symbol = symbol as u8;
-
-            let token = literal_token(symbol, Some(bits), 0);
+            let token = literal_token(next_byte, None, start_bit);
             tokens.push(token);
 
-            symbol_buffer.push(symbol);
+            window.push(next_byte, output)
         }
-    }
-    tokens
+        Ok(())
+    })
 }
 
-fn decode_codelengths(data: &mut BitStream, num_of_codes: usize, code_length_prefixes: &Vec<u16>, code_length_symbols: &Vec<u16>, code_length_codelengths: &Vec<u8>) -> (Vec<u8>, Vec<Token>) {
-    // given huffman codes (symbols and prefixes) for the codelength table, decode a given number of codes from the bitstream
 
-    let mut tokens: Vec<Token> = Vec::new();
-    let mut decoded_codelengths: Vec<u8> = Vec::new();
+fn deflate_fixed_huffman_block(data: &mut BitStream, window: &mut Window, output: &mut Vec<u8>, tokens: &mut dyn TokenSink) -> Result<(), DecodeError> {
+    let symbols = group_token(TokenKind::Symbols, "fixed Huffman codes".to_string(), "Literals and string references in this block", data.current_abs_bit_position());
+    decode_group(tokens, symbols, |tokens| deflate_symbols(data, fixed_literal_length_table(), fixed_distance_table(), window, output, tokens))
+}
 
+fn deflate_symbols(
+    data: &mut BitStream, literal_length_table: &HuffmanTable, distance_table: &HuffmanTable, window: &mut Window, output: &mut Vec<u8>, tokens: &mut dyn TokenSink
+) -> Result<(), DecodeError> {
+    // symbols of a fixed or dynamic block up to the end of block code, decoded the same way as the inflater with a token for each
     loop {
-        let mut prefix_code: u16 = 0;
-        let mut prefix_code_bits: Vec<u8> = Vec::new();
-        let mut current_codelength = 0;
-
-        loop {
-            // read next bit to prefix code
-            let next_bit = data.next().unwrap();
-            prefix_code_bits.push(next_bit);
-
-            prefix_code = (prefix_code << 1) | (next_bit as u16);
-            current_codelength += 1;
-
-            // valid prefixes are where the codelength of this prefix == current_codelength
-            let mut valid_prefixes = code_length_prefixes
-                .iter()
-                .cloned()
-                .enumerate()
-                .filter(
-                    |(i, _)| *code_length_codelengths.get(*i).unwrap() == current_codelength
-                );
-
-            let prefix_position = valid_prefixes.position(|(_, x)| x == prefix_code);
-            
-            if prefix_position.is_none() { continue }
-
-            // get position, and use position to index code_length_symbols to find the symbol
-            let symbol = *code_length_symbols.get( code_length_prefixes.iter().position(|&x| x == prefix_code).unwrap() ).unwrap() as u8; // code length symbols <= 18
-
-            match symbol {
-                0..=15 => {
-                    // literal code length
-                    decoded_codelengths.push(symbol);
-                    tokens.push(
-                        literal_token(symbol, Some(prefix_code_bits), 0)
-                    );
-                },
-                16 => {
-                    // Copy the previous code length 3-6 times, 2 extra bits
-                    let &prev_symbol = decoded_codelengths.last().unwrap();
-                    let next_bits = data.next_n(2);
-                    let repitions = (bits_to_byte(&next_bits, false) >> 6u8) + 3;
-
-                    prefix_code_bits.extend(next_bits);
-                    
-                    tokens.push(reference_token(prefix_code_bits, 1, repitions as u16, 0));
-
-                    for _ in 0..repitions {
-                        decoded_codelengths.push(prev_symbol);
-                    }
-                },
-                17 => {
-                    // Copy 0 3-10 times, 3 extra bits
-                    let next_bits = data.next_n(3);
-                    
-                    let repitions = (bits_to_byte(&next_bits, false) >> 5u8) + 3;
-
-                    let zero_vector = vec![0, repitions];
-                    prefix_code_bits.extend(next_bits);
-                    tokens.push(
-                        Token {
-                            bits: prefix_code_bits,
-                            using_bytes: false,
-                            nest_level: 0,
-                            data: format!("{:?}", zero_vector),
-                            token_type: "repeated_0".to_string(),
-                            description: "Repeat 0 3-10 times".to_string(),
-                        }
-                    );
-
-                    for _ in 0..repitions {
-                        decoded_codelengths.push(0);
+        let start_bit = data.current_abs_bit_position();
+        match decode_symbol(data, literal_length_table, distance_table)? {
+            Symbol::Literal(byte) => {
+                tokens.push(literal_token(byte, Some(data.bits_from(start_bit)), start_bit));
+                window.push(byte, output);
+            },
+            Symbol::EndOfBlock => {
+                let bits = data.bits_from(start_bit);
+                tokens.push(
+                    Token {
+                        bit_length: bits.len(),
+                        bits,
+                        using_bytes: false,
+                        start_bit,
+                        children: vec![],
+                        data: "256".to_string(),
+                        kind: TokenKind::EndOfBlock,
+                        description: "All data from block has been decoded".to_string()
                     }
-                },
-                18 => {
-                    // Copy 0 11-138 times, 7 extra bits
-                    let next_bits = data.next_n(7);
-
-                    let repitions = (bits_to_byte(&next_bits, false) >> 1u8) + 11;
-
-                    let zero_vector = vec![0, repitions];
-                    prefix_code_bits.extend(next_bits);
-                    tokens.push(
-                        Token {
-                            bits: prefix_code_bits,
-                            using_bytes: false,
-                            nest_level: 0,
-                            data: format!("{:?}", zero_vector),
-                            token_type: "repeated_0_long".to_string(),
-                            description: "Repeat 0 11-138 times".to_string(),
-                        }
-                    );
-
-                    for _ in 0..repitions {
-                        decoded_codelengths.push(0);
-                    }
-                }
-                _ => panic!("Invalid code length symbol")
-            }
-
-            break;
+                );
+                return Ok(())
+            },
+            Symbol::Reference { length, distance } => {
+                window.reference(length, distance, start_bit, output)?;
+
+                // length code, extra length bits, distance code and extra distance bits
+                tokens.push(reference_token(data.bits_from(start_bit), distance, length, start_bit));
+            },
         }
-
-        if decoded_codelengths.len() >= num_of_codes {break}
     }
-    (decoded_codelengths, tokens)
 }
 
-fn deflate_dynamic_huffman_block(data: &mut BitStream, symbol_buffer: &mut Vec<u8>) -> Vec<Token> {
-    let mut tokens: Vec<Token> = Vec::new();
-
-    let num_of_normal_codes_bits = data.next_n(5);
-
-    let num_of_normal_codes = ((bits_to_byte(&num_of_normal_codes_bits, false) >> 3) as u16 + 257) as usize;
+fn decode_codelengths(data: &mut BitStream, decoded_codelengths: &mut Vec<u8>, num_of_codes: usize, max_codes: usize, code_length_table: &HuffmanTable, tokens: &mut dyn TokenSink) -> Result<(), DecodeError> {
+    // given the huffman table for the codelength alphabet, decode codes from the bitstream until there are num_of_codes, with a token for each
+    read_codelengths(data, decoded_codelengths, num_of_codes, max_codes, code_length_table, |data, symbol, repetitions, code_bit_offset| {
+        let bits = data.bits_from(code_bit_offset);
+        let token = match symbol {
+            // literal code length
+            0..=15 => literal_token(symbol, Some(bits), code_bit_offset),
+            // copy the previous code length
+            16 => reference_token(bits, 1, repetitions as u16, code_bit_offset),
+            // copy 0, 17 has 3 extra bits and 18 has 7
+            _ => Token {
+                bit_length: bits.len(),
+                bits,
+                using_bytes: false,
+                start_bit: code_bit_offset,
+                children: vec![],
+                data: format!("{:?}", vec![0, repetitions]),
+                kind: if symbol == 17 { TokenKind::RepeatedZero(repetitions) } else { TokenKind::RepeatedZeroLong(repetitions) },
+                description: if symbol == 17 { "Repeat 0 3-10 times".to_string() } else { "Repeat 0 11-138 times".to_string() },
+            },
+        };
+        tokens.push(token);
+    })
+}
 
+fn table_tokens(table: &HuffmanTable, alphabet: Alphabet, name: &str, start_bit: usize, tokens: &mut dyn TokenSink) {
+    // symbols and prefixes of a huffman table, these don't use any bits
     tokens.push(
         Token {
-            bits: num_of_normal_codes_bits,
+            bits: vec![],
             using_bytes: false,
-            nest_level: 0,
-            data: num_of_normal_codes.to_string(),
-            token_type: "hlit".to_string(),
-            description: "# of Literal/Length codes".to_string(),
+            start_bit,
+            bit_length: 0,
+            children: vec![],
+            data: format!("{:?}", table.symbols),
+            kind: TokenKind::HuffmanSymbols(alphabet),
+            description: format!("Symbols for {} alphabet", name.replace('_', "/")),
         }
     );
-
-    let num_of_dist_codes_bits = data.next_n(5);
-    let num_of_dist_codes = ((bits_to_byte(&num_of_dist_codes_bits, false) >> 3) + 1) as usize;
-
     tokens.push(
         Token {
-            bits: num_of_dist_codes_bits,
+            bits: vec![],
             using_bytes: false,
-            nest_level: 0,
-            data: num_of_dist_codes.to_string(),
-            token_type: "hdist".to_string(),
-            description: "# of Distance codes".to_string(),
+            start_bit,
+            bit_length: 0,
+            children: vec![],
+            data: format!("{:?}", table.prefixes),
+            kind: TokenKind::HuffmanPrefixes(alphabet),
+            description: format!("Prefixes for {} alphabet in base 10", name.replace('_', "/")),
         }
     );
+}
 
-    // 1) Parse codelength huffman codes
-    let num_of_codelength_codes_bits = data.next_n(4);
-    let num_of_codelength_codes = ((bits_to_byte(&num_of_codelength_codes_bits, false) >> 4) + 4) as usize;
+pub fn dynamic_huffman_header(data: &mut BitStream, tokens: &mut dyn TokenSink) -> Result<(HuffmanTable, HuffmanTable), DecodeError> {
+    // reads the code tables at the start of a dynamic huffman block, returns the literal/length and distance tables
+    let num_of_normal_codes_bit_offset = data.current_abs_bit_position();
+    let num_of_normal_codes = data.bits(5)? as usize + 257;
 
     tokens.push(
         Token {
-            bits: num_of_codelength_codes_bits,
+            bits: data.bits_from(num_of_normal_codes_bit_offset),
             using_bytes: false,
-            nest_level: 0,
-            data: num_of_codelength_codes.to_string(),
-            token_type: "hclen".to_string(),
-            description: "# of Code Length codes".to_string(),
+            start_bit: num_of_normal_codes_bit_offset,
+            bit_length: 5,
+            children: vec![],
+            data: num_of_normal_codes.to_string(),
+            kind: TokenKind::LiteralLengthCount(num_of_normal_codes),
+            description: "# of Literal/Length codes".to_string(),
         }
     );
 
-    // reorder codelength codelengths
-    const ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
-    let mut code_length_codelengths = vec![0u8; 19];
-    let mut code_length_codelengths_bits = Vec::new();
-
-    for i in 0..(num_of_codelength_codes) {
-        let bits = data.next_n(3);
-        let codelength_codelength = bits_to_byte(&bits, false) >> 5;
-
-        code_length_codelengths_bits.extend(bits);
-
-        code_length_codelengths[ORDER[i]] = codelength_codelength;
-    }
-
-    tokens.push(
-        Token {
-            bits: code_length_codelengths_bits,
-            using_bytes: false,
-            nest_level: 0,
-            data: format!("{:?}", code_length_codelengths),
-            token_type: "clen_codelengths".to_string(),
-            description: "Codelengths for codelength alphabet, reordered.".to_string(),
-        }
-    );
+    let num_of_dist_codes_bit_offset = data.current_abs_bit_position();
+    let num_of_dist_codes = data.bits(5)? as usize + 1;
 
-    let (code_length_symbols, code_length_prefixes) = huffman_codes_from_codelengths(&code_length_codelengths);
-    tokens.push(
-        Token {
-            bits: vec![],
-            using_bytes: false,
-            nest_level: 0,
-            data: format!("{:?}", code_length_symbols),
-            token_type: "cl_symbols".to_string(),
-            description: "Symbols for codelength alphabet".to_string(),
-        }
-    );
     tokens.push(
         Token {
-            bits: vec![],
+            bits: data.bits_from(num_of_dist_codes_bit_offset),
             using_bytes: false,
-            nest_level: 0,
-            data: format!("{:?}", code_length_prefixes),
-            token_type: "cl_prefixes".to_string(),
-            description: "Prefixes for codelength alphabet in base 10".to_string(),
+            start_bit: num_of_dist_codes_bit_offset,
+            bit_length: 5,
+            children: vec![],
+            data: num_of_dist_codes.to_string(),
+            kind: TokenKind::DistanceCount(num_of_dist_codes),
+            description: "# of Distance codes".to_string(),
         }
     );
 
-    // 2) Parse main huffman codelengths
-    
-    let filtered_code_length_codelengths = code_length_codelengths.iter().cloned().filter(|&x| x > 0).collect();
-    let (decoded_normal_codelengths, decoded_normal_codelengths_tokens) = decode_codelengths(data, num_of_normal_codes, &code_length_prefixes, &code_length_symbols, &filtered_code_length_codelengths);
-    let (decoded_distance_codelengths, decoded_distance_codelengths_tokens) = decode_codelengths(data, num_of_dist_codes, &code_length_prefixes, &code_length_symbols, &filtered_code_length_codelengths);
-    tokens.extend(decoded_normal_codelengths_tokens);
-    tokens.extend(decoded_distance_codelengths_tokens);
-
-    let (huffman_normal_symbols, huffman_normal_prefixes) = huffman_codes_from_codelengths(&decoded_normal_codelengths);
+    // 1) Parse codelength huffman codes
+    let num_of_codelength_codes_bit_offset = data.current_abs_bit_position();
+    let num_of_codelength_codes = data.bits(4)? as usize + 4;
 
     tokens.push(
         Token {
-            bits: vec![],
-            using_bytes: false,
-            nest_level: 0,
-            data: format!("{:?}", huffman_normal_symbols),
-            token_type: "literal_length_symbols".to_string(),
-            description: "Symbols for literal/length alphabet".to_string(),
-        }
-    );
-    tokens.push(
-        Token {
-            bits: vec![],
+            bits: data.bits_from(num_of_codelength_codes_bit_offset),
             using_bytes: false,
-            nest_level: 0,
-            data: format!("{:?}", huffman_normal_prefixes),
-            token_type: "literal_length_prefixes".to_string(),
-            description: "Prefixes for literal/length alphabet in base 10".to_string(),
+            start_bit: num_of_codelength_codes_bit_offset,
+            bit_length: 4,
+            children: vec![],
+            data: num_of_codelength_codes.to_string(),
+            kind: TokenKind::CodeLengthCount(num_of_codelength_codes),
+            description: "# of Code Length codes".to_string(),
         }
     );
-    let huffman_normal_codelengths: Vec<u8> = decoded_normal_codelengths.iter().cloned().filter(|&x| x > 0).collect();
 
-    let (huffman_distance_symbols, huffman_distance_prefixes) = huffman_codes_from_codelengths(&decoded_distance_codelengths);
+    let code_length_table_group = group_token(TokenKind::HuffmanTable(Alphabet::CodeLength), "code length codes".to_string(), "Huffman codes used to compress the other tables' codelengths", data.current_abs_bit_position());
+    let code_length_table = decode_group(tokens, code_length_table_group, |tokens| {
+        // reorder codelength codelengths
+        let code_length_codelengths_bit_offset = data.current_abs_bit_position();
+        let code_length_codelengths = read_code_length_codelengths(data, num_of_codelength_codes)?;
+        let code_length_codelengths_bits = data.bits_from(code_length_codelengths_bit_offset);
 
-    tokens.push(
-        Token {
-            bits: vec![],
-            using_bytes: false,
-            nest_level: 0,
-            data: format!("{:?}", huffman_distance_symbols),
-            token_type: "distance_symbols".to_string(),
-            description: "Symbols for distance alphabet".to_string(),
-        }
-    );
-    tokens.push(
-        Token {
-            bits: vec![],
-            using_bytes: false,
-            nest_level: 0,
-            data: format!("{:?}", huffman_distance_prefixes),
-            token_type: "distance_prefixes".to_string(),
-            description: "Prefixes for distance alphabet".to_string(),
-        }
-    );
+        tokens.push(
+            Token {
+                bit_length: code_length_codelengths_bits.len(),
+                bits: code_length_codelengths_bits,
+                using_bytes: false,
+                start_bit: code_length_codelengths_bit_offset,
+                children: vec![],
+                data: format!("{:?}", code_length_codelengths),
+                kind: TokenKind::CodeLengthCodelengths,
+                description: "Codelengths for codelength alphabet, reordered.".to_string(),
+            }
+        );
 
-    let huffman_distance_codelengths: Vec<u8> = decoded_distance_codelengths.iter().cloned().filter(|&x| x > 0).collect();
+        check_codelengths(&code_length_codelengths, Alphabet::CodeLength, code_length_codelengths_bit_offset)?;
+        let code_length_table = huffman_table(&code_length_codelengths);
+        tokens.push(
+            Token {
+                bits: vec![],
+                using_bytes: false,
+                start_bit: data.current_abs_bit_position(),
+                bit_length: 0,
+                children: vec![],
+                data: format!("{:?}", code_length_table.symbols),
+                kind: TokenKind::HuffmanSymbols(Alphabet::CodeLength),
+                description: "Symbols for codelength alphabet".to_string(),
+            }
+        );
+        tokens.push(
+            Token {
+                bits: vec![],
+                using_bytes: false,
+                start_bit: data.current_abs_bit_position(),
+                bit_length: 0,
+                children: vec![],
+                data: format!("{:?}", code_length_table.prefixes),
+                kind: TokenKind::HuffmanPrefixes(Alphabet::CodeLength),
+                description: "Prefixes for codelength alphabet in base 10".to_string(),
+            }
+        );
 
-    // 3) Parse data using huffman codes
-    loop {
-        let (symbol, symbol_bits) = next_huffman_symbol(data, &huffman_normal_symbols, &huffman_normal_prefixes, &huffman_normal_codelengths, true);
-        if symbol > 256 {
-            let (extra_length_bits, length) = decode_length(data, symbol);
+        Ok(code_length_table)
+    })?;
 
-            let (distance_symbol, distance_symbol_bits) = next_huffman_symbol(data, &huffman_distance_symbols, &huffman_distance_prefixes, &huffman_distance_codelengths, true);
+    // 2) Parse main huffman codelengths
+    let literal_length_table_group = group_token(TokenKind::HuffmanTable(Alphabet::LiteralLength), format!("{} codes", num_of_normal_codes), "Codelengths of the literal/length alphabet, and the Huffman codes made from them", data.current_abs_bit_position());
+    let max_codes = num_of_normal_codes + num_of_dist_codes;
+    let mut codelengths = Vec::with_capacity(max_codes);
+    let literal_length_table = decode_group(tokens, literal_length_table_group, |tokens| {
+        let table_bit_offset = data.current_abs_bit_position();
+        decode_codelengths(data, &mut codelengths, num_of_normal_codes, max_codes, &code_length_table, tokens)?;
+        check_codelengths(&codelengths[..num_of_normal_codes], Alphabet::LiteralLength, table_bit_offset)?;
+        let table = huffman_table(&codelengths[..num_of_normal_codes]);
+        table_tokens(&table, Alphabet::LiteralLength, "literal_length", data.current_abs_bit_position(), tokens);
+        Ok(table)
+    })?;
+
+    let distance_table_group = group_token(TokenKind::HuffmanTable(Alphabet::Distance), format!("{} codes", num_of_dist_codes), "Codelengths of the distance alphabet, and the Huffman codes made from them", data.current_abs_bit_position());
+    let distance_table = decode_group(tokens, distance_table_group, |tokens| {
+        // a repeat at the end of the literal/length codelengths may have already given some of these
+        let table_bit_offset = data.current_abs_bit_position();
+        decode_codelengths(data, &mut codelengths, max_codes, max_codes, &code_length_table, tokens)?;
+        check_codelengths(&codelengths[num_of_normal_codes..], Alphabet::Distance, table_bit_offset)?;
+        let table = huffman_table(&codelengths[num_of_normal_codes..]);
+        table_tokens(&table, Alphabet::Distance, "distance", data.current_abs_bit_position(), tokens);
+        Ok(table)
+    })?;
+
+    Ok((literal_length_table, distance_table))
+}
 
-            let (extra_distance_bits, distance) = decode_distance(data, distance_symbol as u8);
-            let duplicate_values = decode_duplicate_reference(symbol_buffer, length, distance);
-            symbol_buffer.extend(duplicate_values);
+fn deflate_dynamic_huffman_block(data: &mut BitStream, window: &mut Window, output: &mut Vec<u8>, tokens: &mut dyn TokenSink) -> Result<(), DecodeError> {
+    let header = group_token(TokenKind::HuffmanHeader, "code tables".to_string(), "Huffman code tables for this block", data.current_abs_bit_position());
+    let (literal_length_table, distance_table) = decode_group(tokens, header, |tokens| dynamic_huffman_header(data, tokens))?;
 
-            // bits + extra_length_bits + distance_symbol_bits + extra_distance_bits
-            let all_bits = [symbol_bits, extra_length_bits, distance_symbol_bits, extra_distance_bits].concat();
+    // 3) Parse data using huffman codes
+    let symbols = group_token(TokenKind::Symbols, "dynamic Huffman codes".to_string(), "Literals and string references in this block", data.current_abs_bit_position());
+    decode_group(tokens, symbols, |tokens| deflate_symbols(data, &literal_length_table, &distance_table, window, output, tokens))
+}
 
-            tokens.push(
-                reference_token(
-                    all_bits, distance, length, 0
-                )
-            )
-        }
-        else if symbol == 256 {
-            tokens.push(
-                Token {
-                    bits: symbol_bits,
-                    using_bytes: false,
-                    nest_level: 0,
-                    data: "256".to_string(),
-                    token_type: "end of block".to_string(),
-                    description: "All data from block has been decoded".to_string()
-                }
-            );
-            break
-        } else {
-            let symbol = symbol as u8;
-            tokens.push( literal_token(symbol, Some(symbol_bits), 0) );
-            symbol_buffer.push(symbol as u8);
-        }
-    }
-    tokens
+pub fn new_parse_deflate(data: &[u8]) -> Result<(Vec<Token>, Vec<u8>), DecodeError> {
+    // tokens are a group for each block, followed by any padding
+    let mut tokens = TokenCollector::new();
+    let decompressed_data = parse_deflate_into(data, &mut tokens)?;
+    Ok((tokens.into_tokens(), decompressed_data))
 }
 
-pub fn new_parse_deflate(data: Vec<u8>) -> (Vec<Token>, Vec<u8>) {
-    let mut bit_stream = BitStream::new(data, false);
+pub fn parse_deflate_into(data: &[u8], all_tokens: &mut dyn TokenSink) -> Result<Vec<u8>, DecodeError> {
+    // same as new_parse_deflate, tokens are added to all_tokens as they are decoded so they're kept if there is an error
+    let mut bit_stream = BitStream::new(data);
 
-    let mut all_tokens: Vec<Token> = Vec::new();
+    let mut window = Window::new();
     let mut decompressed_data = Vec::new();
 
-    loop {
-        let (bfinal, tokens) = parse_next_block(&mut bit_stream, &mut decompressed_data);
-
-        all_tokens.extend(tokens);
+    for block_number in 1.. {
+        let block = group_token(TokenKind::DeflateBlock { number: block_number }, format!("block {}", block_number), "Deflate block, with its header and the symbols in it", bit_stream.current_abs_bit_position());
+        let bfinal = decode_group(all_tokens, block, |tokens| parse_next_block(&mut bit_stream, &mut window, &mut decompressed_data, tokens))?;
 
         if bfinal {
             break
@@ -475,15 +394,17 @@ pub fn new_parse_deflate(data: Vec<u8>) -> (Vec<Token>, Vec<u8>) {
             Token {
                 bits: vec![0; padding],
                 using_bytes: false,
-                nest_level: 0,
+                start_bit: bit_stream.current_abs_bit_position(),
+                bit_length: padding,
+                children: vec![],
                 data: "End of deflate padding".to_string(),
-                token_type: "padding".to_string(),
+                kind: TokenKind::Padding,
                 description: "Padding after deflate stream to next byte boundary".to_string()
             }
         )
     }
 
-    (all_tokens, decompressed_data)
+    Ok(decompressed_data)
 }
 
 #[cfg(test)]
@@ -495,9 +416,87 @@ mod tests {
         // 00011101 11000110 01001001 00000001 00000000 00000000 00010000 01000000 11000000 10101100 10100011 01111111 10001000 00111101 00111100 00100000 00101010 10010111 10011101 00110111 01011110 00011101 00001100
         let data = vec![29, 198, 73, 1, 0, 0, 16, 64, 192, 172, 163, 127, 136, 61, 60, 32, 42, 151, 157, 55, 94, 29, 12];
 
-        let (tokens, decompressed) = new_parse_deflate(data);
+        let (tokens, decompressed) = new_parse_deflate(&data).unwrap();
 
         println!("{:?}", tokens);
         assert_eq!(decompressed,  vec![97, 98, 97, 97, 98, 98, 98, 97, 98, 97, 97, 98, 97, 98, 98, 97, 97, 98, 97, 98, 97, 97, 97, 97, 98, 97, 97, 97, 98, 98, 98, 98, 98, 97, 97]);
     }
+
+    fn pack_bits(fields: &[(u32, u32)]) -> Vec<u8> {
+        // (value, number of bits) fields, each written lsb first
+        let mut bytes = vec![];
+        let mut bit = 0;
+        for &(value, length) in fields {
+            for i in 0..length {
+                if bit % 8 == 0 {
+                    bytes.push(0);
+                }
+                *bytes.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (bit % 8);
+                bit += 1;
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_invalid_code_lengths() {
+        // final dynamic block, 257 literal/length codes, 1 distance code, and 4 code length codes (for 16, 17, 18 and 0)
+        let header = [(1, 1), (2, 2), (0, 5), (0, 5), (0, 4)];
+
+        // every code length code is 1 bit
+        let data = pack_bits(&[&header[..], &[(1, 3); 4]].concat());
+        let error = new_parse_deflate(&data).unwrap_err();
+        assert_eq!(error.bit_offset(), 17);
+        assert!(error.to_string().contains("Code length code lengths are over-subscribed"), "{}", error);
+        assert_eq!(crate::inflate(&data).unwrap_err(), error);
+
+        // code length codes are 2 bits, 18 is 11, then every literal/length code is repeated 0
+        let data = pack_bits(&[&header[..], &[(2, 3); 4], &[(3, 2), (127, 7), (3, 2), (108, 7), (0, 8)]].concat());
+        let error = new_parse_deflate(&data).unwrap_err();
+        assert_eq!(error.bit_offset(), 29);
+        assert!(error.to_string().contains("Literal/length code lengths are incomplete, 0 codes"), "{}", error);
+        assert_eq!(crate::inflate(&data).unwrap_err(), error);
+
+        // repeat goes past the 258 literal/length and distance codelengths
+        let data = pack_bits(&[&header[..], &[(2, 3); 4], &[(3, 2), (127, 7), (3, 2), (127, 7), (0, 8)]].concat());
+        let error = new_parse_deflate(&data).unwrap_err();
+        assert_eq!(error, DecodeError::HuffmanTable {
+            bit_offset: 38,
+            reason: "Code length repeat gives 276 codelengths, but there are only 258 literal/length and distance codes".to_string()
+        });
+        assert_eq!(crate::inflate(&data).unwrap_err(), error);
+    }
+
+    fn huffman_code(code: u32, length: u32) -> (u32, u32) {
+        // Huffman codes are packed msb first
+        (code.reverse_bits() >> (32 - length), length)
+    }
+
+    #[test]
+    fn test_invalid_references() {
+        // final fixed block, then a literal 'a' (0x30 + 97) so there is 1 byte for references to copy
+        let header = [(1, 1), (1, 2)];
+        let literal = huffman_code(0x30 + 97, 8);
+
+        // length symbol 286 has a code, but isn't a length
+        let data = pack_bits(&[&header[..], &[huffman_code(0b11000110, 8)]].concat());
+        let error = new_parse_deflate(&data).unwrap_err();
+        assert_eq!(error, DecodeError::DeflateBlock { bit_offset: 3, reason: "Invalid length symbol 286, only 257-285 are lengths".to_string() });
+        assert_eq!(crate::inflate(&data).unwrap_err(), error);
+
+        // length 3, then distance code 30, which is reported where its code starts
+        let data = pack_bits(&[&header[..], &[literal, huffman_code(1, 7), huffman_code(30, 5)]].concat());
+        let error = new_parse_deflate(&data).unwrap_err();
+        assert_eq!(error, DecodeError::DeflateBlock { bit_offset: 18, reason: "Invalid distance symbol 30, only 0-29 are distances".to_string() });
+        assert_eq!(crate::inflate(&data).unwrap_err(), error);
+
+        // length 3, distance 2, with only 1 byte decoded
+        let data = pack_bits(&[&header[..], &[literal, huffman_code(1, 7), huffman_code(1, 5)]].concat());
+        let error = new_parse_deflate(&data).unwrap_err();
+        assert_eq!(error, DecodeError::DeflateBlock {
+            bit_offset: 11,
+            reason: "String reference distance 2 is before the start of the data, 1 bytes have been decoded".to_string()
+        });
+        assert_eq!(crate::inflate(&data).unwrap_err(), error);
+    }
 }
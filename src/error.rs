@@ -0,0 +1,65 @@
+use std::fmt::Display;
+
+// Errors from every stage of decoding. Offsets are relative to the data that stage was given:
+// the PNG file, the zlib stream, the deflate stream, or the decompressed image data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    PngChunk { byte_offset: usize, reason: String },
+    ZlibHeader { byte_offset: usize, reason: String },
+    ZlibChecksum { byte_offset: usize, stored: u32, calculated: u32 },
+    DeflateBlock { bit_offset: usize, reason: String },
+    HuffmanTable { bit_offset: usize, reason: String },
+    Filter { byte_offset: usize, reason: String },
+}
+
+impl DecodeError {
+    pub fn stage(&self) -> &'static str {
+        match self {
+            Self::PngChunk { .. } => "PNG chunk",
+            Self::ZlibHeader { .. } => "zlib header",
+            Self::ZlibChecksum { .. } => "zlib checksum",
+            Self::DeflateBlock { .. } => "deflate block",
+            Self::HuffmanTable { .. } => "Huffman table",
+            Self::Filter { .. } => "filter",
+        }
+    }
+
+    pub fn bit_offset(&self) -> usize {
+        match self {
+            Self::DeflateBlock { bit_offset, .. } | Self::HuffmanTable { bit_offset, .. } => *bit_offset,
+            Self::PngChunk { byte_offset, .. }
+            | Self::ZlibHeader { byte_offset, .. }
+            | Self::ZlibChecksum { byte_offset, .. }
+            | Self::Filter { byte_offset, .. } => byte_offset*8,
+        }
+    }
+
+    pub fn shifted(self, bytes: usize) -> Self {
+        // move the offset along, for when the failing data started part way through a larger input
+        match self {
+            Self::PngChunk { byte_offset, reason } => Self::PngChunk { byte_offset: byte_offset+bytes, reason },
+            Self::ZlibHeader { byte_offset, reason } => Self::ZlibHeader { byte_offset: byte_offset+bytes, reason },
+            Self::ZlibChecksum { byte_offset, stored, calculated } => Self::ZlibChecksum { byte_offset: byte_offset+bytes, stored, calculated },
+            Self::DeflateBlock { bit_offset, reason } => Self::DeflateBlock { bit_offset: bit_offset + bytes*8, reason },
+            Self::HuffmanTable { bit_offset, reason } => Self::HuffmanTable { bit_offset: bit_offset + bytes*8, reason },
+            Self::Filter { byte_offset, reason } => Self::Filter { byte_offset: byte_offset+bytes, reason },
+        }
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PngChunk { byte_offset, reason }
+            | Self::ZlibHeader { byte_offset, reason }
+            | Self::Filter { byte_offset, reason } => write!(f, "{} error at byte {}: {}", self.stage(), byte_offset, reason),
+            Self::ZlibChecksum { byte_offset, stored, calculated } => write!(
+                f, "{} error at byte {}: stored Adler-32 {} doesn't match calculated {}", self.stage(), byte_offset, stored, calculated
+            ),
+            Self::DeflateBlock { bit_offset, reason }
+            | Self::HuffmanTable { bit_offset, reason } => write!(f, "{} error at bit {}: {}", self.stage(), bit_offset, reason),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,159 @@
+// PNG scanline filtering, RFC 2083 6
+use crate::error::DecodeError;
+
+pub const FILTER_NONE: u8 = 0;
+pub const FILTER_SUB: u8 = 1;
+pub const FILTER_UP: u8 = 2;
+pub const FILTER_AVERAGE: u8 = 3;
+pub const FILTER_PAETH: u8 = 4;
+
+pub fn filter_name(filter_type: u8) -> &'static str {
+    match filter_type {
+        FILTER_NONE => "None",
+        FILTER_SUB => "Sub",
+        FILTER_UP => "Up",
+        FILTER_AVERAGE => "Average",
+        FILTER_PAETH => "Paeth",
+        _ => "invalid"
+    }
+}
+
+pub fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+    // a = left, b = above, c = upper left
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+
+    // ties are broken in the order a, b, c
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+pub fn unfilter_row(filter_type: u8, row: &mut [u8], prev_row: &[u8], bytes_per_pixel: usize) -> Result<(), String> {
+    // reverses the filter on a single scanline in place
+    // prev_row is the already unfiltered row above, all 0s for the first row
+    // bytes_per_pixel is rounded up to 1 for bit depths under 8, as the left neighbour is the previous byte
+    match filter_type {
+        FILTER_NONE => {},
+        FILTER_SUB => {
+            for i in bytes_per_pixel..row.len() {
+                row[i] = row[i].wrapping_add(row[i-bytes_per_pixel]);
+            }
+        },
+        FILTER_UP => {
+            for i in 0..row.len() {
+                row[i] = row[i].wrapping_add(prev_row[i]);
+            }
+        },
+        FILTER_AVERAGE => {
+            for i in 0..row.len() {
+                let left = if i >= bytes_per_pixel { row[i-bytes_per_pixel] as u16 } else { 0 };
+                let above = prev_row[i] as u16;
+                row[i] = row[i].wrapping_add(((left + above) / 2) as u8);
+            }
+        },
+        FILTER_PAETH => {
+            for i in 0..row.len() {
+                let (left, upper_left) = if i >= bytes_per_pixel {
+                    (row[i-bytes_per_pixel], prev_row[i-bytes_per_pixel])
+                } else {
+                    (0, 0)
+                };
+                row[i] = row[i].wrapping_add(paeth_predictor(left, prev_row[i], upper_left));
+            }
+        },
+        _ => return Err(format!("Unsupported filter type {}", filter_type))
+    }
+    Ok(())
+}
+
+pub fn unfilter_scanlines(data: &[u8], row_bytes: usize, bytes_per_pixel: usize) -> Result<Vec<u8>, DecodeError> {
+    // data is a sequence of scanlines, each a filter type byte followed by row_bytes of filtered data
+    // returns the unfiltered rows joined together, without filter type bytes
+    let mut unfiltered: Vec<u8> = Vec::with_capacity((data.len() / (row_bytes+1)) * row_bytes);
+    let mut prev_row = vec![0u8; row_bytes];
+
+    for (row_number, scanline) in data.chunks(row_bytes+1).enumerate() {
+        let byte_offset = row_number * (row_bytes+1);
+        if scanline.len() != row_bytes+1 {
+            return Err(DecodeError::Filter {
+                byte_offset,
+                reason: format!("Scanline is truncated, expected {} bytes, found {}", row_bytes+1, scanline.len())
+            })
+        }
+
+        let mut row = scanline[1..].to_vec();
+        unfilter_row(scanline[0], &mut row, &prev_row, bytes_per_pixel)
+            .map_err(|reason| DecodeError::Filter { byte_offset, reason })?;
+
+        unfiltered.extend_from_slice(&row);
+        prev_row = row;
+    }
+
+    Ok(unfiltered)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paeth_predictor() {
+        assert_eq!(paeth_predictor(0, 0, 0), 0);
+        // p = 10 + 20 - 5 = 25, closest to b
+        assert_eq!(paeth_predictor(10, 20, 5), 20);
+        // p = 20 + 10 - 25 = 5, closest to b
+        assert_eq!(paeth_predictor(20, 10, 25), 10);
+        // p = 100 + 10 - 10 = 100, equal to a
+        assert_eq!(paeth_predictor(100, 10, 10), 100);
+        // p = 50 + 60 - 200 = -90, closest to a
+        assert_eq!(paeth_predictor(50, 60, 200), 50);
+    }
+
+    #[test]
+    fn test_unfilter_scanlines() {
+        // 2x3 image, 2 bytes per pixel, one row with each filter type
+        let data = vec![
+            FILTER_NONE, 10, 20, 30, 40,
+            FILTER_SUB, 1, 2, 3, 4,
+            FILTER_UP, 5, 5, 250, 250,
+        ];
+        assert_eq!(
+            unfilter_scanlines(&data, 4, 2).unwrap(),
+            vec![
+                10, 20, 30, 40,
+                1, 2, 4, 6,
+                6, 7, 254, 0
+            ]
+        );
+
+        let data = vec![
+            FILTER_AVERAGE, 10, 20, 30, 40,
+            FILTER_PAETH, 1, 2, 3, 4,
+        ];
+        assert_eq!(
+            unfilter_scanlines(&data, 4, 2).unwrap(),
+            vec![
+                // first row only has left neighbours, above is 0
+                10, 20, 35, 50,
+                // paeth(0, 10, 0) = 10, paeth(0, 20, 0) = 20, paeth(11, 35, 10) = 35, paeth(22, 50, 20) = 50
+                11, 22, 38, 54
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unfilter_invalid_filter_type() {
+        assert_eq!(
+            unfilter_scanlines(&[0, 0, 0, 5, 0, 0], 2, 1).unwrap_err(),
+            DecodeError::Filter { byte_offset: 3, reason: "Unsupported filter type 5".to_string() }
+        );
+    }
+}
@@ -1,33 +1,24 @@
-use core::panic;
-
-use crate::{bitstream::BitStream, low_level_functions::bits_to_byte};
+use std::sync::OnceLock;
+use crate::{bitstream::BitStream, error::DecodeError, token::Alphabet};
 
 // === CONSTANTS ===
 
-// Fixed huffman codes constructed from codelengths given in RFC 1951 3.2.6
-const FIXED_HUFFMAN_CODES_8: [u16; 144] = [48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95, 96, 97, 98, 99, 100,
-101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111, 112, 113, 114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126, 127, 128, 129, 130, 131, 132, 133, 134, 135, 136, 137, 138, 139, 140, 141, 142, 143, 144, 145, 146, 147, 148, 149, 150, 151,
-152, 153, 154, 155, 156, 157, 158, 159, 160, 161, 162, 163, 164, 165, 166, 167, 168, 169, 170, 171, 172, 173, 174, 175, 176, 177, 178, 179, 180, 181, 182, 183, 184, 185, 186, 187, 188, 189, 190, 191];
-
-const FIXED_HUFFMAN_CODES_7: [u16; 24] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23];
-
-const FIXED_HUFFMAN_CODES_8_2: [u16; 8] = [192, 193, 194, 195, 196, 197, 198, 199];
-
-const FIXED_HUFFMAN_CODES_9: [u16; 112] = [400, 401, 402, 403, 404, 405, 406, 407, 408, 409, 410, 411, 412, 413, 414, 415, 416, 417, 418, 419, 420, 421, 422, 423, 424, 425, 426, 427, 428, 429, 430, 431, 432, 433, 434, 435, 436, 437, 438, 439, 440, 441,
-442, 443, 444, 445, 446, 447, 448, 449, 450, 451, 452, 453, 454, 455, 456, 457, 458, 459, 460, 461, 462, 463, 464, 465, 466, 467, 468, 469, 470, 471, 472, 473, 474, 475, 476, 477, 478, 479, 480, 481, 482, 483, 484, 485, 486, 487, 488, 489, 490, 491, 492,
-493, 494, 495, 496, 497, 498, 499, 500, 501, 502, 503, 504, 505, 506, 507, 508, 509, 510, 511];
+// codes up to this length are decoded with one lookup, longer codes continue in a sub-table
+const PRIMARY_BITS: u8 = 9;
 
 // RFC 1951 3.2.5
 const LENGTH_BASES: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
-const LENGTH_EXTRA_BITS: [usize; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const LENGTH_EXTRA_BITS: [u32; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
 const DIST_BASES: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
-const DIST_EXTRA_BITS: [usize; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+const DIST_EXTRA_BITS: [u32; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// order the code length alphabet's codelengths are stored in, RFC 1951 3.2.7
+pub const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
 
 // ==============
 
 
 fn base_codes_for_lengths(codelengths: &Vec<u8>) -> Vec<u16> {
-    let &max_code_length = codelengths.iter().max().unwrap();
+    let &max_code_length = codelengths.iter().max().unwrap_or(&0);
     let mut base_code: Vec<u16> = Vec::with_capacity((max_code_length+1) as usize);
     base_code.push(0);
 
@@ -60,79 +51,64 @@ pub fn prefix_codes_from_codelengths(codelengths: Vec<u8>) -> Vec<u16> {
     prefix_codes
 }
 
-pub fn next_huffman_symbol(data: &mut BitStream, symbols: &Vec<u16>, prefixes: &Vec<u16>, codelengths: &Vec<u8>, big_endian: bool) -> (u16, Vec<u8>) {
-    let mut current_prefix = 0;
-    let mut current_codelength: u8 = 0;
-    let symbol;
-    let mut bits: Vec<u8> = Vec::new();
-
-    loop {
-        if big_endian {
-            let next_bit = data.next().unwrap();
-            current_prefix = (current_prefix << 1) | (next_bit as u16);
-            bits.push(next_bit);
-        } else {
-            let next_bit = data.next().unwrap();
-            current_prefix = current_prefix | ((next_bit as u16) << current_codelength);
-            bits.push(next_bit);
-        }
-        current_codelength += 1;
+pub fn decode_huffman_symbol(data: &mut BitStream, table: &HuffmanTable) -> Result<u16, DecodeError> {
+    // the next bits are looked up in the primary table, and then in a sub-table for long codes
+    let start_bit_offset = data.current_abs_bit_position();
+    let next_bits = data.peek(table.max_length as u32) as usize;
 
-        // valid prefixes are where the codelength of this prefix == current_codelength
-        let mut valid_prefixes = prefixes
-            .iter()
-            .cloned()
-            .enumerate()
-            .filter(
-                |(i, _)| *codelengths.get(*i).unwrap() == current_codelength
-            );
-
-        let prefix_position = valid_prefixes.position(|(_, x)| x == current_prefix);
-        if prefix_position.is_none() {
-            continue
-        }
-        symbol = *symbols.get( prefixes.iter().position(|&x| x == current_prefix).unwrap() ).unwrap();
-        break;
+    let mut entry = table.lookup[next_bits & ((1 << table.primary_bits) - 1)];
+    if let LookupEntry::SubTable { start, bits } = entry {
+        entry = table.lookup[start + ((next_bits >> table.primary_bits) & ((1 << bits) - 1))];
     }
 
-    (symbol, bits)
+    match entry {
+        LookupEntry::Symbol { symbol, length } => {
+            // bits past the end of the data were looked up as 0s, reading them gives the end of data error
+            data.bits(length as u32)?;
+            Ok(symbol)
+        },
+        _ => {
+            data.bits(table.max_length as u32)?;
+            Err(DecodeError::HuffmanTable {
+                bit_offset: start_bit_offset,
+                reason: format!("No code in the table matches the bits {:?}", data.bits_from(start_bit_offset))
+            })
+        }
+    }
 }
 
+pub fn next_huffman_symbol(data: &mut BitStream, table: &HuffmanTable) -> Result<(u16, Vec<u8>), DecodeError> {
+    // same as decode_huffman_symbol, also returning the bits of the code for tokens
+    let start_bit_offset = data.current_abs_bit_position();
+    let symbol = decode_huffman_symbol(data, table)?;
+    Ok((symbol, data.bits_from(start_bit_offset)))
+}
 
-pub fn next_fixed_huffman_symbol(data: &mut BitStream) -> (u16, Vec<u8>) {
-    let mut first_bits = data.next_n(7);
-    let first_7 = bits_to_byte(&first_bits, true) as u16;
-    let symbol7 = FIXED_HUFFMAN_CODES_7.iter().position(|&x| x == first_7);
-    if symbol7.is_some() {
-        return ((symbol7.unwrap() as u16) + 256, first_bits)
-    }
+pub fn fixed_literal_length_table() -> &'static HuffmanTable {
+    // codelengths from RFC 1951 3.2.6
+    static TABLE: OnceLock<HuffmanTable> = OnceLock::new();
+    TABLE.get_or_init(|| huffman_table(&fixed_codelengths()))
+}
 
-    let next_bit = data.next().unwrap();
-    let first_8 = (first_7 << 1) | (next_bit as u16);
-    first_bits.push(next_bit);
-    
-    let symbol8 = FIXED_HUFFMAN_CODES_8.iter().position(|&x| x == first_8);
-    if symbol8.is_some() {
-        return (symbol8.unwrap() as u16, first_bits)
-    }
-    let symbol8_2 = FIXED_HUFFMAN_CODES_8_2.iter().position(|&x| x == first_8);
-    if symbol8_2.is_some() {
-        return ((symbol8_2.unwrap() as u16) + 280, first_bits)
-    }
+fn fixed_codelengths() -> [u8; 288] {
+    let mut codelengths = [8u8; 288];
+    codelengths[144..256].fill(9);
+    codelengths[256..280].fill(7);
+    codelengths
+}
 
-    let next_bit = data.next().unwrap();
-    first_bits.push(next_bit);
-    let first_9 = (first_8 << 1) | (next_bit as u16);
-    let symbol9 = FIXED_HUFFMAN_CODES_9.iter().position(|&x| x == first_9);
-    if symbol9.is_some() {
-        return ((symbol9.unwrap() as u16) + 144, first_bits)
-    }
+pub fn fixed_distance_table() -> &'static HuffmanTable {
+    // 5 bit codes, 30 and 31 are in the table so they can be reported as invalid distances
+    static TABLE: OnceLock<HuffmanTable> = OnceLock::new();
+    TABLE.get_or_init(|| huffman_table(&[5; 32]))
+}
 
-    panic!("Can't get fixed huffman code")
+pub fn next_fixed_huffman_symbol(data: &mut BitStream) -> Result<(u16, Vec<u8>), DecodeError> {
+    next_huffman_symbol(data, fixed_literal_length_table())
 }
 
 
-pub fn huffman_codes_from_codelengths(codelengths: &Vec<u8>) -> (Vec<u16>, Vec<u16>) {
+pub fn huffman_codes_from_codelengths(codelengths: &[u8]) -> (Vec<u16>, Vec<u16>) {
     // returns same size vectors of symbols, and prefixes
     let mut symbols = Vec::new();
 
@@ -154,56 +130,216 @@ pub fn huffman_codes_from_codelengths(codelengths: &Vec<u8>) -> (Vec<u16>, Vec<u
     (symbols, prefixes)
 }
 
-pub fn decode_length(data: &mut BitStream, length_sym: u16) -> (Vec<u8>, u16) {
-    let index = (length_sym - 257) as usize;
-    let length_base = *LENGTH_BASES.get(index).unwrap();
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LookupEntry {
+    Invalid,
+    Symbol { symbol: u16, length: u8 },
+    SubTable { start: usize, bits: u8 }, // index of the sub-table in lookup, and the number of bits after the primary bits it is indexed by
+}
+
+pub struct HuffmanTable {
+    pub symbols: Vec<u16>,
+    pub prefixes: Vec<u16>,
+    pub codelengths: Vec<u8>, // codelengths of the symbols, without 0s
+    max_length: u8,
+    primary_bits: u8,
+    lookup: Vec<LookupEntry>, // primary table indexed by the next primary_bits bits, first bit read is the lsb, followed by the sub-tables
+}
 
-    let num_extra_bits = *LENGTH_EXTRA_BITS.get(index).unwrap();
+pub fn check_codelengths(codelengths: &[u8], alphabet: Alphabet, bit_offset: usize) -> Result<(), DecodeError> {
+    // a code of n bits uses 1/2^n of the prefixes, so the codes fit if these add up to at most 1 (Kraft inequality)
+    // they must add up to exactly 1, except that one distance code of 1 bit, or none, is allowed (RFC 1951 3.2.7)
+    // zlib also allows a single literal/length code, for a block that only has the end of block symbol
+    let (name, single_code_allowed) = match alphabet {
+        Alphabet::CodeLength => ("Code length", false),
+        Alphabet::LiteralLength => ("Literal/length", true),
+        Alphabet::Distance => ("Distance", true),
+    };
+    let lengths: Vec<u8> = codelengths.iter().cloned().filter(|&length| length > 0).collect();
+    let used: u32 = lengths.iter().map(|&length| 1 << (15 - length)).sum();
+
+    if used > 1 << 15 {
+        return Err(DecodeError::HuffmanTable {
+            bit_offset,
+            reason: format!("{} code lengths are over-subscribed, {} codes with lengths {:?} don't all fit", name, lengths.len(), lengths)
+        })
+    }
 
-    if num_extra_bits > 0 {
-        let extra_bits = data.next_n(num_extra_bits);
-        let length = (bits_to_byte(&extra_bits, false) >> (8-num_extra_bits)) as u16 + length_base;
-        (extra_bits, length)
-    } else {
-        (vec![], length_base)
+    let single_code = lengths == [1] || (lengths.is_empty() && alphabet == Alphabet::Distance);
+    if used < 1 << 15 && !(single_code_allowed && single_code) {
+        return Err(DecodeError::HuffmanTable {
+            bit_offset,
+            reason: format!("{} code lengths are incomplete, {} codes with lengths {:?} leave some bits with no code", name, lengths.len(), lengths)
+        })
     }
+    Ok(())
 }
 
-pub fn decode_distance(data: &mut BitStream, dist_sym: u8) -> (Vec<u8>, u16) {
-    let index = dist_sym as usize;
-    let dist_base = *DIST_BASES.get(index).unwrap();
+pub fn check_repeat_count(decoded: usize, max_codes: usize, bit_offset: usize) -> Result<(), DecodeError> {
+    // a repeated code length can't go past the end of the distance codelengths
+    if decoded > max_codes {
+        return Err(DecodeError::HuffmanTable {
+            bit_offset,
+            reason: format!("Code length repeat gives {} codelengths, but there are only {} literal/length and distance codes", decoded, max_codes)
+        })
+    }
+    Ok(())
+}
+
+pub fn read_code_length_codelengths(data: &mut BitStream, num_of_codelength_codes: usize) -> Result<[u8; 19], DecodeError> {
+    // 3 bits each, in the order of CODE_LENGTH_ORDER, codes that aren't given have length 0
+    let mut code_length_codelengths = [0u8; 19];
+    for &position in CODE_LENGTH_ORDER.iter().take(num_of_codelength_codes) {
+        code_length_codelengths[position] = data.bits(3)? as u8;
+    }
+    Ok(code_length_codelengths)
+}
 
-    let num_extra_bits = *DIST_EXTRA_BITS.get(index).unwrap();
+pub fn read_codelengths(
+    data: &mut BitStream, codelengths: &mut Vec<u8>, num_of_codes: usize, max_codes: usize, code_length_table: &HuffmanTable,
+    mut on_code: impl FnMut(&BitStream, u8, u8, usize)
+) -> Result<(), DecodeError> {
+    // adds codelengths until there are num_of_codes, on_code is given each code length symbol, how many codelengths it gives and its bit offset
+    // the literal/length and distance codelengths are one sequence, so a repeat can carry on past num_of_codes, but not past max_codes
+    while codelengths.len() < num_of_codes {
+        let code_bit_offset = data.current_abs_bit_position();
+        let symbol = decode_huffman_symbol(data, code_length_table)?;
+        let (codelength, repetitions) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                // copy the previous code length 3-6 times, 2 extra bits
+                let &previous = codelengths.last().ok_or(DecodeError::HuffmanTable {
+                    bit_offset: code_bit_offset,
+                    reason: "Code length 16 repeats the previous code length, but there isn't one".to_string()
+                })?;
+                (previous, data.bits(2)? as u8 + 3)
+            },
+            17 => (0, data.bits(3)? as u8 + 3), // copy 0 3-10 times, 3 extra bits
+            18 => (0, data.bits(7)? as u8 + 11), // copy 0 11-138 times, 7 extra bits
+            _ => return Err(DecodeError::HuffmanTable {
+                bit_offset: code_bit_offset,
+                reason: format!("Invalid code length symbol {}", symbol)
+            })
+        };
+        on_code(data, symbol as u8, repetitions, code_bit_offset);
+        codelengths.extend(std::iter::repeat_n(codelength, repetitions as usize));
+        check_repeat_count(codelengths.len(), max_codes, code_bit_offset)?;
+    }
+    Ok(())
+}
+
+fn reversed_code(prefix: u16, length: u8) -> usize {
+    // codes are read msb first, so they're reversed to match the order the bits are looked up in
+    (prefix.reverse_bits() >> (16 - length)) as usize
+}
 
-    if num_extra_bits > 0 {
-        let extra_bits = data.next_n(num_extra_bits);
-        let mut extra_bits_value: u16 = 0;
+pub fn huffman_table(codelengths: &[u8]) -> HuffmanTable {
+    let (symbols, prefixes) = huffman_codes_from_codelengths(codelengths);
+    let codelengths: Vec<u8> = codelengths.iter().cloned().filter(|&x| x > 0).collect();
+    let max_length = codelengths.iter().cloned().max().unwrap_or(0);
+    let primary_bits = max_length.min(PRIMARY_BITS);
+    let primary_mask = (1 << primary_bits) - 1;
+
+    // a sub-table for each primary index that starts a long code, big enough for the longest of them
+    let mut lookup = vec![LookupEntry::Invalid; 1 << primary_bits];
+    let mut sub_table_bits = vec![0u8; 1 << primary_bits];
+    for (&prefix, &length) in prefixes.iter().zip(&codelengths) {
+        if length > primary_bits {
+            let index = reversed_code(prefix, length) & primary_mask;
+            sub_table_bits[index] = sub_table_bits[index].max(length - primary_bits);
+        }
+    }
+    for (index, &bits) in sub_table_bits.iter().enumerate() {
+        if bits > 0 {
+            lookup[index] = LookupEntry::SubTable { start: lookup.len(), bits };
+            lookup.extend(std::iter::repeat_n(LookupEntry::Invalid, 1 << bits));
+        }
+    }
 
-        // same as bits_to_byte function, with added support for u16, as may be up to 13 bits
-        for (i, &bit) in extra_bits.iter().enumerate() {
-            extra_bits_value = extra_bits_value | ((bit as u16) << i);
+    // every index whose first bits are the code maps to its symbol
+    for ((&symbol, &prefix), &length) in symbols.iter().zip(&prefixes).zip(&codelengths) {
+        let code = reversed_code(prefix, length);
+        let entry = LookupEntry::Symbol { symbol, length };
+        if length <= primary_bits {
+            for index in (code..1 << primary_bits).step_by(1 << length) {
+                lookup[index] = entry;
+            }
+        } else if let LookupEntry::SubTable { start, bits } = lookup[code & primary_mask] {
+            for index in ((code >> primary_bits)..1 << bits).step_by(1 << (length - primary_bits)) {
+                lookup[start + index] = entry;
+            }
         }
-        (extra_bits, dist_base + extra_bits_value)
+    }
 
-    } else {
-        (vec![], dist_base)
+    HuffmanTable {
+        symbols,
+        prefixes,
+        codelengths,
+        max_length,
+        primary_bits,
+        lookup,
     }
 }
 
-pub fn decode_duplicate_reference(prev_literals: &Vec<u8>, length: u16, distance: u16) -> Vec<u8> {
-    let mut literals: Vec<u8> = Vec::new();
-    
-    let mut position: usize = prev_literals.len();
-    position -= distance as usize;
+pub fn decode_length(data: &mut BitStream, length_sym: u16, symbol_bit_offset: usize) -> Result<u16, DecodeError> {
+    // symbols 286 and 287 have codes in the fixed table, but aren't lengths
+    if !(257..=285).contains(&length_sym) {
+        return Err(DecodeError::DeflateBlock {
+            bit_offset: symbol_bit_offset,
+            reason: format!("Invalid length symbol {}, only 257-285 are lengths", length_sym)
+        })
+    }
+    let index = (length_sym - 257) as usize;
+
+    Ok(LENGTH_BASES[index] + data.bits(LENGTH_EXTRA_BITS[index])? as u16)
+}
 
-    for _i in 0..(length) {
-        literals.push(*prev_literals.get(position).unwrap());
-        if position == prev_literals.len()-1 {
-            position -= distance as usize;
+pub fn decode_distance(data: &mut BitStream, dist_sym: u8, symbol_bit_offset: usize) -> Result<u16, DecodeError> {
+    // distance codes 30 and 31 can have codes, but aren't distances
+    let index = dist_sym as usize;
+    if index >= DIST_BASES.len() {
+        return Err(DecodeError::DeflateBlock {
+            bit_offset: symbol_bit_offset,
+            reason: format!("Invalid distance symbol {}, only 0-29 are distances", dist_sym)
+        })
+    }
+
+    Ok(DIST_BASES[index] + data.bits(DIST_EXTRA_BITS[index])? as u16)
+}
+
+// a literal/length symbol, with the length and distance of a string reference read after it
+pub enum Symbol {
+    Literal(u8),
+    EndOfBlock,
+    Reference { length: u16, distance: u16 },
+}
+
+pub fn decode_symbol(data: &mut BitStream, literal_length_table: &HuffmanTable, distance_table: &HuffmanTable) -> Result<Symbol, DecodeError> {
+    // the next symbol of a fixed or dynamic block, errors are reported where the symbol or its distance code starts
+    let start_bit = data.current_abs_bit_position();
+    let symbol = decode_huffman_symbol(data, literal_length_table)?;
+    match symbol {
+        0..=255 => Ok(Symbol::Literal(symbol as u8)),
+        256 => Ok(Symbol::EndOfBlock),
+        _ => {
+            let length = decode_length(data, symbol, start_bit)?;
+            let distance_start_bit = data.current_abs_bit_position();
+            let distance_symbol = decode_huffman_symbol(data, distance_table)?;
+            let distance = decode_distance(data, distance_symbol as u8, distance_start_bit)?;
+            Ok(Symbol::Reference { length, distance })
         }
-        position += 1;
     }
-    literals
+}
+
+pub fn check_distance(distance: u16, decoded: usize, bit_offset: usize) -> Result<(), DecodeError> {
+    // a string reference can only copy bytes that have already been decoded
+    if distance as usize > decoded {
+        return Err(DecodeError::DeflateBlock {
+            bit_offset,
+            reason: format!("String reference distance {} is before the start of the data, {} bytes have been decoded", distance, decoded)
+        })
+    }
+    Ok(())
 }
 
 
@@ -215,55 +351,78 @@ mod tests {
     #[test]
     fn test_decode_length() {
         // 10011010 11011111 00010111
-        let mut bs = BitStream::new(vec![154, 223, 23], false);
+        let mut bs = BitStream::new(vec![154, 223, 23]);
 
         // no extra bits, 259 symbol
-        assert_eq!(decode_length(&mut bs, 259).1, 5);
+        assert_eq!(decode_length(&mut bs, 259, 0).unwrap(), 5);
 
         // 1 extra bit, 268 symbol, where next bit is 0
-        assert_eq!(decode_length(&mut bs, 268).1, 17);
+        assert_eq!(decode_length(&mut bs, 268, 0).unwrap(), 17);
 
         // 1 extra bit, 268 symbol, where next bit is 1
-        assert_eq!(decode_length(&mut bs, 268).1, 18);
+        assert_eq!(decode_length(&mut bs, 268, 0).unwrap(), 18);
 
-        // 5 extra bits, 282 symbol, next bits 01100, 175 = 163 + 12
-        assert_eq!(decode_length(&mut bs, 282).1, 175);
+        // 5 extra bits, 282 symbol, next bits 01100 read lsb first, 169 = 163 + 6
+        assert_eq!(decode_length(&mut bs, 282, 0).unwrap(), 169);
 
         // no extra bits, 285 symbol
-        assert_eq!(decode_length(&mut bs, 285).1, 258);
+        assert_eq!(decode_length(&mut bs, 285, 0).unwrap(), 258);
 
         // false
         // next bit 1
-        assert_ne!(decode_length(&mut bs, 268).1, 17);
+        assert_ne!(decode_length(&mut bs, 268, 0).unwrap(), 17);
         
         // next bits 111
-        assert_ne!(decode_length(&mut bs, 274).1, 46);
+        assert_ne!(decode_length(&mut bs, 274, 0).unwrap(), 46);
+
+        // 13 bits left
+        assert!(decode_length(&mut bs, 282, 0).is_ok());
+        assert!(decode_length(&mut bs, 282, 0).is_ok());
+        assert!(decode_length(&mut bs, 274, 0).is_ok());
+        assert!(decode_length(&mut bs, 268, 0).is_err());
+
+        // 286 and 287 aren't lengths, and nothing is read
+        assert_eq!(
+            decode_length(&mut bs, 286, 5).unwrap_err(),
+            DecodeError::DeflateBlock { bit_offset: 5, reason: "Invalid length symbol 286, only 257-285 are lengths".to_string() }
+        );
+        assert!(decode_length(&mut bs, 256, 0).is_err());
     }
 
     #[test]
     fn test_decode_distance() {
         // 10011010 11011111 00010111
-        let mut bs = BitStream::new(vec![154, 223, 23], false);
+        let mut bs = BitStream::new(vec![154, 223, 23]);
 
         // no extra bits
-        assert_eq!(decode_distance(&mut bs, 2).1, 3);
+        assert_eq!(decode_distance(&mut bs, 2, 0).unwrap(), 3);
 
         // next bit 0
-        assert_eq!(decode_distance(&mut bs, 5).1, 7);
+        assert_eq!(decode_distance(&mut bs, 5, 0).unwrap(), 7);
 
         // next bits 101100
-        assert_eq!(decode_distance(&mut bs, 14).1, 142);
+        assert_eq!(decode_distance(&mut bs, 14, 0).unwrap(), 142);
         
         // next bits 1111110111110
-        assert_eq!(decode_distance(&mut bs, 28).1, 20416);
+        assert_eq!(decode_distance(&mut bs, 28, 0).unwrap(), 20416);
+
+        // codes 30 and 31 aren't distances
+        assert_eq!(
+            decode_distance(&mut bs, 30, 9).unwrap_err(),
+            DecodeError::DeflateBlock { bit_offset: 9, reason: "Invalid distance symbol 30, only 0-29 are distances".to_string() }
+        );
     }
 
     #[test]
-    fn test_decode_duplicate_reference() {
-        assert_eq!(decode_duplicate_reference(&vec![1, 2, 3, 4, 5], 3, 4), vec![2, 3, 4]);
-
-        // repeated reference test
-        assert_eq!(decode_duplicate_reference(&vec![1, 2, 3, 4, 5, 6, 7, 8], 8, 3), vec![6, 7, 8, 6, 7, 8, 6, 7]);
+    fn test_check_distance() {
+        assert!(check_distance(4, 5, 0).is_ok());
+
+        // distance goes back past the start of the data
+        assert_eq!(
+            check_distance(4, 3, 20).unwrap_err(),
+            DecodeError::DeflateBlock { bit_offset: 20, reason: "String reference distance 4 is before the start of the data, 3 bytes have been decoded".to_string() }
+        );
+        assert!(check_distance(1, 0, 0).is_err());
     }
 
     #[test]
@@ -274,4 +433,57 @@ mod tests {
         assert_eq!(huffman_code_symbols, vec![1, 2, 4, 16, 17, 18]);
         assert_eq!(huffman_code_prefixes, vec![12, 0, 13, 14, 15, 2]);
     }
+
+    #[test]
+    fn test_check_codelengths() {
+        assert!(check_codelengths(&[2, 1, 3, 3], Alphabet::CodeLength, 0).is_ok());
+        assert_eq!(
+            check_codelengths(&[1, 1, 0, 2], Alphabet::LiteralLength, 40).unwrap_err(),
+            DecodeError::HuffmanTable { bit_offset: 40, reason: "Literal/length code lengths are over-subscribed, 3 codes with lengths [1, 1, 2] don't all fit".to_string() }
+        );
+        assert!(check_codelengths(&[2, 2, 2], Alphabet::Distance, 0).is_err());
+
+        // one distance code of 1 bit, or none at all, is allowed
+        assert!(check_codelengths(&[0, 1, 0], Alphabet::Distance, 0).is_ok());
+        assert!(check_codelengths(&[0; 30], Alphabet::Distance, 0).is_ok());
+        assert!(check_codelengths(&[0, 2], Alphabet::Distance, 0).is_err());
+        assert!(check_codelengths(&[0; 19], Alphabet::CodeLength, 0).is_err());
+        assert!(check_codelengths(&[1], Alphabet::CodeLength, 0).is_err());
+
+        assert!(check_codelengths(&fixed_codelengths(), Alphabet::LiteralLength, 0).is_ok());
+    }
+
+    #[test]
+    fn test_huffman_table_lookup() {
+        // lengths up to 12, so the longest codes are in sub-tables
+        let codelengths = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 12];
+        let table = huffman_table(&codelengths);
+        for (i, (&prefix, &length)) in table.prefixes.iter().zip(&table.codelengths).enumerate() {
+            // code is written msb first, followed by a bit that isn't part of it
+            let bits: Vec<u8> = (0..length).rev().map(|bit| ((prefix >> bit) & 1) as u8).chain([1]).collect();
+            let bytes: Vec<u8> = bits.chunks(8).map(|byte| byte.iter().enumerate().map(|(i, &bit)| bit << i).sum()).collect();
+            let mut bs = BitStream::new(bytes);
+            assert_eq!(next_huffman_symbol(&mut bs, &table).unwrap(), (table.symbols[i], bits[..length as usize].to_vec()));
+        }
+
+        // data ends part way through a 9 bit code
+        let mut bs = BitStream::new(vec![255]);
+        assert_eq!(next_huffman_symbol(&mut bs, &table).unwrap_err().stage(), "deflate block");
+
+        // only the code 0 exists
+        let mut bs = BitStream::new(vec![1]);
+        assert_eq!(
+            next_huffman_symbol(&mut bs, &huffman_table(&[1])).unwrap_err(),
+            DecodeError::HuffmanTable { bit_offset: 0, reason: "No code in the table matches the bits [1]".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_fixed_huffman_symbol() {
+        // 7, 8 and 9 bit codes: 256 is 0000000, 0 is 00110000, 144 is 110010000
+        let mut bs = BitStream::new(vec![0, 134, 9, 0]);
+        assert_eq!(next_fixed_huffman_symbol(&mut bs).unwrap().0, 256);
+        assert_eq!(next_fixed_huffman_symbol(&mut bs).unwrap().0, 0);
+        assert_eq!(next_fixed_huffman_symbol(&mut bs).unwrap(), (144, vec![1, 1, 0, 0, 1, 0, 0, 0, 0]));
+    }
 }
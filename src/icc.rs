@@ -0,0 +1,169 @@
+use crate::token::{group_token, Token, TokenKind};
+
+// ICC PROFILES, ICC.1:2022 section 7
+// A profile starts with a 128 byte header, followed by a table of the tags that make up the rest of it.
+
+/// The header fields of an ICC profile, and its tag table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IccHeader {
+    pub size: u32, // profile size in bytes
+    pub cmm: String, // signature of the preferred colour management module
+    pub version: (u8, u8, u8), // major, minor and bug fix version
+    pub device_class: String, // signature such as "mntr" for displays
+    pub colour_space: String, // signature of the data colour space, such as "RGB "
+    pub connection_space: String, // "XYZ " or "Lab "
+    pub rendering_intent: u32,
+    pub tags: Vec<IccTag>,
+}
+
+/// An entry in the tag table, giving where a tag's data is in the profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IccTag {
+    pub signature: String,
+    pub offset: u32,
+    pub size: u32,
+}
+
+const HEADER_LENGTH: usize = 128;
+
+fn u32_at(profile: &[u8], index: usize) -> u32 {
+    u32::from_be_bytes([profile[index], profile[index+1], profile[index+2], profile[index+3]])
+}
+
+fn signature_at(profile: &[u8], index: usize) -> String {
+    profile[index..index+4].iter().map(|&byte| byte as char).collect()
+}
+
+fn device_class_name(signature: &str) -> &'static str {
+    match signature {
+        "scnr" => "input device",
+        "mntr" => "display device",
+        "prtr" => "output device",
+        "link" => "device link",
+        "spac" => "colour space",
+        "abst" => "abstract",
+        "nmcl" => "named colour",
+        _ => "unknown",
+    }
+}
+
+pub(crate) fn rendering_intent_name(intent: u32) -> &'static str {
+    // the same intents as the sRGB chunk
+    match intent {
+        0 => "perceptual",
+        1 => "relative colorimetric",
+        2 => "saturation",
+        3 => "absolute colorimetric",
+        _ => "unknown",
+    }
+}
+
+impl IccHeader {
+    /// Reads the header and tag table, None if the profile is too short for them.
+    pub fn parse(profile: &[u8]) -> Option<Self> {
+        if profile.len() < HEADER_LENGTH + 4 {
+            return None
+        }
+        let tag_count = u32_at(profile, HEADER_LENGTH) as usize;
+        if (profile.len() - HEADER_LENGTH - 4) / 12 < tag_count {
+            return None
+        }
+
+        Some(Self {
+            size: u32_at(profile, 0),
+            cmm: signature_at(profile, 4),
+            version: (profile[8], profile[9] >> 4, profile[9] & 0xf),
+            device_class: signature_at(profile, 12),
+            colour_space: signature_at(profile, 16),
+            connection_space: signature_at(profile, 20),
+            rendering_intent: u32_at(profile, 64),
+            tags: (0..tag_count).map(|index| {
+                let entry = HEADER_LENGTH + 4 + index*12;
+                IccTag { signature: signature_at(profile, entry), offset: u32_at(profile, entry+4), size: u32_at(profile, entry+8) }
+            }).collect(),
+        })
+    }
+
+    /// A group with the header fields and tag table.
+    ///
+    /// The profile is compressed in the file, so the tokens show its bytes but cover no input, and are all placed at start_bit.
+    pub(crate) fn tokens(&self, profile: &[u8], start_bit: usize) -> Token {
+        let field = |kind: TokenKind, start: usize, end: usize, value: String, description: &str| Token {
+            bits: profile[start..end].to_vec(),
+            using_bytes: true,
+            start_bit,
+            bit_length: 0,
+            children: vec![],
+            data: value,
+            kind,
+            description: format!("{}, profile bytes {} to {}", description, start, end),
+        };
+
+        let mut icc_profile = group_token(TokenKind::IccProfile, format!("{} bytes", profile.len()), "The inflated ICC profile, its header fields and tag table", start_bit);
+        let (major, minor, bug_fix) = self.version;
+        for token in [
+            field(TokenKind::ProfileSize(self.size), 0, 4, format!("{} bytes", self.size), "Size of the profile"),
+            field(TokenKind::PreferredCmm(self.cmm.clone()), 4, 8, self.cmm.clone(), "Preferred colour management module"),
+            field(TokenKind::ProfileVersion { major, minor, bug_fix }, 8, 12, format!("{}.{}.{}", major, minor, bug_fix), "Version of the ICC specification"),
+            field(
+                TokenKind::DeviceClass(self.device_class.clone()), 12, 16,
+                format!("{} ({})", self.device_class, device_class_name(&self.device_class)), "Class of device the profile is for"
+            ),
+            field(TokenKind::ColourSpace(self.colour_space.clone()), 16, 20, self.colour_space.clone(), "Colour space of the image data"),
+            field(TokenKind::ColourSpace(self.connection_space.clone()), 20, 24, self.connection_space.clone(), "Profile connection space"),
+            field(TokenKind::RenderingIntent(self.rendering_intent), 64, 68, rendering_intent_name(self.rendering_intent).to_string(), "Rendering intent"),
+        ] {
+            icc_profile.push_child(token);
+        }
+
+        let mut tag_table = group_token(TokenKind::IccTagTable, format!("{} tags", self.tags.len()), "Signature, offset and size of each tag in the profile", start_bit);
+        tag_table.push_child(field(TokenKind::TagCount(self.tags.len()), HEADER_LENGTH, HEADER_LENGTH + 4, format!("{}", self.tags.len()), "Number of tags"));
+        for (index, tag) in self.tags.iter().enumerate() {
+            let entry = HEADER_LENGTH + 4 + index*12;
+            tag_table.push_child(field(
+                TokenKind::IccTag { signature: tag.signature.clone(), offset: tag.offset, size: tag.size },
+                entry, entry + 12,
+                format!("{}: {} bytes at {}", tag.signature, tag.size, tag.offset),
+                "Tag signature, and the offset and size of its data"
+            ));
+        }
+        icc_profile.push_child(tag_table);
+        icc_profile
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icc_header() {
+        // a display profile with a single tag, the rest of the header is zero
+        let mut profile = vec![0; 128];
+        profile[0..4].copy_from_slice(&[0, 0, 0, 152]);
+        profile[4..8].copy_from_slice(b"lcms");
+        profile[8..10].copy_from_slice(&[4, 0x30]);
+        profile[12..24].copy_from_slice(b"mntrRGB XYZ ");
+        profile[67] = 1;
+        profile.extend([0, 0, 0, 1]);
+        profile.extend(b"desc");
+        profile.extend([0, 0, 0, 144, 0, 0, 0, 8]);
+        profile.extend([0; 8]);
+
+        let header = IccHeader::parse(&profile).unwrap();
+        assert_eq!((header.size, header.cmm.as_str(), header.version), (152, "lcms", (4, 3, 0)));
+        assert_eq!((header.device_class.as_str(), header.colour_space.as_str(), header.rendering_intent), ("mntr", "RGB ", 1));
+        assert_eq!(header.tags, vec![IccTag { signature: "desc".to_string(), offset: 144, size: 8 }]);
+
+        let icc_profile = header.tokens(&profile, 800);
+        let values: Vec<&str> = icc_profile.children.iter().map(|token| token.data.as_str()).collect();
+        assert_eq!(values, vec!["152 bytes", "lcms", "4.3.0", "mntr (display device)", "RGB ", "XYZ ", "relative colorimetric", "1 tags"]);
+        assert_eq!(icc_profile.children[7].children[1].kind, TokenKind::IccTag { signature: "desc".to_string(), offset: 144, size: 8 });
+        assert!(icc_profile.flatten().iter().all(|token| token.start_bit == 800 && token.bit_length == 0));
+
+        // too short for the tag table
+        assert!(IccHeader::parse(&profile[..140]).is_none());
+        assert!(IccHeader::parse(&profile[..100]).is_none());
+    }
+}
@@ -0,0 +1,561 @@
+use std::fmt::Display;
+use crate::error::DecodeError;
+use crate::filter::unfilter_scanlines;
+
+// PNG colour types, RFC 2083 4.1.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorType {
+    Greyscale,
+    Truecolor,
+    Indexed,
+    GreyscaleAlpha,
+    TruecolorAlpha,
+}
+
+impl ColorType {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Greyscale),
+            2 => Some(Self::Truecolor),
+            3 => Some(Self::Indexed),
+            4 => Some(Self::GreyscaleAlpha),
+            6 => Some(Self::TruecolorAlpha),
+            _ => None
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        // samples per pixel
+        match self {
+            Self::Greyscale | Self::Indexed => 1,
+            Self::GreyscaleAlpha => 2,
+            Self::Truecolor => 3,
+            Self::TruecolorAlpha => 4,
+        }
+    }
+
+    pub fn allowed_bit_depths(&self) -> &'static [u8] {
+        match self {
+            Self::Greyscale => &[1, 2, 4, 8, 16],
+            Self::Indexed => &[1, 2, 4, 8],
+            Self::Truecolor | Self::GreyscaleAlpha | Self::TruecolorAlpha => &[8, 16],
+        }
+    }
+}
+
+impl Display for ColorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Greyscale => "greyscale",
+            Self::Truecolor => "truecolour",
+            Self::Indexed => "indexed-colour",
+            Self::GreyscaleAlpha => "greyscale with alpha",
+            Self::TruecolorAlpha => "truecolour with alpha",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn bytes_per_scanline(width: usize, color_type: ColorType, bit_depth: u8) -> Option<usize> {
+    // bytes in a row of filtered data, excluding the filter type byte. rows are padded to a whole byte
+    // None if the width is too large for the row to fit in a usize
+    Some(width.checked_mul(color_type.channels() * bit_depth as usize)?.div_ceil(8))
+}
+
+pub fn filter_bytes_per_pixel(color_type: ColorType, bit_depth: u8) -> usize {
+    // distance to the 'left' byte used by filters, rounded up to 1 for bit depths under 8
+    ((color_type.channels() * bit_depth as usize) / 8).max(1)
+}
+
+pub fn unpack_samples(row: &[u8], samples: usize, bit_depth: u8) -> Vec<u8> {
+    // split a row of packed sub-byte samples into one byte per sample, leftmost sample is in the msb
+    // padding bits at the end of the row are ignored
+    let samples_per_byte = 8 / bit_depth as usize;
+    let mask = (1u16 << bit_depth) as u8 - 1;
+
+    (0..samples)
+        .map(|i| {
+            let byte = row[i / samples_per_byte];
+            let shift = 8 - bit_depth as usize * (1 + i % samples_per_byte);
+            (byte >> shift) & mask
+        })
+        .collect()
+}
+
+fn decode_sub_image(data: &[u8], width: usize, height: usize, color_type: ColorType, bit_depth: u8) -> Result<Vec<u8>, DecodeError> {
+    // unfilter and unpack the scanlines of an image (or a single Adam7 pass) from the start of data
+    if width == 0 || height == 0 {
+        return Ok(Vec::new())
+    }
+
+    let (row_bytes, image_bytes) = bytes_per_scanline(width, color_type, bit_depth)
+        .and_then(|row_bytes| Some((row_bytes, height.checked_mul(row_bytes.checked_add(1)?)?)))
+        .ok_or(DecodeError::Filter {
+            byte_offset: 0,
+            reason: format!("A {}x{} image is too large to decode", width, height)
+        })?;
+    if data.len() < image_bytes {
+        return Err(DecodeError::Filter {
+            byte_offset: data.len(),
+            reason: format!("Image data is truncated, expected {} bytes, found {}", image_bytes, data.len())
+        })
+    }
+
+    let unfiltered = unfilter_scanlines(&data[..image_bytes], row_bytes, filter_bytes_per_pixel(color_type, bit_depth))?;
+
+    if bit_depth < 8 {
+        Ok(
+            unfiltered
+                .chunks(row_bytes)
+                .flat_map(|row| unpack_samples(row, width * color_type.channels(), bit_depth))
+                .collect()
+        )
+    } else {
+        Ok(unfiltered)
+    }
+}
+
+fn filtered_image_len(width: usize, height: usize, color_type: ColorType, bit_depth: u8) -> Option<usize> {
+    // bytes of an image (or Adam7 pass) in the decompressed stream, None if it doesn't fit in a usize
+    // empty images have no filter type bytes
+    if width == 0 || height == 0 {
+        return Some(0)
+    }
+    height.checked_mul(bytes_per_scanline(width, color_type, bit_depth)?.checked_add(1)?)
+}
+
+// Adam7 interlacing, RFC 2083 2.6
+// (first column, first row, column step, row step) of each pass
+const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+
+pub struct Adam7Pass {
+    pub number: usize, // 1-7
+    pub x_start: usize,
+    pub y_start: usize,
+    pub x_step: usize,
+    pub y_step: usize,
+    pub width: usize,  // size of the reduced image, can be 0 for small images
+    pub height: usize,
+}
+
+impl Adam7Pass {
+    pub fn filtered_len(&self, color_type: ColorType, bit_depth: u8) -> Option<usize> {
+        // bytes of this pass in the decompressed stream, None if it doesn't fit in a usize
+        filtered_image_len(self.width, self.height, color_type, bit_depth)
+    }
+
+    pub fn image_position(&self, x: usize, y: usize) -> (usize, usize) {
+        // position in the full image of pixel (x, y) of this pass
+        (self.x_start + x * self.x_step, self.y_start + y * self.y_step)
+    }
+}
+
+pub fn adam7_passes(width: usize, height: usize) -> Vec<Adam7Pass> {
+    ADAM7_PASSES
+        .iter()
+        .enumerate()
+        .map(|(i, &(x_start, y_start, x_step, y_step))| Adam7Pass {
+            number: i+1,
+            x_start,
+            y_start,
+            x_step,
+            y_step,
+            width: width.saturating_sub(x_start).div_ceil(x_step),
+            height: height.saturating_sub(y_start).div_ceil(y_step),
+        })
+        .collect()
+}
+
+pub fn adam7_pass_lens(width: usize, height: usize, color_type: ColorType, bit_depth: u8) -> Result<Vec<(Adam7Pass, usize)>, DecodeError> {
+    // each pass with its length in the decompressed stream
+    // the sizes come from IHDR, so it's an error if the passes don't fit in a usize together
+    let mut total = 0usize;
+    adam7_passes(width, height)
+        .into_iter()
+        .map(|pass| {
+            let pass_len = pass.filtered_len(color_type, bit_depth)?;
+            total = total.checked_add(pass_len)?;
+            Some((pass, pass_len))
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or(DecodeError::Filter {
+            byte_offset: 0,
+            reason: format!("A {}x{} interlaced image is too large to decode", width, height)
+        })
+}
+
+// PLTE and tRNS chunks, RFC 2083 4.1.2 and 4.2.1.1
+#[derive(Clone)]
+pub struct Palette {
+    pub entries: Vec<[u8; 3]>,
+    pub alpha: Vec<u8>, // from tRNS, entries past the end of this are fully opaque
+}
+
+impl Palette {
+    pub fn from_chunk(data: &[u8]) -> Result<Self, String> {
+        if !data.len().is_multiple_of(3) || data.is_empty() || data.len() > 256*3 {
+            return Err(format!("PLTE chunk length {} is not a multiple of 3 between 3 and 768", data.len()))
+        }
+
+        Ok(Self {
+            entries: data.chunks(3).map(|rgb| [rgb[0], rgb[1], rgb[2]]).collect(),
+            alpha: Vec::new()
+        })
+    }
+
+    pub fn rgba(&self, index: u8) -> Option<[u8; 4]> {
+        let [r, g, b] = *self.entries.get(index as usize)?;
+        let a = *self.alpha.get(index as usize).unwrap_or(&255);
+        Some([r, g, b, a])
+    }
+}
+
+// tRNS for colour types without a palette, a single colour that is fully transparent
+pub enum TransparencyKey {
+    Greyscale(u16),
+    Truecolor([u16; 3]),
+}
+
+impl TransparencyKey {
+    pub fn from_chunk(data: &[u8], color_type: ColorType) -> Result<Self, String> {
+        let sample = |i: usize| ((data[i*2] as u16) << 8) | data[i*2 + 1] as u16;
+        match (color_type, data.len()) {
+            (ColorType::Greyscale, 2) => Ok(Self::Greyscale(sample(0))),
+            (ColorType::Truecolor, 6) => Ok(Self::Truecolor([sample(0), sample(1), sample(2)])),
+            _ => Err(format!("tRNS chunk of length {} is invalid for {} images", data.len(), color_type))
+        }
+    }
+}
+
+// RAW IMAGE PIXELS
+// samples are stored row by row, pixel by pixel, in the channel order of the colour type.
+// bit depths under 8 are unpacked to one sample per byte (values are not scaled),
+// 16 bit samples are stored as 2 big endian bytes.
+#[derive(Clone)]
+pub struct ImageData {
+    pub width: usize,
+    pub height: usize,
+    pub color_type: ColorType,
+    pub bit_depth: u8,
+    pub data: Vec<u8>,
+}
+
+impl ImageData {
+    pub fn from_png_stream(data: &[u8], width: usize, height: usize, color_type: ColorType, bit_depth: u8, interlaced: bool) -> Result<Self, DecodeError> {
+        // data is the decompressed, filtered image stream
+        // bit_depth must be one of color_type.allowed_bit_depths()
+        let data = if interlaced {
+            Self::deinterlace(data, width, height, color_type, bit_depth)?
+        } else {
+            decode_sub_image(data, width, height, color_type, bit_depth)?
+        };
+
+        Ok(Self {
+            width,
+            height,
+            color_type,
+            bit_depth,
+            data
+        })
+    }
+
+    fn deinterlace(data: &[u8], width: usize, height: usize, color_type: ColorType, bit_depth: u8) -> Result<Vec<u8>, DecodeError> {
+        // decode each pass as its own image, then scatter its pixels into the full image
+        // the sizes come from IHDR, so they're checked against the data before anything is allocated
+        let bytes_per_pixel = color_type.channels() * if bit_depth == 16 { 2 } else { 1 };
+        let passes = adam7_pass_lens(width, height, color_type, bit_depth)?;
+        let expected_len = passes.iter().map(|(_, pass_len)| pass_len).sum();
+        let image_len = width.checked_mul(height).and_then(|pixels| pixels.checked_mul(bytes_per_pixel)).ok_or(DecodeError::Filter {
+            byte_offset: 0,
+            reason: format!("A {}x{} interlaced image is too large to decode", width, height)
+        })?;
+        if data.len() != expected_len {
+            return Err(DecodeError::Filter {
+                byte_offset: data.len().min(expected_len),
+                reason: format!("Interlaced image data is {} bytes, expected {}", data.len(), expected_len)
+            })
+        }
+        let mut pixels = vec![0u8; image_len];
+
+        let mut pass_start = 0;
+        for (pass, pass_len) in passes {
+            let pass_data = decode_sub_image(&data[pass_start.min(data.len())..], pass.width, pass.height, color_type, bit_depth)
+                .map_err(|error| error.shifted(pass_start))?;
+            pass_start += pass_len;
+
+            for (i, pixel) in pass_data.chunks(bytes_per_pixel).enumerate() {
+                let (x, y) = pass.image_position(i % pass.width, i / pass.width);
+                let start = (y * width + x) * bytes_per_pixel;
+                pixels[start..start+bytes_per_pixel].copy_from_slice(pixel);
+            }
+        }
+        Ok(pixels)
+    }
+
+    pub fn expand_palette(self, palette: &Palette) -> Result<Self, String> {
+        // replace every palette index with its colour, 8 bit truecolour with alpha if the palette has tRNS values
+        let with_alpha = !palette.alpha.is_empty();
+        let color_type = if with_alpha { ColorType::TruecolorAlpha } else { ColorType::Truecolor };
+
+        let mut data = Vec::with_capacity(self.data.len() * color_type.channels());
+        for (pixel, &index) in self.data.iter().enumerate() {
+            let rgba = palette.rgba(index).ok_or(format!(
+                "Pixel ({}, {}) uses palette index {}, but the palette only has {} entries",
+                pixel % self.width, pixel / self.width, index, palette.entries.len()
+            ))?;
+            data.extend_from_slice(if with_alpha { &rgba } else { &rgba[..3] });
+        }
+
+        Ok(Self {
+            color_type,
+            bit_depth: 8,
+            data,
+            ..self
+        })
+    }
+
+    pub fn apply_transparency_key(self, key: &TransparencyKey) -> Self {
+        // add an alpha channel, 0 for pixels matching the key, max value for the bit depth otherwise
+        let color_type = match key {
+            TransparencyKey::Greyscale(_) => ColorType::GreyscaleAlpha,
+            TransparencyKey::Truecolor(_) => ColorType::TruecolorAlpha,
+        };
+        let key_samples: &[u16] = match key {
+            TransparencyKey::Greyscale(grey) => std::slice::from_ref(grey),
+            TransparencyKey::Truecolor(rgb) => rgb,
+        };
+        let max_value = ((1u32 << self.bit_depth) - 1) as u16;
+
+        let mut data = Vec::with_capacity(self.data.len() / self.color_type.channels() * color_type.channels());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let matches_key = key_samples
+                    .iter()
+                    .enumerate()
+                    .all(|(channel, &key_sample)| self.sample(x, y, channel) == key_sample);
+                let alpha = if matches_key { 0 } else { max_value };
+
+                data.extend_from_slice(self.pixel(x, y));
+                if self.bit_depth == 16 {
+                    data.extend_from_slice(&alpha.to_be_bytes());
+                } else {
+                    data.push(alpha as u8);
+                }
+            }
+        }
+
+        Self {
+            color_type,
+            data,
+            ..self
+        }
+    }
+
+    pub fn to_rgba(&self) -> Self {
+        // truecolour with alpha, 16 bit images stay 16 bit and lower bit depths are scaled up to 8 bit
+        let bit_depth = if self.bit_depth == 16 { 16 } else { 8 };
+        let max_value = ((1u32 << self.bit_depth) - 1) as u16;
+        let scale = |sample: u16| if self.bit_depth < 8 { (sample as u32 * 255 / max_value as u32) as u16 } else { sample };
+        let channels: [usize; 4] = match self.color_type {
+            ColorType::Greyscale | ColorType::Indexed => [0, 0, 0, 4],
+            ColorType::GreyscaleAlpha => [0, 0, 0, 1],
+            ColorType::Truecolor => [0, 1, 2, 4],
+            ColorType::TruecolorAlpha => [0, 1, 2, 3],
+        };
+
+        let mut data = Vec::with_capacity(self.width * self.height * 4 * (bit_depth as usize / 8));
+        for y in 0..self.height {
+            for x in 0..self.width {
+                for channel in channels {
+                    // channel 4 is a missing alpha channel, so fully opaque
+                    let sample = if channel == 4 { max_value } else { self.sample(x, y, channel) };
+                    if bit_depth == 16 {
+                        data.extend_from_slice(&sample.to_be_bytes());
+                    } else {
+                        data.push(scale(sample) as u8);
+                    }
+                }
+            }
+        }
+
+        Self {
+            width: self.width,
+            height: self.height,
+            color_type: ColorType::TruecolorAlpha,
+            bit_depth,
+            data,
+        }
+    }
+
+    pub fn bytes_per_sample(&self) -> usize {
+        if self.bit_depth == 16 { 2 } else { 1 }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.color_type.channels() * self.bytes_per_sample()
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> &[u8] {
+        // bytes of the pixel at column x, row y
+        let bpp = self.bytes_per_pixel();
+        let start = (y * self.width + x) * bpp;
+        &self.data[start..start+bpp]
+    }
+
+    pub fn sample(&self, x: usize, y: usize, channel: usize) -> u16 {
+        // value of one channel of a pixel, widened to u16 for 16 bit images
+        let pixel = self.pixel(x, y);
+        match self.bit_depth {
+            16 => ((pixel[channel*2] as u16) << 8) | pixel[channel*2 + 1] as u16,
+            _ => pixel[channel] as u16
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unpack_samples() {
+        // 10110001
+        assert_eq!(unpack_samples(&[177], 8, 1), vec![1, 0, 1, 1, 0, 0, 0, 1]);
+        assert_eq!(unpack_samples(&[177], 4, 2), vec![2, 3, 0, 1]);
+        // padding bits at the end of the row are dropped
+        assert_eq!(unpack_samples(&[177, 240], 3, 4), vec![11, 1, 15]);
+    }
+
+    #[test]
+    fn test_greyscale_2_bit() {
+        // 3x2 image, each row is 6 bits padded to 1 byte
+        let data = vec![
+            0, 0b00011011,
+            2, 0b01000000,
+        ];
+        let image = ImageData::from_png_stream(&data, 3, 2, ColorType::Greyscale, 2, false).unwrap();
+
+        assert_eq!(image.data, vec![0, 1, 2, 1, 1, 2]);
+    }
+
+    #[test]
+    fn test_to_rgba() {
+        // 2 bit greyscale is scaled up to 8 bit, with an opaque alpha channel
+        let image = ImageData::from_png_stream(&[0, 0b00011011], 3, 1, ColorType::Greyscale, 2, false).unwrap().to_rgba();
+        assert_eq!((image.color_type, image.bit_depth), (ColorType::TruecolorAlpha, 8));
+        assert_eq!(image.data, vec![0, 0, 0, 255, 85, 85, 85, 255, 170, 170, 170, 255]);
+
+        let image = ImageData { width: 1, height: 1, color_type: ColorType::GreyscaleAlpha, bit_depth: 16, data: vec![1, 2, 3, 4] }.to_rgba();
+        assert_eq!((image.bit_depth, image.data), (16, vec![1, 2, 1, 2, 1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_truecolor_alpha_16_bit() {
+        let data = vec![
+            0, 1, 2, 3, 4, 5, 6, 7, 8,
+        ];
+        let image = ImageData::from_png_stream(&data, 1, 1, ColorType::TruecolorAlpha, 16, false).unwrap();
+
+        assert_eq!(image.bytes_per_pixel(), 8);
+        assert_eq!(image.sample(0, 0, 0), 258);
+        assert_eq!(image.sample(0, 0, 3), 1800);
+    }
+
+    #[test]
+    fn test_expand_palette() {
+        let mut palette = Palette::from_chunk(&[255, 0, 0, 0, 255, 0, 0, 0, 255]).unwrap();
+        // 1 bit indices, 10100000
+        let image = ImageData::from_png_stream(&[0, 160], 3, 1, ColorType::Indexed, 1, false).unwrap();
+        assert_eq!(image.data, vec![1, 0, 1]);
+
+        let rgb = ImageData::from_png_stream(&[0, 160], 3, 1, ColorType::Indexed, 1, false).unwrap().expand_palette(&palette).unwrap();
+        assert_eq!(rgb.color_type, ColorType::Truecolor);
+        assert_eq!(rgb.data, vec![0, 255, 0, 255, 0, 0, 0, 255, 0]);
+
+        // first entry is half transparent, others are opaque
+        palette.alpha = vec![128];
+        let rgba = image.expand_palette(&palette).unwrap();
+        assert_eq!(rgba.color_type, ColorType::TruecolorAlpha);
+        assert_eq!(rgba.data, vec![0, 255, 0, 255, 255, 0, 0, 128, 0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn test_apply_transparency_key() {
+        let image = ImageData::from_png_stream(&[0, 5, 9, 5], 3, 1, ColorType::Greyscale, 8, false).unwrap();
+        let key = TransparencyKey::from_chunk(&[0, 5], ColorType::Greyscale).unwrap();
+
+        let image = image.apply_transparency_key(&key);
+        assert_eq!(image.color_type, ColorType::GreyscaleAlpha);
+        assert_eq!(image.data, vec![5, 0, 9, 255, 5, 0]);
+
+        // 2 bit greyscale, alpha uses the same bit depth
+        let image = ImageData::from_png_stream(&[0, 0b00011011], 4, 1, ColorType::Greyscale, 2, false).unwrap();
+        let key = TransparencyKey::from_chunk(&[0, 2], ColorType::Greyscale).unwrap();
+        assert_eq!(image.apply_transparency_key(&key).data, vec![0, 3, 1, 3, 2, 0, 3, 3]);
+    }
+
+    #[test]
+    fn test_adam7_passes() {
+        let sizes: Vec<(usize, usize)> = adam7_passes(5, 3).iter().map(|pass| (pass.width, pass.height)).collect();
+        assert_eq!(sizes, vec![(1, 1), (1, 1), (2, 0), (1, 1), (3, 1), (2, 2), (5, 1)]);
+
+        assert_eq!(adam7_passes(8, 8).iter().map(|pass| pass.width * pass.height).sum::<usize>(), 64);
+    }
+
+    #[test]
+    fn test_deinterlace() {
+        // 3x3 8 bit greyscale, pixel value is its index in the full image
+        // pass 1: (0, 0), pass 4: (2, 0), pass 5: (0, 2) (2, 2), pass 6: (1, 0) (1, 2), pass 7: row 1
+        let data = vec![
+            0, 0,
+            0, 2,
+            0, 6, 8,
+            0, 1, 0, 7,
+            0, 3, 4, 5,
+        ];
+        let image = ImageData::from_png_stream(&data, 3, 3, ColorType::Greyscale, 8, true).unwrap();
+        assert_eq!(image.data, vec![0, 1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_truncated_pass() {
+        // 3x3 interlaced image from test_deinterlace, missing the last row of pass 6
+        let data = vec![
+            0, 0,
+            0, 2,
+            0, 6, 8,
+            0, 1,
+        ];
+        let error = ImageData::from_png_stream(&data, 3, 3, ColorType::Greyscale, 8, true).err().unwrap();
+        assert_eq!(error.stage(), "filter");
+        assert_eq!(error.bit_offset(), 9*8);
+
+        // sizes that would overflow are an error rather than an allocation
+        let error = ImageData::from_png_stream(&data, usize::MAX / 2, 3, ColorType::TruecolorAlpha, 16, true).err().unwrap();
+        assert_eq!(error.to_string(), format!("filter error at byte 0: A {}x3 interlaced image is too large to decode", usize::MAX / 2));
+        let error = ImageData::from_png_stream(&data, 1 << 12, 1 << 12, ColorType::Greyscale, 8, true).err().unwrap();
+        assert_eq!(error.bit_offset(), 9*8);
+    }
+
+    #[test]
+    fn test_image_too_large() {
+        // rows, or the whole image, that don't fit in a usize are an error rather than an overflow
+        let error = ImageData::from_png_stream(&[0, 0], usize::MAX / 2, 1, ColorType::TruecolorAlpha, 16, false).err().unwrap();
+        assert_eq!(error.to_string(), format!("filter error at byte 0: A {}x1 image is too large to decode", usize::MAX / 2));
+        let error = ImageData::from_png_stream(&[0, 0], 1, usize::MAX / 4, ColorType::TruecolorAlpha, 16, false).err().unwrap();
+        assert_eq!(error.bit_offset(), 0);
+
+        // a large image that fits is only truncated
+        let error = ImageData::from_png_stream(&[0, 0], 1 << 12, 1 << 12, ColorType::Greyscale, 8, false).err().unwrap();
+        assert_eq!(error.bit_offset(), 2*8);
+    }
+
+    #[test]
+    fn test_palette_index_out_of_range() {
+        let palette = Palette::from_chunk(&[255, 0, 0]).unwrap();
+        let image = ImageData::from_png_stream(&[0, 0, 1], 2, 1, ColorType::Indexed, 8, false).unwrap();
+        assert!(image.expand_palette(&palette).is_err());
+
+        assert!(Palette::from_chunk(&[255, 0]).is_err());
+    }
+}
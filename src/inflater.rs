@@ -0,0 +1,347 @@
+use crate::bitstream::BitStream;
+use crate::error::DecodeError;
+use crate::huffman_coding::*;
+use crate::token::Alphabet;
+
+// string references can reach at most 32 KiB back (RFC 1951 2)
+const WINDOW_SIZE: usize = 32768;
+
+// the last 32 KiB of output, in a ring buffer, used by the token path too
+pub(crate) struct Window {
+    bytes: Vec<u8>,
+    position: usize, // where the next byte goes
+    filled: usize, // bytes written so far, up to the window size
+}
+
+impl Window {
+    pub(crate) fn new() -> Self {
+        Self {
+            bytes: vec![0; WINDOW_SIZE],
+            position: 0,
+            filled: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, byte: u8, output: &mut Vec<u8>) {
+        self.bytes[self.position] = byte;
+        self.position = (self.position + 1) % WINDOW_SIZE;
+        self.filled = (self.filled + 1).min(WINDOW_SIZE);
+        output.push(byte);
+    }
+
+    fn copy(&mut self, length: u16, distance: u16, output: &mut Vec<u8>) {
+        // the reference can overlap the bytes it produces, so they're copied one at a time
+        for _ in 0..length {
+            let byte = self.bytes[(self.position + WINDOW_SIZE - distance as usize) % WINDOW_SIZE];
+            self.push(byte, output);
+        }
+    }
+
+    pub(crate) fn reference(&mut self, length: u16, distance: u16, bit_offset: usize, output: &mut Vec<u8>) -> Result<(), DecodeError> {
+        // a string reference, which can't go back past the first byte
+        check_distance(distance, self.filled, bit_offset)?;
+        self.copy(length, distance, output);
+        Ok(())
+    }
+}
+
+fn dynamic_tables(data: &mut BitStream) -> Result<(HuffmanTable, HuffmanTable), DecodeError> {
+    // the same as dynamic_huffman_header in the token path, reading the codelengths with the same functions but without making any tokens
+    let num_of_normal_codes = data.bits(5)? as usize + 257;
+    let num_of_dist_codes = data.bits(5)? as usize + 1;
+    let num_of_codelength_codes = data.bits(4)? as usize + 4;
+
+    let code_length_codelengths_bit_offset = data.current_abs_bit_position();
+    let code_length_codelengths = read_code_length_codelengths(data, num_of_codelength_codes)?;
+    check_codelengths(&code_length_codelengths, Alphabet::CodeLength, code_length_codelengths_bit_offset)?;
+    let code_length_table = huffman_table(&code_length_codelengths);
+
+    let max_codes = num_of_normal_codes + num_of_dist_codes;
+    let mut lengths = Vec::with_capacity(max_codes);
+    let literal_length_bit_offset = data.current_abs_bit_position();
+    read_codelengths(data, &mut lengths, num_of_normal_codes, max_codes, &code_length_table, |_, _, _, _| ())?;
+    check_codelengths(&lengths[..num_of_normal_codes], Alphabet::LiteralLength, literal_length_bit_offset)?;
+
+    let distance_bit_offset = data.current_abs_bit_position();
+    read_codelengths(data, &mut lengths, max_codes, max_codes, &code_length_table, |_, _, _, _| ())?;
+    check_codelengths(&lengths[num_of_normal_codes..], Alphabet::Distance, distance_bit_offset)?;
+
+    Ok((huffman_table(&lengths[..num_of_normal_codes]), huffman_table(&lengths[num_of_normal_codes..])))
+}
+
+enum State {
+    BlockHeader,
+    StoredHeader,
+    Stored { remaining: usize },
+    Symbols { tables: Option<(HuffmanTable, HuffmanTable)> }, // literal/length and distance tables, None for the fixed codes
+    Done,
+}
+
+enum Step {
+    Continue,
+    NeedInput,
+    Done,
+}
+
+/// Decompresses a raw deflate stream (RFC 1951) that arrives in pieces, such as IDAT chunks or network reads.
+///
+/// Each [`push`](Inflater::push) decodes as much as it can, and a symbol cut off at the end of the input is
+/// decoded once the rest of it is pushed. Only the last 32 KiB of output is kept for string references.
+///
+/// ```
+/// // "hello hello hello", split part way through a symbol
+/// let data = [203, 72, 205, 201, 201, 87, 200, 64, 144, 0];
+/// let mut inflater = png_decoder::Inflater::new();
+/// let mut output = Vec::new();
+/// inflater.push(&data[..3], &mut output).unwrap();
+/// inflater.push(&data[3..], &mut output).unwrap();
+/// inflater.finish().unwrap();
+/// assert_eq!(output, b"hello hello hello");
+/// ```
+pub struct Inflater {
+    input: BitStream<'static>, // bytes before the one being read are dropped after each push
+    dropped_bytes: usize, // bytes dropped from the front of the input, so errors are from the start of the stream
+    state: State,
+    bfinal: bool,
+    window: Window,
+    error: Option<DecodeError>,
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Inflater {
+    pub fn new() -> Self {
+        Self {
+            input: BitStream::new(Vec::new()),
+            dropped_bytes: 0,
+            state: State::BlockHeader,
+            bfinal: false,
+            window: Window::new(),
+            error: None,
+        }
+    }
+
+    /// Decodes the next piece of the stream, adding the decompressed bytes to output.
+    ///
+    /// Bytes decoded before an error are still added. Once there has been an error, every push returns it.
+    /// Input after the end of the final block is ignored, and isn't kept.
+    pub fn push(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), DecodeError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone())
+        }
+        if self.is_done() {
+            return Ok(())
+        }
+
+        self.input.bytes.to_mut().extend_from_slice(input);
+        let result = self.decode(output).map_err(|error| error.shifted(self.dropped_bytes));
+
+        // bytes that have been read won't be needed again
+        self.dropped_bytes += self.input.drop_read_bytes();
+
+        if let Err(error) = &result {
+            self.error = Some(error.clone());
+        }
+        result
+    }
+
+    /// Checks that the stream is complete, for when there is no more input.
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone())
+        }
+        if !self.is_done() {
+            return Err(DecodeError::DeflateBlock {
+                bit_offset: self.dropped_bytes*8 + self.input.current_abs_bit_position(),
+                reason: "Unexpected end of data, the stream ends before the final block".to_string()
+            })
+        }
+        Ok(())
+    }
+
+    /// Whether the final block has been decoded.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    fn decode(&mut self, output: &mut Vec<u8>) -> Result<(), DecodeError> {
+        loop {
+            // a step that runs out of input is read again from its start when there is more
+            let step_start = self.input.current_abs_bit_position();
+            match self.step(output) {
+                Ok(Step::Continue) => continue,
+                Ok(Step::NeedInput) | Ok(Step::Done) => return Ok(()),
+                Err(_) if self.input.ran_out() => {
+                    self.input.seek(step_start);
+                    return Ok(())
+                },
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn end_of_block(&self) -> State {
+        if self.bfinal {State::Done} else {State::BlockHeader}
+    }
+
+    fn step(&mut self, output: &mut Vec<u8>) -> Result<Step, DecodeError> {
+        // reads one block header or symbol, nothing is output unless all of its bits are there
+        let data = &mut self.input;
+        match &mut self.state {
+            State::BlockHeader => {
+                let bfinal = data.next_bit()? == 1;
+                let btype_bit_offset = data.current_abs_bit_position();
+                let state = match data.bits(2)? {
+                    0 => State::StoredHeader,
+                    1 => State::Symbols { tables: None },
+                    2 => State::Symbols { tables: Some(dynamic_tables(data)?) },
+                    _ => return Err(DecodeError::DeflateBlock {
+                        bit_offset: btype_bit_offset,
+                        reason: "BTYPE has reserved value (11)".to_string()
+                    })
+                };
+                self.bfinal = bfinal;
+                self.state = state;
+            },
+            State::StoredHeader => {
+                data.align_to_byte();
+                let length_bit_offset = data.current_abs_bit_position();
+                let header = [data.next_byte()?, data.next_byte()?, data.next_byte()?, data.next_byte()?];
+                if (header[0], header[1]) != (!header[2], !header[3]) {
+                    return Err(DecodeError::DeflateBlock {
+                        bit_offset: length_bit_offset,
+                        reason: format!("LEN {:?} is not the complement of NLEN {:?}", (header[0], header[1]), (header[2], header[3]))
+                    })
+                }
+                self.state = State::Stored { remaining: u16::from_le_bytes([header[0], header[1]]) as usize };
+            },
+            State::Stored { remaining } => {
+                if *remaining == 0 {
+                    self.state = self.end_of_block();
+                    return Ok(Step::Continue)
+                }
+                let bytes = data.aligned_bytes(*remaining);
+                if bytes.is_empty() {
+                    return Ok(Step::NeedInput)
+                }
+
+                for &byte in bytes {
+                    self.window.push(byte, output);
+                }
+                *remaining -= bytes.len();
+            },
+            State::Symbols { tables } => {
+                let start_bit = data.current_abs_bit_position();
+                let (literal_length_table, distance_table) = match tables {
+                    None => (fixed_literal_length_table(), fixed_distance_table()),
+                    Some((literal_length, distance)) => (&*literal_length, &*distance),
+                };
+
+                match decode_symbol(data, literal_length_table, distance_table)? {
+                    Symbol::Literal(byte) => self.window.push(byte, output),
+                    Symbol::EndOfBlock => self.state = self.end_of_block(),
+                    Symbol::Reference { length, distance } => self.window.reference(length, distance, start_bit, output)?,
+                }
+            },
+            State::Done => return Ok(Step::Done),
+        }
+        Ok(Step::Continue)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::new_parse_deflate;
+
+    fn inflate_in_pieces(data: &[u8], piece_length: usize) -> Result<Vec<u8>, DecodeError> {
+        let mut inflater = Inflater::new();
+        let mut output = Vec::new();
+        for piece in data.chunks(piece_length) {
+            inflater.push(piece, &mut output)?;
+        }
+        inflater.finish()?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_split_input() {
+        // dynamic, fixed and stored blocks give the same output however the input is split
+        let streams = [
+            vec![29, 198, 73, 1, 0, 0, 16, 64, 192, 172, 163, 127, 136, 61, 60, 32, 42, 151, 157, 55, 94, 29, 12],
+            vec![203, 72, 205, 201, 201, 87, 200, 64, 144, 0],
+            vec![0, 3, 0, 252, 255, 97, 98, 99, 1, 2, 0, 253, 255, 100, 101],
+        ];
+        for data in streams {
+            let (_tokens, expected) = new_parse_deflate(&data).unwrap();
+            for piece_length in 1..=data.len() {
+                assert_eq!(inflate_in_pieces(&data, piece_length).unwrap(), expected, "pieces of {} bytes", piece_length);
+            }
+
+            // split at every position, each push returns the bytes that can be decoded so far
+            for split in 0..=data.len() {
+                let mut inflater = Inflater::new();
+                let mut output = Vec::new();
+                inflater.push(&data[..split], &mut output).unwrap();
+                assert!(expected.starts_with(&output));
+                inflater.push(&data[split..], &mut output).unwrap();
+                assert!(inflater.is_done());
+                assert_eq!(output, expected);
+            }
+        }
+
+        // input after the final block isn't added to the output or kept
+        let mut inflater = Inflater::new();
+        let mut output = Vec::new();
+        inflater.push(&[203, 72, 205, 201, 201, 87, 200, 64, 144, 0], &mut output).unwrap();
+        for _ in 0..100 {
+            inflater.push(&[0; 1024], &mut output).unwrap();
+        }
+        assert_eq!(output, b"hello hello hello");
+        assert!(inflater.input.bytes.len() < 1024);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut window = Window::new();
+        let mut output = Vec::new();
+        for i in 0..40000 {
+            window.push((i % 251) as u8, &mut output);
+        }
+        assert_eq!(window.filled, WINDOW_SIZE);
+
+        // furthest back reference, and one that overlaps its own output
+        window.copy(2, 32768, &mut output);
+        assert_eq!(output[40000..], [((40000 - 32768) % 251) as u8, ((40001 - 32768) % 251) as u8]);
+        window.copy(5, 2, &mut output);
+        assert_eq!(output[40002..], [output[40000], output[40001], output[40000], output[40001], output[40000]]);
+    }
+
+    #[test]
+    fn test_errors() {
+        // truncated
+        let error = inflate_in_pieces(&[203, 72, 205, 201, 201, 87], 2).unwrap_err();
+        assert_eq!(error.stage(), "deflate block");
+
+        // fixed block whose first symbol is a reference, error is kept for later pushes
+        let mut inflater = Inflater::new();
+        let mut output = Vec::new();
+        let error = inflater.push(&[3, 2, 0], &mut output).unwrap_err();
+        assert_eq!(error, DecodeError::DeflateBlock {
+            bit_offset: 3,
+            reason: "String reference distance 1 is before the start of the data, 0 bytes have been decoded".to_string()
+        });
+        assert_eq!(inflater.push(&[0], &mut output).unwrap_err(), error);
+
+        // stored length doesn't match its complement, offset counts bytes from earlier pushes
+        let mut inflater = Inflater::new();
+        inflater.push(&[0], &mut output).unwrap();
+        let error = inflater.push(&[3, 0, 0, 0], &mut output).unwrap_err();
+        assert_eq!(error.bit_offset(), 8);
+    }
+}
@@ -1,31 +1,130 @@
 #![allow(dead_code)]
+//! Decodes and tokenises zlib streams and PNG files.
+//!
+//! The decoding functions return the decompressed data or image, and the tokenising functions return
+//! a tree of [`Token`]s that breaks the input down into its structure, ending with an `error` token if the input is damaged.
+//!
+//! ```
+//! // "hello hello hello"
+//! let data = [120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 125];
+//! assert_eq!(png_decoder::decode_zlib(&data).unwrap(), b"hello hello hello");
+//! ```
+//!
+//! The wasm exports used by the website are behind the `wasm` feature.
 
 mod png_parser;
+mod ancillary;
+mod icc;
+mod apng;
+mod chunk_reader;
 mod low_level_functions;
 mod zlib;
 mod deflate;
 mod bitstream;
 mod huffman_coding;
+mod filter;
+mod image_data;
 mod token;
+mod error;
+mod inflater;
+mod sink;
+#[cfg(test)]
+mod test_fixtures;
+#[cfg(feature = "wasm")]
+mod wasm;
 
+pub use apng::{Animation, AnimationControl, AnimationFrame, BlendOp, DisposeOp, Frame, FrameControl, Frames};
+pub use ancillary::{AncillaryChunks, Background, Chromaticities, IccProfile, PhysicalDimensions, SuggestedPalette, SuggestedPaletteEntry, TextChunk, TextEntry, Time, UndecodedText};
+pub use error::DecodeError;
+pub use icc::{IccHeader, IccTag};
+pub use image_data::{ColorType, ImageData};
+pub use inflater::Inflater;
+pub use png_parser::{PNGMetadata, PNGParser};
+pub use sink::{JsonWriter, OpenGroups, TokenCollector, TokenSink, TokenStats};
+pub use token::{tokens_to_json, Alphabet, Token, TokenKind};
 
-use png_parser::PNGParser;
-use wasm_bindgen::prelude::*;
-use zlib::new_parse_zlib;
+/// Decompresses a raw deflate stream (RFC 1951), with no zlib header or checksum.
+///
+/// Use an [`Inflater`] to decompress a stream that arrives in pieces.
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut inflater = Inflater::new();
+    let mut decompressed = Vec::new();
+    inflater.push(data, &mut decompressed)?;
+    inflater.finish()?;
+    Ok(decompressed)
+}
+
+/// Decompresses a zlib stream (RFC 1950), checking the header and the Adler-32 checksum.
+///
+/// No tokens are made, so this is much faster than [`tokenise_zlib`].
+pub fn decode_zlib(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    zlib::decode_zlib(data)
+}
 
-extern crate web_sys;
+/// Decodes a PNG file to its header metadata and pixels.
+///
+/// No tokens are made, use a [`PNGParser`] to get them as well.
+/// Chunk CRC mismatches are ignored, use [`PNGParser::decode_strict`] to reject them.
+pub fn decode_png(data: &[u8]) -> Result<(PNGMetadata, ImageData), DecodeError> {
+    PNGParser::decode(data)
+}
 
-#[wasm_bindgen]
-pub fn decode_png(data: Vec<u8>) -> String {
-    let parser = PNGParser::new(data);
-    
-    format!("{:?}", parser.tokens)
+/// Breaks a zlib stream down into a tree of tokens, with the header fields, deflate blocks and checksum below the root.
+///
+/// If the stream is damaged the tokens decoded so far are returned, and the last child of the root is an `error` token.
+pub fn tokenise_zlib(data: &[u8]) -> Token {
+    zlib::tokenise_zlib(data)
 }
 
-#[wasm_bindgen]
-pub fn decode_zlib(data: Vec<u8>) -> String {
-    let (tokens, _decompressed) = new_parse_zlib(&data);
-    let token_string = format!("{:?}", tokens);
-    token_string
+/// Breaks a PNG file down into a tree of tokens, with the signature and chunks below the root.
+///
+/// If the file is damaged the tokens decoded so far are returned, and the last child of the root is an `error` token.
+pub fn tokenise_png(data: &[u8]) -> Token {
+    PNGParser::tokenise(data)
 }
 
+/// Sends the tokens of a zlib stream to a sink as they are decoded, instead of building the whole tree.
+///
+/// The tokens are the same as [`tokenise_zlib`] returns, and the decompressed data is returned as well.
+///
+/// ```
+/// let data = [120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 125];
+/// let mut stats = png_decoder::TokenStats::new();
+/// png_decoder::tokenise_zlib_into(&data, &mut stats).unwrap();
+/// // "hello h" then a reference back to "hello hell"
+/// assert_eq!((stats.counts["literal"], stats.counts["string reference"]), (7, 1));
+/// ```
+pub fn tokenise_zlib_into(data: &[u8], sink: &mut dyn TokenSink) -> Result<Vec<u8>, DecodeError> {
+    zlib::tokenise_zlib_into(data, sink)
+}
+
+/// Sends the tokens of a PNG file to a sink as they are decoded, instead of building the whole tree.
+///
+/// The tokens are the same as [`tokenise_png`] returns, and the decoded image is returned as well.
+pub fn tokenise_png_into(data: &[u8], sink: &mut dyn TokenSink) -> Result<(PNGMetadata, ImageData), DecodeError> {
+    PNGParser::tokenise_into(data, sink)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_public_api() {
+        // "hello hello hello", deflate stream is between the 2 byte header and 4 byte checksum
+        let data = [120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 125];
+        assert_eq!(inflate(&data[2..12]).unwrap(), b"hello hello hello");
+        assert_eq!(decode_zlib(&data).unwrap(), b"hello hello hello");
+        assert_eq!(tokenise_zlib(&data).children.last().unwrap().kind, TokenKind::Adler32 { stored: 976094845, calculated: 976094845 });
+
+        // 2x2 8 bit truecolour
+        let data = crate::test_fixtures::rgb_png();
+        let (metadata, image) = decode_png(&data).unwrap();
+        assert_eq!((metadata.width, metadata.height), (2, 2));
+        assert_eq!(image.pixel(1, 1), &[17, 27, 37]);
+        assert_eq!(tokenise_png(&data).children[0].kind, TokenKind::Signature);
+
+        assert_eq!(decode_png(&data[..40]).err().unwrap().stage(), "PNG chunk");
+    }
+}
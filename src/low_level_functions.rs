@@ -10,26 +10,7 @@ pub fn bytes_vec_to_single(hex_vec: &Vec<u8>) -> u32 {
     running_value
 }
 
-pub fn bits_to_byte(bits: &Vec<u8>, big_endian: bool) -> u8 {
-    // big endian: first bit is msb
-    // little endian: first bit is lsb
-
-    // result is aligned to left (e.g. if 7 bits given, lsb in byte will be 0)
-    assert!(bits.len() <= 8);
-    let mut running_value = 0u8;
-    for &bit in bits {
-        
-        running_value = match big_endian {
-            // shift running value to right, shift bit to '128 value', bitwise OR
-            false => (running_value >> 1) | (bit << 7),
-            // shift to left, bitwise OR with next bit
-            true => (running_value << 1) | bit,
-        }
-    }
-    running_value
-}
-
-pub fn adler_32(bytes: &Vec<u8>) -> u32 {
+pub fn adler_32(bytes: &[u8]) -> u32 {
     let mut a = 1u32;
     let mut b = 0u32;
 
@@ -41,6 +22,34 @@ pub fn adler_32(bytes: &Vec<u8>) -> u32 {
     ((b as u32) << 16) | (a as u32)
 }
 
+// lookup table of the crc of every byte value, using the reversed polynomial 0xEDB88320
+const CRC_32_TABLE: [u32; 256] = crc_32_table();
+
+const fn crc_32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+pub fn crc_32(bytes: &[u8]) -> u32 {
+    // RFC 2083 15
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in bytes {
+        crc = CRC_32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc ^ 0xFFFFFFFF
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -48,12 +57,22 @@ mod tests {
 
     #[test]
     fn test_adler_32() {
-        assert_eq!(adler_32(&vec![97, 98, 99]), 38600999);
+        assert_eq!(adler_32(&[97, 98, 99]), 38600999);
+
+        assert_eq!(adler_32(&[0]), 65537);
 
-        assert_eq!(adler_32(&vec![0]), 65537);
+        assert_eq!(adler_32(&[65, 98, 67]), 30212327);
+
+        assert_eq!(adler_32(&[126, 124]), 24772859);
+    }
+
+    #[test]
+    fn test_crc_32() {
+        assert_eq!(crc_32(&[]), 0);
 
-        assert_eq!(adler_32(&vec![65, 98, 67]), 30212327);
+        assert_eq!(crc_32(b"123456789"), 0xCBF43926);
 
-        assert_eq!(adler_32(&vec![126, 124]), 24772859);
+        // IEND chunk type with no data
+        assert_eq!(crc_32(b"IEND"), 0xAE426082);
     }
 }
@@ -1,20 +1,32 @@
 use std::fmt::Display;
+use crate::ancillary::{AncillaryChunk, AncillaryChunks, TextEntry};
+use crate::apng::{sequence_number_token, Animation, AnimationFrame, AnimationReader, Frames};
+use crate::chunk_reader::ChunkReader;
+use crate::error::DecodeError;
+use crate::filter::filter_name;
+use crate::image_data::{adam7_pass_lens, ColorType, ImageData, Palette, TransparencyKey};
 use crate::low_level_functions::bytes_vec_to_single;
-use crate::token::Token;
-use crate::zlib::new_parse_zlib;
+use crate::sink::{MovedTokens, NoTokens, TokenCollector, TokenSink};
+use crate::token::{decode_group, error_token, group_token, ignored_error_token, Token, TokenKind};
+use crate::zlib::{decode_zlib, parse_zlib_into};
+
+pub(crate) const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 
 // METADATA
 pub struct PNGMetadata {
     pub bit_depth: u8,
     pub width: usize,
     pub height: usize,
-    pub color_type: u8,
+    pub color_type: ColorType,
+    pub interlace_method: u8,
     pub filesize: usize,
+    pub ancillary: AncillaryChunks, // the standard ancillary chunks in the file
+    pub animation: Option<Animation>, // the frames of an animated PNG, from its acTL, fcTL and fdAT chunks
 }
 
 impl Display for PNGMetadata {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Height: {}, Width: {}, Bit Depth: {}, Color Type: {}, Filesize: {}", self.height, self.width, self.bit_depth, self.color_type, self.filesize)
+        write!(f, "Height: {}, Width: {}, Bit Depth: {}, Color Type: {}, Interlace Method: {}, Filesize: {}", self.height, self.width, self.bit_depth, self.color_type, self.interlace_method, self.filesize)
     }
 }
 
@@ -22,6 +34,7 @@ impl Display for PNGMetadata {
 pub struct PNGChunk {
     pub chunk_type: String,
     pub chunk_data: Vec<u8>,
+    pub offset: usize, // byte offset of the chunk length in the file
 }
 
 impl Display for PNGChunk {
@@ -30,298 +43,1092 @@ impl Display for PNGChunk {
     }
 }
 
-trait ImageData {
-    fn from_png_stream(data: &Vec<u8>, width: usize) -> Self;
+// PNG Parser
+pub struct PNGParser {
+    pub token_tree: Token,
+    pub metadata: PNGMetadata,
+    pub image_data: ImageData,
 }
 
-// RAW IMAGE PIXELS
-pub struct RGBImageData {
-    pub data: Vec<Vec<[u8; 3]>>
-}
+impl PNGParser {
+    pub fn new(data: Vec<u8>) -> Result<Self, DecodeError> {
+        // chunk crc mismatches are shown in the tokens
+        Self::with_strictness(data, false)
+    }
+
+    pub fn new_strict(data: Vec<u8>) -> Result<Self, DecodeError> {
+        // rejects chunks whose crc doesn't match
+        Self::with_strictness(data, true)
+    }
+
+    pub fn tokenise(data: &[u8]) -> Token {
+        // tokens for as much of the file as could be decoded, followed by an error token if it is damaged
+        let mut tokens = TokenCollector::new();
+        let _ = Self::tokenise_into(data, &mut tokens);
+        tokens.into_tokens().pop().unwrap()
+    }
 
-impl ImageData for RGBImageData {
-    fn from_png_stream(data: &Vec<u8>, width: usize) -> Self {
-        let mut image_data = Vec::new();
+    /// Sends the same tokens as [`PNGParser::tokenise`] to a sink as they are decoded, and returns the decoded image.
+    pub fn tokenise_into(data: &[u8], tokens: &mut dyn TokenSink) -> Result<(PNGMetadata, ImageData), DecodeError> {
+        let filesize = data.len();
+        decode_group(tokens, Self::png_file_token(filesize), |tokens| {
+            let result = Self::parse_png(data, false, Some(tokens));
+            match &result {
+                Err(error @ DecodeError::Filter { .. }) => {
+                    // filter errors are in the decompressed data, which has no bits in the file
+                    let mut token = error_token(error, &[]);
+                    token.start_bit = filesize*8;
+                    tokens.push(token);
+                },
+                Err(error) => tokens.push(error_token(error, data)),
+                Ok(_) => {},
+            }
+            result
+        })
+    }
 
-        let bits_row_width = width*3;
+    /// Decodes a PNG file to its header metadata and pixels, without making any tokens.
+    ///
+    /// Chunk CRC mismatches are ignored, and errors are the same as [`PNGParser::new`] gives.
+    pub fn decode(data: &[u8]) -> Result<(PNGMetadata, ImageData), DecodeError> {
+        Self::decode_with_strictness(data, false)
+    }
 
-        let mut row: usize = 0;
-        loop {
-            let mut row_pixels = Vec::with_capacity(width);
+    /// Like [`PNGParser::decode`], but rejects chunks whose crc doesn't match.
+    pub fn decode_strict(data: &[u8]) -> Result<(PNGMetadata, ImageData), DecodeError> {
+        Self::decode_with_strictness(data, true)
+    }
 
-            let filter_type = data.get(row * (bits_row_width+1));
-            if filter_type.is_none() { break }
-            if *filter_type.unwrap() != 0 { panic!("Unsupported filter type") }
+    /// The keyword and text of every tEXt, zTXt and iTXt chunk, with compressed text inflated.
+    pub fn text_entries(&self) -> Vec<TextEntry<'_>> {
+        self.metadata.ancillary.text_entries()
+    }
+
+    /// The inflated ICC profile from the iCCP chunk, if there is one.
+    pub fn icc_profile(&self) -> Option<&[u8]> {
+        self.metadata.ancillary.icc_profile.as_ref().and_then(|icc_profile| icc_profile.profile.as_deref().ok())
+    }
 
-            let row_start = ((bits_row_width+1)*row) + 1;
+    /// The frames of an animated PNG composited in turn, with how long to show each one. None for a still image.
+    pub fn frames(&self) -> Option<Frames<'_>> {
+        self.metadata.animation.as_ref().map(Animation::composited)
+    }
+
+    fn with_strictness(data: Vec<u8>, strict: bool) -> Result<Self, DecodeError> {
+        let mut tokens = TokenCollector::new();
+        let (metadata, image_data) = Self::parse_png_tree(&data, strict, &mut tokens)?;
+
+        Ok(Self {
+            token_tree: tokens.into_tokens().pop().unwrap(),
+            metadata,
+            image_data,
+        })
+    }
+
+    fn parse_png_tree(data: &[u8], strict: bool, tokens: &mut dyn TokenSink) -> Result<(PNGMetadata, ImageData), DecodeError> {
+        // adds a png_file token, which keeps the tokens decoded before an error
+        decode_group(tokens, Self::png_file_token(data.len()), |tokens| Self::parse_png(data, strict, Some(tokens)))
+    }
+
+    fn png_file_token(filesize: usize) -> Token {
+        group_token(TokenKind::PngFile, format!("{} bytes", filesize), "PNG file, the signature followed by chunks", 0)
+    }
+
+    fn decode_with_strictness(data: &[u8], strict: bool) -> Result<(PNGMetadata, ImageData), DecodeError> {
+        // the same chunk loop as tokenising, so errors are found in the same order
+        Self::parse_png(data, strict, None)
+    }
+
+    fn chunk_error(byte_offset: usize, reason: String) -> DecodeError {
+        DecodeError::PngChunk { byte_offset, reason }
+    }
+
+    fn palette_len(palette: &Option<(Palette, usize)>) -> Option<usize> {
+        palette.as_ref().map(|(palette, _)| palette.entries.len())
+    }
+
+    fn adam7_pass_tokens(decompressed: &[u8], metadata: &PNGMetadata, start_bit: usize) -> Result<Token, DecodeError> {
+        // a group for each pass of an interlaced image, showing where it is in the decompressed data and each row's filter
+        // these don't cover any input, so are all placed at start_bit
+        let mut passes = group_token(TokenKind::Adam7Passes, "Adam7 passes".to_string(), "The 7 passes of the interlaced image in the decompressed data", start_bit);
+
+        let mut pass_start = 0;
+        for (pass, pass_len) in adam7_pass_lens(metadata.width, metadata.height, metadata.color_type, metadata.bit_depth)? {
+            let mut pass_token = group_token(
+                TokenKind::Adam7Pass(pass.number),
+                format!("pass {}: {}x{}", pass.number, pass.width, pass.height),
+                &format!("Adam7 pass {}, decompressed bytes {} to {}", pass.number, pass_start, pass_start+pass_len),
+                start_bit
+            );
 
-            for col in 0..width {
-                let pixel_start = row_start+(col*3);
-                row_pixels.push([data[pixel_start], data[pixel_start+1], data[pixel_start+2]])
+            if pass_len > 0 {
+                let row_len = pass_len / pass.height;
+                for row in 0..pass.height {
+                    // truncated image data is reported when the pixels are decoded
+                    let Some(&filter_type) = decompressed.get(pass_start + row*row_len) else { break };
+                    pass_token.push_child(
+                        Token {
+                            bits: vec![],
+                            using_bytes: false,
+                            start_bit,
+                            bit_length: 0,
+                            children: vec![],
+                            data: format!("row {}: {}", pass.y_start + row*pass.y_step, filter_name(filter_type)),
+                            kind: TokenKind::Scanline { row: pass.y_start + row*pass.y_step, filter_type },
+                            description: "Row of the full image and the filter type used for it in this pass".to_string()
+                        }
+                    );
+                }
             }
-            image_data.push(row_pixels);
-            row += 1;
+
+            passes.push_child(pass_token);
+            pass_start += pass_len;
         }
-        Self {
-            data: image_data
+        Ok(passes)
+    }
+
+    fn chunk_header_tokens(chunk_length_bytes: Vec<u8>, chunk_type: &str, chunk_offset: usize) -> Vec<Token> {
+        let chunk_length = bytes_vec_to_single(&chunk_length_bytes);
+        vec![
+            Token {
+                data: format!("length {}", chunk_length),
+                bits: chunk_length_bytes,
+                using_bytes: true,
+                start_bit: chunk_offset*8,
+                bit_length: 32,
+                children: vec![],
+                kind: TokenKind::ChunkLength(chunk_length),
+                description: "Number of bytes in chunk data".to_string()
+            },
+            Token {
+                bits: chunk_type.chars().map(|c| c as u8).collect(),
+                using_bytes: true,
+                start_bit: (chunk_offset+4)*8,
+                bit_length: 32,
+                children: vec![],
+                data: chunk_type.to_string(),
+                kind: TokenKind::ChunkType(chunk_type.to_string()),
+                description: "Type of chunk".to_string()
+            },
+        ]
+    }
+
+    fn chunk_data_token(chunk_data: &[u8], chunk_offset: usize) -> Token {
+        // the data of a chunk that isn't decoded
+        Token {
+            start_bit: (chunk_offset + 8)*8,
+            bit_length: chunk_data.len()*8,
+            bits: chunk_data.to_vec(),
+            using_bytes: true,
+            children: vec![],
+            data: "chunk data".to_string(),
+            kind: TokenKind::ChunkData,
+            description: "Chunk bytes".to_string()
         }
     }
-}
 
-// PNG Parser
-pub struct PNGParser {
-    pub tokens: Vec<Token>,
-    pub image_data: RGBImageData,
-}
+    fn crc_token(crc_bytes: Vec<u8>, calculated_crc: u32, crc_offset: usize) -> Token {
+        let stored_crc = bytes_vec_to_single(&crc_bytes);
+        let crc_matches = stored_crc == calculated_crc;
+        Token {
+            bits: crc_bytes,
+            using_bytes: true,
+            start_bit: crc_offset*8,
+            bit_length: 32,
+            children: vec![],
+            data: format!(
+                "expected {:#010x}, stored {:#010x}{}",
+                calculated_crc, stored_crc, if crc_matches {""} else {" MISMATCH"}
+            ),
+            kind: TokenKind::Crc32 { stored: stored_crc, calculated: calculated_crc },
+            description: if crc_matches {
+                "crc-32 check on chunk type and chunk data".to_string()
+            } else {
+                "crc-32 check on chunk type and chunk data, stored value doesn't match the chunk".to_string()
+            }
+        }
+    }
 
-impl PNGParser {
-    pub fn new(data: Vec<u8>) -> Self {
-        let (tokens, image_data) = Self::parse_png(data);
+    fn ihdr_tokens(chunk_data: &[u8], data_offset: usize) -> Vec<Token> {
+        // IHDR fields are shown instead of the chunk data
+        let start_bit = data_offset*8;
+        let width_bytes = chunk_data[0..4].to_vec();
+        let height_bytes = chunk_data[4..8].to_vec();
+        let width = bytes_vec_to_single(&width_bytes);
+        let height = bytes_vec_to_single(&height_bytes);
 
-        Self {
-            image_data,
-            tokens
+        vec![
+            Token {
+                data: format!("{}", width),
+                bits: width_bytes,
+                using_bytes: true,
+                start_bit,
+                bit_length: 32,
+                children: vec![],
+                kind: TokenKind::Width(width),
+                description: "Image width".to_string()
+            },
+            Token {
+                data: format!("{}", height),
+                bits: height_bytes,
+                using_bytes: true,
+                start_bit: start_bit + 32,
+                bit_length: 32,
+                children: vec![],
+                kind: TokenKind::Height(height),
+                description: "Image height".to_string()
+            },
+            Token {
+                bits: vec![chunk_data[8]],
+                using_bytes: true,
+                start_bit: start_bit + 64,
+                bit_length: 8,
+                children: vec![],
+                data: format!("{}", chunk_data[8]),
+                kind: TokenKind::BitDepth(chunk_data[8]),
+                description: "Image bit depth".to_string()
+            },
+            Token {
+                bits: vec![chunk_data[9]],
+                using_bytes: true,
+                start_bit: start_bit + 72,
+                bit_length: 8,
+                children: vec![],
+                data: format!("{}", chunk_data[9]),
+                kind: TokenKind::ColorType(chunk_data[9]),
+                description: "PNG image color type".to_string()
+            },
+            Token {
+                bits: vec![chunk_data[10]],
+                using_bytes: true,
+                start_bit: start_bit + 80,
+                bit_length: 8,
+                children: vec![],
+                data: format!("{}", chunk_data[10]),
+                kind: TokenKind::CompressionMethod(chunk_data[10]),
+                description: "PNG Compression Method".to_string()
+            },
+            Token {
+                bits: vec![chunk_data[11]],
+                using_bytes: true,
+                start_bit: start_bit + 88,
+                bit_length: 8,
+                children: vec![],
+                data: format!("{}", chunk_data[11]),
+                kind: TokenKind::FilterMethod(chunk_data[11]),
+                description: "PNG Filter Method".to_string()
+            },
+            Token {
+                bits: vec![chunk_data[12]],
+                using_bytes: true,
+                start_bit: start_bit + 96,
+                bit_length: 8,
+                children: vec![],
+                data: format!("{}", chunk_data[12]),
+                kind: TokenKind::InterlaceMethod(chunk_data[12]),
+                description: "PNG Interlace Method, either 'no interlace' or 'Adam7'".to_string()
+            },
+        ]
+    }
+
+    fn parse_ihdr(chunk_data: &[u8], chunk_offset: usize, filesize: usize) -> Result<PNGMetadata, DecodeError> {
+        // IHDR data starts after the chunk length and type
+        let ihdr_data_offset = chunk_offset + 8;
+
+        // width and height are 1 to 2^31-1, PNG specification 11.2.2
+        let width = bytes_vec_to_single(&chunk_data[0..4].to_vec());
+        let height = bytes_vec_to_single(&chunk_data[4..8].to_vec());
+        for (value, name, offset) in [(width, "width", 0), (height, "height", 4)] {
+            if !(1..=0x7fff_ffff).contains(&value) {
+                return Err(Self::chunk_error(ihdr_data_offset+offset, format!("Image {} {} is not in the range 1 to 2^31-1", name, value)))
+            }
+        }
+
+        let color_type = ColorType::from_byte(chunk_data[9])
+            .ok_or(Self::chunk_error(ihdr_data_offset+9, format!("Invalid colour type {}", chunk_data[9])))?;
+
+        if !color_type.allowed_bit_depths().contains(&chunk_data[8]) {
+            return Err(Self::chunk_error(
+                ihdr_data_offset+8,
+                format!("Bit depth {} is not allowed for {} images", chunk_data[8], color_type)
+            ))
+        }
+
+        if chunk_data[10] != 0 {
+            return Err(Self::chunk_error(ihdr_data_offset+10, format!("Unknown compression method {}", chunk_data[10])))
+        }
+
+        if chunk_data[11] != 0 {
+            return Err(Self::chunk_error(ihdr_data_offset+11, format!("Unknown filter method {}", chunk_data[11])))
         }
+
+        if chunk_data[12] > 1 {
+            return Err(Self::chunk_error(ihdr_data_offset+12, format!("Invalid interlace method {}", chunk_data[12])))
+        }
+
+        Ok(PNGMetadata {
+            bit_depth: chunk_data[8],
+            width: width as usize,
+            height: height as usize,
+            color_type,
+            interlace_method: chunk_data[12],
+            filesize,
+            ancillary: AncillaryChunks::default(),
+            animation: None,
+        })
     }
 
-    fn finish_read_chunk(mut_data: &mut Vec<u8>, chunk_length: &u32) {
-        // remove parsed bytes from data, and deallocate vec to free up memory
-        // TODO: benchmark if this improves performance or memory usage
-        mut_data.drain(0..(12+chunk_length) as usize);
-        mut_data.shrink_to_fit();
+    fn idat_file_bit(combined_bit: usize, idat_chunks: &[(usize, usize)]) -> usize {
+        // position in the file of a bit in the combined IDAT data, or the combined fdAT data of a frame
+        // idat_chunks has the offset of each IDAT chunk's data in the file and in the combined data
+        // the chunk is found with a binary search, as every token is moved and there can be thousands of chunks
+        // past the end of the data, this counts on from the end of the last chunk
+        let combined_byte = combined_bit / 8;
+        let index = idat_chunks.partition_point(|&(_, combined_offset)| combined_offset <= combined_byte).max(1) - 1;
+        let (file_offset, combined_offset) = idat_chunks[index];
+        combined_bit + (file_offset - combined_offset)*8
     }
 
-    fn parse_png(data: Vec<u8>) -> (Vec<Token>, RGBImageData) {
-        let mut tokens: Vec<Token> = Vec::new();
+    fn move_idat_tokens(token: &mut Token, idat_chunks: &[(usize, usize)]) {
+        // a token split over two chunks covers the chunk end and start between its bits
+        let start_bit = Self::idat_file_bit(token.start_bit, idat_chunks);
+        if token.bit_length > 0 {
+            token.bit_length = Self::idat_file_bit(token.start_bit + token.bit_length - 1, idat_chunks) + 1 - start_bit;
+        }
+        token.start_bit = start_bit;
+
+        for child in &mut token.children {
+            Self::move_idat_tokens(child, idat_chunks);
+        }
+    }
 
-        let _filesize = (&data).len();
-        let mut mut_data = data;
-        let header = mut_data.drain(0..8);
+    fn parse_idat(
+        idat_combined: &[u8], idat_chunks: &[(usize, usize)], mut idat_group: Token, tokens: &mut dyn TokenSink, make_tokens: bool
+    ) -> Result<Vec<u8>, DecodeError> {
+        // adds the group of IDAT chunks followed by the zlib stream, tokens and errors are moved to their positions in the file
+        if !make_tokens {
+            return decode_zlib(idat_combined).map_err(|error| Self::idat_error(error, idat_chunks))
+        }
+
+        let idat_chunk_tokens = std::mem::take(&mut idat_group.children);
+        let decompressed = decode_group(tokens, idat_group, |tokens| {
+            for token in idat_chunk_tokens {
+                tokens.push(token);
+            }
+            let mut zlib_tokens = MovedTokens {
+                sink: tokens,
+                move_token: |token: &mut Token| Self::move_idat_tokens(token, idat_chunks)
+            };
+            parse_zlib_into(idat_combined, &mut zlib_tokens)
+        });
+
+        decompressed.map_err(|error| Self::idat_error(error, idat_chunks))
+    }
+
+    fn idat_error(error: DecodeError, idat_chunks: &[(usize, usize)]) -> DecodeError {
+        // moves an error in the combined IDAT or fdAT data to its position in the file
+        let combined_bit = error.bit_offset();
+        let file_bit = Self::idat_file_bit(combined_bit, idat_chunks);
+        error.shifted(file_bit/8 - combined_bit/8)
+    }
+
+    fn image_data(
+        decompressed: &[u8], metadata: &PNGMetadata, width: usize, height: usize, palette: &Option<(Palette, usize)>, transparency: &Option<PNGChunk>
+    ) -> Result<ImageData, DecodeError> {
+        // unfilters the decompressed data, then applies the palette or transparent colour
+        // width and height are the image's, or an animation frame's
+        let color_type = metadata.color_type;
+        let interlaced = metadata.interlace_method == 1;
+        let image_data = ImageData::from_png_stream(decompressed, width, height, color_type, metadata.bit_depth, interlaced)?;
+
+        if color_type == ColorType::Indexed {
+            let (palette, palette_offset) = palette
+                .as_ref()
+                .ok_or(Self::chunk_error(metadata.filesize, "No PLTE chunk for indexed-colour image".to_string()))?;
+            let mut palette = palette.clone();
+            if let Some(transparency) = transparency {
+                // alpha values past the end of the palette are ignored
+                palette.alpha = transparency.chunk_data.iter().take(palette.entries.len()).copied().collect();
+            }
+            image_data
+                .expand_palette(&palette)
+                .map_err(|reason| Self::chunk_error(*palette_offset, reason))
+        } else if let Some(key) = transparency.as_ref().and_then(|transparency| TransparencyKey::from_chunk(&transparency.chunk_data, color_type).ok()) {
+            Ok(image_data.apply_transparency_key(&key))
+        } else {
+            Ok(image_data)
+        }
+    }
+
+    fn animation(
+        reader: AnimationReader, metadata: &PNGMetadata, palette: &Option<(Palette, usize)>, transparency: &Option<PNGChunk>, image_data: &ImageData
+    ) -> Result<Animation, DecodeError> {
+        // the IDAT image is the first frame if it had an fcTL, the fdAT frames are decoded the same way at their own size
+        let (control, first_frame, frame_data) = reader.finish()?;
+        let mut frames = Vec::new();
+        if let Some(control) = first_frame {
+            frames.push(AnimationFrame { control, image: image_data.clone() });
+        }
+        for (control, decompressed) in frame_data {
+            let image = Self::image_data(&decompressed, metadata, control.width, control.height, palette, transparency)?;
+            frames.push(AnimationFrame { control, image });
+        }
+
+        Ok(Animation {
+            control,
+            width: metadata.width,
+            height: metadata.height,
+            frames,
+            default_image_is_first_frame: first_frame.is_some(),
+        })
+    }
+
+    fn parse_png(data: &[u8], strict: bool, tokens: Option<&mut dyn TokenSink>) -> Result<(PNGMetadata, ImageData), DecodeError> {
+        // tokens are added as they are decoded so they're kept if there is an error
+        // without a sink the tokens are dropped, and the image data is inflated without making any
+        let make_tokens = tokens.is_some();
+        let mut no_tokens = NoTokens;
+        let tokens = tokens.unwrap_or(&mut no_tokens);
+        if !data.starts_with(&SIGNATURE) {
+            return Err(Self::chunk_error(0, "Missing PNG signature".to_string()))
+        }
+
+        let filesize = data.len();
 
         tokens.push(
             Token {
-                bits: header.into_iter().collect(),
+                bits: SIGNATURE.to_vec(),
                 using_bytes: true,
-                nest_level: 2,
+                start_bit: 0,
+                bit_length: 64,
+                children: vec![],
                 data: "png header".to_string(),
-                token_type: "header".to_string(),
+                kind: TokenKind::Signature,
                 description: "All PNGs contain these bytes".to_string()
             }
         );
 
         let mut idat_combined: Vec<u8> = Vec::new();
-        let mut ihdr: Option<PNGChunk> = None;
-        let mut parsing_idat = false;
+        let mut idat_chunks: Vec<(usize, usize)> = Vec::new();
+        let mut metadata: Option<PNGMetadata> = None;
+        let mut palette: Option<(Palette, usize)> = None;
+        let mut transparency: Option<PNGChunk> = None;
+        let mut ancillary = AncillaryChunks::default();
+        let mut idat_group: Option<Token> = None;
         let mut decompressed = Vec::new();
+        let mut animation: Option<AnimationReader> = None;
+        let mut fdat_combined: Vec<u8> = Vec::new();
+        let mut fdat_chunks: Vec<(usize, usize)> = Vec::new();
+        let mut fdat_group: Option<Token> = None;
 
-        while mut_data.len() > 0 {
+        for raw_chunk in ChunkReader::new(data, SIGNATURE.len()) {
+            let raw_chunk = raw_chunk?;
+            if strict {
+                raw_chunk.check_crc()?;
+            }
 
-            // first 4 bytes are chunk length
-            let chunk_length_bytes = mut_data[0..4].to_vec();
-            let chunk_length = bytes_vec_to_single(&chunk_length_bytes);
+            let chunk_offset = raw_chunk.offset;
+            let chunk_length = raw_chunk.length;
+            let chunk_type = raw_chunk.chunk_type.clone();
+            let chunk_data = raw_chunk.data();
+            let calculated_crc = raw_chunk.calculated_crc();
+            let crc_offset = raw_chunk.crc_offset();
 
-            // next 4 bytes are chunk type
-            let chunk_type_bytes = &mut_data[4..8];
-            let chunk_type: String = chunk_type_bytes.iter().map(|x| *x as char).collect();
+            let chunk = group_token(TokenKind::Chunk { chunk_type: chunk_type.clone(), length: chunk_length }, chunk_type.clone(), "PNG chunk, its length, type, data and crc-32", chunk_offset*8);
+
+            let is_frame_data = chunk_type == "fdAT" && animation.is_some();
+            if let Some(frame_group) = fdat_group.take_if(|_| !is_frame_data) {
+                // ended fdAT chunks
+                let frame = Self::parse_idat(&fdat_combined, &fdat_chunks, frame_group, tokens, make_tokens)?;
+                animation.as_mut().unwrap().end_frame(frame);
+                fdat_combined.clear();
+                fdat_chunks.clear();
+            }
 
-            // non essential chunks are skipped
-            if chunk_type.chars().nth(0).expect("No chunk type").is_lowercase() {
-                Self::finish_read_chunk(&mut mut_data, &chunk_length);
+            if chunk_type == "IDAT" {
+                if let Some(reader) = animation.as_mut() {
+                    reader.idat();
+                }
+                // the data is shown once all IDAT chunks are combined
+                let idat = idat_group.get_or_insert_with(|| group_token(
+                    TokenKind::IdatChunks, "IDAT chunks".to_string(), "Image data chunks, followed by the data from all of them combined", chunk_offset*8
+                ));
+                let mut chunk = chunk;
+                chunk.children.extend(Self::chunk_header_tokens(raw_chunk.length_bytes().to_vec(), &chunk_type, chunk_offset));
+                chunk.push_child(Self::crc_token(raw_chunk.crc_bytes().to_vec(), calculated_crc, crc_offset));
+                idat.push_child(chunk);
+
+                idat_chunks.push((raw_chunk.data_offset(), idat_combined.len()));
+                idat_combined.extend_from_slice(chunk_data);
                 continue;
+            } else if let Some(idat) = idat_group.take() {
+                // ended idat chunks, a later IDAT chunk is an error rather than more data, so it's looked for before decoding them
+                let later_idat = ChunkReader::new(data, chunk_offset)
+                    .map_while(Result::ok)
+                    .take_while(|later_chunk| later_chunk.chunk_type != "IEND")
+                    .find(|later_chunk| later_chunk.chunk_type == "IDAT");
+                if let Some(later_idat) = later_idat {
+                    return Err(Self::chunk_error(later_idat.offset, "IDAT chunks must be consecutive".to_string()))
+                }
+                decompressed = Self::parse_idat(&idat_combined, &idat_chunks, idat, tokens, make_tokens)?;
             }
 
-            // next *chunk length* bytes are chunk data
-            let data_chunk_end = 8+(chunk_length as usize);
-            let chunk_data = mut_data[8..data_chunk_end].iter().cloned().collect::<Vec<u8>>();
+            if let (true, Some(reader)) = (is_frame_data, animation.as_mut()) {
+                // fdAT chunks are grouped by frame like IDAT, each showing its sequence number, then the data after the sequence numbers combined
+                let frame = reader.frame_data(&raw_chunk)?;
+                let frame_group = fdat_group.get_or_insert_with(|| group_token(
+                    TokenKind::FrameDataChunks(frame), format!("frame {} fdAT chunks", frame),
+                    "Frame data chunks, followed by the data from all of them combined", chunk_offset*8
+                ));
+                let mut chunk = chunk;
+                chunk.children.extend(Self::chunk_header_tokens(raw_chunk.length_bytes().to_vec(), &chunk_type, chunk_offset));
+                chunk.children.push(sequence_number_token(chunk_data, raw_chunk.data_offset()));
+                chunk.push_child(Self::crc_token(raw_chunk.crc_bytes().to_vec(), calculated_crc, crc_offset));
+                frame_group.push_child(chunk);
 
-            if chunk_type == "IHDR".to_string() {
-                ihdr = Some(PNGChunk {
-                    chunk_type: chunk_type.clone(),
-                    chunk_data: chunk_data.clone()
-                });
-            } else {
-                if chunk_type == "IDAT".to_string() {
-                    if !parsing_idat {
-                        // first IDAT chunk
+                fdat_chunks.push((raw_chunk.data_offset() + 4, fdat_combined.len()));
+                fdat_combined.extend_from_slice(&chunk_data[4..]);
+                continue;
+            }
+
+            decode_group(tokens, chunk, |tokens| {
+                for token in Self::chunk_header_tokens(raw_chunk.length_bytes().to_vec(), &chunk_type, chunk_offset) {
+                    tokens.push(token);
+                }
+
+                if chunk_type == "IHDR" {
+                    if chunk_offset != SIGNATURE.len() {
+                        return Err(Self::chunk_error(chunk_offset, "IHDR must be the first chunk".to_string()))
+                    }
+                    if chunk_data.len() != 13 {
+                        return Err(Self::chunk_error(chunk_offset, format!("IHDR chunk length is {}, should be 13", chunk_data.len())))
+                    }
+                    for token in Self::ihdr_tokens(chunk_data, chunk_offset+8) {
+                        tokens.push(token);
+                    }
+                    metadata = Some(Self::parse_ihdr(chunk_data, chunk_offset, filesize)?);
+                } else if chunk_type == "PLTE" {
+                    // each palette entry is shown instead of the chunk data
+                    let parsed_palette = Palette::from_chunk(chunk_data)
+                        .map_err(|reason| Self::chunk_error(chunk_offset+8, reason))?;
+                    for (index, entry) in parsed_palette.entries.iter().enumerate() {
                         tokens.push(
                             Token {
-                                bits: vec![],
-                                using_bytes: false,
-                                nest_level: 2,
-                                data: "IDAT start".to_string(),
-                                token_type: "idat_start".to_string(),
-                                description: "Start of image data chunks, following data is all IDAT chunks combined".to_string()
+                                bits: entry.to_vec(),
+                                using_bytes: true,
+                                start_bit: (chunk_offset + 8 + index*3)*8,
+                                bit_length: 24,
+                                children: vec![],
+                                data: format!("{}: rgb({}, {}, {})", index, entry[0], entry[1], entry[2]),
+                                kind: TokenKind::PaletteEntry { index, rgb: *entry },
+                                description: "Palette entry index and its red, green and blue values".to_string()
                             }
                         );
                     }
-
-                    parsing_idat = true;
-
-                    idat_combined.extend(chunk_data);
-                    Self::finish_read_chunk(&mut mut_data, &chunk_length);
-                    continue;
-                } else if parsing_idat {
-                    // ended idat chunks
-                    let (zlib_tokens, decompressed_d) = new_parse_zlib(&idat_combined);
-                    decompressed = decompressed_d;
-                    tokens.extend(zlib_tokens);
-
-                    tokens.push(
-                        Token {
-                            bits: vec![],
-                            using_bytes: false,
-                            nest_level: 2,
-                            data: "IDAT end".to_string(),
-                            token_type: "idat_end".to_string(),
-                            description: "End of combined IDAT chunks".to_string()
+                    palette = Some((parsed_palette, chunk_offset));
+                } else if chunk_type == "tRNS" {
+                    // each palette alpha value is shown, or the transparent colour for other colour types
+                    // tRNS that doesn't fit the colour type is ignored, like a damaged ancillary chunk
+                    let key = match metadata.as_ref().map(|metadata| metadata.color_type) {
+                        Some(ColorType::Indexed) => Ok(None),
+                        Some(color_type) => TransparencyKey::from_chunk(chunk_data, color_type).map(Some),
+                        None => Err("tRNS chunk must come after IHDR".to_string()),
+                    };
+                    if let Err(reason) = &key {
+                        tokens.push(Self::chunk_data_token(chunk_data, chunk_offset));
+                        tokens.push(ignored_error_token(&Self::chunk_error(chunk_offset, reason.clone())));
+                    } else if matches!(key, Ok(None)) {
+                        for (index, alpha) in chunk_data.iter().enumerate() {
+                            tokens.push(
+                                Token {
+                                    bits: vec![*alpha],
+                                    using_bytes: true,
+                                    start_bit: (chunk_offset + 8 + index)*8,
+                                    bit_length: 8,
+                                    children: vec![],
+                                    data: format!("{}: alpha {}", index, alpha),
+                                    kind: TokenKind::PaletteAlpha { index, alpha: *alpha },
+                                    description: "Alpha value for the palette entry at this index".to_string()
+                                }
+                            );
                         }
-                    );
+                    } else {
+                        let samples: Vec<u16> = chunk_data.chunks(2).map(|sample| bytes_vec_to_single(&sample.to_vec()) as u16).collect();
+                        tokens.push(
+                            Token {
+                                bits: chunk_data.to_vec(),
+                                using_bytes: true,
+                                start_bit: (chunk_offset + 8)*8,
+                                bit_length: chunk_data.len()*8,
+                                children: vec![],
+                                data: format!("{:?}", samples),
+                                kind: TokenKind::TransparentColour(samples),
+                                description: "Pixels of this colour are fully transparent".to_string()
+                            }
+                        );
+                    }
+                    if key.is_ok() {
+                        transparency = Some(PNGChunk {
+                            chunk_type: chunk_type.clone(),
+                            chunk_data: chunk_data.to_vec(),
+                            offset: chunk_offset
+                        });
+                    }
+                } else if chunk_type == "acTL" {
+                    if animation.is_some() {
+                        return Err(Self::chunk_error(chunk_offset, "Only one acTL chunk is allowed".to_string()))
+                    }
+                    let reader = AnimationReader::new(&raw_chunk, !idat_chunks.is_empty())?;
+                    for token in reader.control().tokens(chunk_data, raw_chunk.data_offset()) {
+                        tokens.push(token);
+                    }
+                    animation = Some(reader);
+                } else if let ("fcTL", Some(reader)) = (chunk_type.as_str(), animation.as_mut()) {
+                    let control = reader.frame_control(&raw_chunk, metadata.as_ref())?;
+                    for token in control.tokens(chunk_data, raw_chunk.data_offset()) {
+                        tokens.push(token);
+                    }
+                } else {
+                    // the fields of standard ancillary chunks are shown instead of the chunk data
+                    // a damaged one is ignored like an unknown chunk, with its error after the data
+                    let parsed = AncillaryChunk::parse(&raw_chunk, metadata.as_ref(), Self::palette_len(&palette), make_tokens);
+                    if let Ok(Some((ancillary_chunk, zlib_stream))) = parsed {
+                        if make_tokens {
+                            for token in ancillary_chunk.tokens(chunk_data, raw_chunk.data_offset(), zlib_stream) {
+                                tokens.push(token);
+                            }
+                        }
+                        ancillary.add(ancillary_chunk);
+                    } else {
+                        tokens.push(Self::chunk_data_token(chunk_data, chunk_offset));
+                        if let Err(error) = parsed {
+                            tokens.push(ignored_error_token(&error));
+                        }
+                    }
                 }
+
+                tokens.push(Self::crc_token(raw_chunk.crc_bytes().to_vec(), calculated_crc, crc_offset));
+                Ok(())
+            })?;
+
+            if chunk_type == "IEND" {
+                // anything after the end of the image is ignored
+                break
             }
+        }
 
-            // next 4 bytes are crc-32 check
-            let crc_bytes = &mut_data[data_chunk_end..data_chunk_end+4];
-            // let crc = bytes_vec_to_single(&crc_bytes.iter().cloned().collect());
-
-            tokens.push(
-                Token {
-                    bits: chunk_length_bytes,
-                    using_bytes: true,
-                    nest_level: 2,
-                    data: format!("length {}", chunk_length),
-                    token_type: "chunk_length".to_string(),
-                    description: "Number of bytes in chunk data".to_string()
-                }
-            );
+        let mut metadata = metadata.ok_or(Self::chunk_error(8, "No IHDR chunk".to_string()))?;
+        metadata.ancillary = ancillary;
 
-            tokens.push(
-                Token {
-                    bits: chunk_type_bytes.to_vec(),
-                    using_bytes: true,
-                    nest_level: 2,
-                    data: chunk_type.clone(),
-                    token_type: "chunk_type".to_string(),
-                    description: "Type of chunk".to_string()
-                }
-            );
+        if idat_combined.is_empty() {
+            return Err(Self::chunk_error(filesize, "No IDAT chunks found".to_string()))
+        }
 
-            if chunk_type != "IHDR".to_string() {
-                // dont include chunk data for IHDR
-                tokens.push(
-                    Token {
-                        bits: chunk_data,
-                        using_bytes: true,
-                        nest_level: 2,
-                        data: "chunk data".to_string(),
-                        token_type: "chunk_data".to_string(),
-                        description: "Chunk bytes".to_string()
-                    }
-                );
-            }
+        if let Some(idat) = idat_group.take() {
+            // file ended without a chunk after the IDAT chunks
+            decompressed = Self::parse_idat(&idat_combined, &idat_chunks, idat, tokens, make_tokens)?;
+        }
 
-            tokens.push(
-                Token {
-                    bits: crc_bytes.to_vec(),
-                    using_bytes: true,
-                    nest_level: 2,
-                    data: "crc-32".to_string(),
-                    token_type: "crc_32".to_string(),
-                    description: "crc-32 check on chunk type and chunk data".to_string()
-                }
-            );
+        if let (Some(frame_group), Some(reader)) = (fdat_group.take(), animation.as_mut()) {
+            // file ended without a chunk after the fdAT chunks
+            reader.end_frame(Self::parse_idat(&fdat_combined, &fdat_chunks, frame_group, tokens, make_tokens)?);
+        }
 
-            Self::finish_read_chunk(&mut mut_data, &chunk_length);
+        if make_tokens && metadata.interlace_method == 1 {
+            tokens.push(Self::adam7_pass_tokens(&decompressed, &metadata, filesize*8)?);
         }
 
-        let ihdr = ihdr.expect("No IHDR Chunk");
+        let image_data = Self::image_data(&decompressed, &metadata, metadata.width, metadata.height, &palette, &transparency)?;
+        metadata.animation = animation
+            .map(|reader| Self::animation(reader, &metadata, &palette, &transparency, &image_data))
+            .transpose()?;
+        Ok((metadata, image_data))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{chunk, fctl, fdat, ihdr, indexed_png, interlaced_png, png, rgb_png, rgb_png_two_idats, stored_zlib, RGB_PIXELS, RGB_ZLIB};
+    use crate::ancillary::TextChunk;
+    use crate::sink::{JsonWriter, TokenStats};
+
+    #[test]
+    fn test_parse_rgb_png() {
+        let parser = PNGParser::new(rgb_png()).unwrap();
+
+        assert_eq!(parser.metadata.width, 2);
+        assert_eq!(parser.metadata.height, 2);
+        assert_eq!(parser.metadata.color_type, ColorType::Truecolor);
+        assert_eq!(parser.image_data.data, RGB_PIXELS);
+    }
+
+    #[test]
+    fn test_token_offsets() {
+        // 2x2 truecolour image, with the image data split over two IDAT chunks
+        let data = rgb_png_two_idats();
+
+        let parser = PNGParser::new(data.clone()).unwrap();
+        assert_eq!(parser.image_data.data, RGB_PIXELS);
+
+        // the second IDAT chunk starts at byte 50, its header is shown at that offset
+        let second_idat = parser.token_tree.flatten().into_iter().find(|token| token.data == "length 17").unwrap();
+        assert_eq!((second_idat.start_bit, second_idat.bit_length), (400, 32));
+        let ihdr_width = parser.token_tree.flatten().into_iter().find(|token| matches!(token.kind, TokenKind::Width(_))).unwrap();
+        assert_eq!((ihdr_width.start_bit, ihdr_width.bit_length), (128, 32));
 
-        if idat_combined.len() == 0 {
-            panic!("No IDAT chunks found")
+        // every bit of the file is covered by a token, and tokens stay inside the file
+        let mut covered = vec![false; data.len()*8];
+        for token in parser.token_tree.flatten() {
+            assert!(token.start_bit + token.bit_length <= data.len()*8, "{:?}", token);
+            covered[token.start_bit..token.start_bit+token.bit_length].fill(true);
         }
+        assert!(covered.iter().all(|&bit| bit));
+    }
 
-        let width_bytes = ihdr.chunk_data[0..4].to_vec();
-        let width = bytes_vec_to_single(&width_bytes) as usize;
+    #[test]
+    fn test_parse_indexed_png() {
+        let parser = PNGParser::new(indexed_png()).unwrap();
 
-        let height_bytes = ihdr.chunk_data[4..8].to_vec();
-        let height = bytes_vec_to_single(&height_bytes) as usize;
+        assert_eq!(parser.image_data.color_type, ColorType::TruecolorAlpha);
+        assert_eq!(parser.image_data.data, vec![255, 0, 0, 0, 0, 255, 0, 255, 0, 0, 255, 255]);
 
-        // header data placed 3 token after the main PNG header
-        tokens.insert(3, 
-            Token {
-                bits: width_bytes,
-                using_bytes: true,
-                nest_level: 1,
-                data: format!("{}", width),
-                token_type: "width".to_string(),
-                description: "Image width".to_string()
-            }
-        );
+        let palette_tokens: Vec<&String> = parser.token_tree.flatten().into_iter().filter(|token| matches!(token.kind, TokenKind::PaletteEntry { .. })).map(|token| &token.data).collect();
+        assert_eq!(palette_tokens, vec!["0: rgb(255, 0, 0)", "1: rgb(0, 255, 0)", "2: rgb(0, 0, 255)"]);
+    }
 
-        tokens.insert(4, 
-            Token {
-                bits: height_bytes,
-                using_bytes: true,
-                nest_level: 1,
-                data: format!("{}", height),
-                token_type: "height".to_string(),
-                description: "Image height".to_string()
-            }
-        );
+    #[test]
+    fn test_invalid_transparency() {
+        // indexed image, with alpha for more entries than the palette has
+        let indexed = indexed_png();
+        let long_alpha = [&indexed[..54], &chunk("tRNS", &[0, 128, 255, 10, 20]), &indexed[67..]].concat();
+        let (_, image_data) = PNGParser::decode(&long_alpha).unwrap();
+        assert_eq!(image_data.data, vec![255, 0, 0, 0, 0, 255, 0, 128, 0, 0, 255, 255]);
 
-        tokens.insert(5, 
-            Token {
-                bits: vec![ihdr.chunk_data[8]],
-                using_bytes: true,
-                nest_level: 1,
-                data: format!("{}", ihdr.chunk_data[8]),
-                token_type: "bit_depth".to_string(),
-                description: "Image bit depth".to_string()
-            }
+        // truecolour image, with a tRNS of the wrong length, which is ignored
+        let wrong_length = png(&[&ihdr(2, 2, 8, 2, 0), &chunk("tRNS", &[0, 10]), &chunk("IDAT", &RGB_ZLIB)]);
+        let parser = PNGParser::new(wrong_length.clone()).unwrap();
+        assert_eq!(parser.image_data.color_type, ColorType::Truecolor);
+        let error = DecodeError::PngChunk { byte_offset: 33, reason: "tRNS chunk of length 2 is invalid for truecolour images".to_string() };
+        let trns_kinds: Vec<&TokenKind> = parser.token_tree.children[2].children.iter().map(|token| &token.kind).collect();
+        assert_eq!(trns_kinds[2..4], [&TokenKind::ChunkData, &TokenKind::Error(error)]);
+        assert_eq!(PNGParser::decode(&wrong_length).unwrap().1.color_type, ColorType::Truecolor);
+    }
+
+    #[test]
+    fn test_parse_interlaced_png() {
+        let parser = PNGParser::new(interlaced_png()).unwrap();
+
+        assert_eq!(parser.image_data.data, vec![0, 1, 2, 3, 7, 12, 6, 7, 8]);
+
+        let pass_tokens = parser.token_tree.flatten().into_iter().filter(|token| matches!(token.kind, TokenKind::Adam7Pass(_))).count();
+        assert_eq!(pass_tokens, 7);
+        assert!(parser.token_tree.flatten().into_iter().any(|token| token.data == "row 1: Paeth"));
+    }
+
+    #[test]
+    fn test_crc_mismatch() {
+        // 3x3 interlaced image, with the last byte of the IEND crc changed
+        let mut data = interlaced_png();
+        *data.last_mut().unwrap() = 0;
+
+        let parser = PNGParser::new(data.clone()).unwrap();
+        let crc_tokens: Vec<&String> = parser.token_tree.flatten().into_iter().filter(|token| matches!(token.kind, TokenKind::Crc32 { .. })).map(|token| &token.data).collect();
+        assert_eq!(crc_tokens, vec!["expected 0x0444daf5, stored 0x0444daf5", "expected 0x40237a3a, stored 0x40237a3a", "expected 0xae426082, stored 0xae426000 MISMATCH"]);
+
+        assert_eq!(
+            PNGParser::new_strict(data).err(),
+            Some(DecodeError::PngChunk { byte_offset: 76, reason: "CRC mismatch in IEND chunk, stored 0xae426000, calculated 0xae426082".to_string() })
         );
+    }
 
-        tokens.insert(6, 
-            Token {
-                bits: vec![ihdr.chunk_data[9]],
-                using_bytes: true,
-                nest_level: 1,
-                data: format!("{}", ihdr.chunk_data[9]),
-                token_type: "color_type".to_string(),
-                description: "PNG image color type".to_string()
-            }
+    #[test]
+    fn test_invalid_ihdr() {
+        // 2x2 truecolour image, with the bit depth changed to 4
+        let mut data = rgb_png();
+        data[24] = 4;
+
+        assert_eq!(
+            PNGParser::new(data.clone()).err(),
+            Some(DecodeError::PngChunk { byte_offset: 24, reason: "Bit depth 4 is not allowed for truecolour images".to_string() })
         );
-        tokens.insert(7, 
-            Token {
-                bits: vec![ihdr.chunk_data[10]],
-                using_bytes: true,
-                nest_level: 1,
-                data: format!("{}", ihdr.chunk_data[10]),
-                token_type: "compression_method".to_string(),
-                description: "PNG Compression Method".to_string()
-            }
+
+        // zero width, and a height past 2^31-1
+        let mut zero_width = data.clone();
+        zero_width[16..20].fill(0);
+        assert_eq!(
+            PNGParser::decode(&zero_width).err(),
+            Some(DecodeError::PngChunk { byte_offset: 16, reason: "Image width 0 is not in the range 1 to 2^31-1".to_string() })
         );
-        tokens.insert(8, 
-            Token {
-                bits: vec![ihdr.chunk_data[11]],
-                using_bytes: true,
-                nest_level: 1,
-                data: format!("{}", ihdr.chunk_data[11]),
-                token_type: "filter_method".to_string(),
-                description: "PNG Filter Method".to_string()
-            }
+        let mut tall = data;
+        tall[20] = 128;
+        assert_eq!(
+            PNGParser::decode(&tall).err(),
+            Some(DecodeError::PngChunk { byte_offset: 20, reason: "Image height 2147483650 is not in the range 1 to 2^31-1".to_string() })
         );
-        tokens.insert(9, 
-            Token {
-                bits: vec![ihdr.chunk_data[12]],
-                using_bytes: true,
-                nest_level: 1,
-                data: format!("{}", ihdr.chunk_data[12]),
-                token_type: "interlace_method".to_string(),
-                description: "PNG Interlace Method, either 'no interlace' or 'Adam7'".to_string()
+
+        assert_eq!(PNGParser::new(SIGNATURE[..4].to_vec()).err().unwrap().stage(), "PNG chunk");
+    }
+
+    #[test]
+    fn test_image_too_large() {
+        // the largest image IHDR allows, 16 bit truecolour with alpha, its data doesn't fit in a usize
+        let idat = chunk("IDAT", &stored_zlib(&[0; 16]));
+        let data = png(&[&ihdr(0x7fff_ffff, 0x7fff_ffff, 16, 6, 0), &idat]);
+        let error = "filter error at byte 0: A 2147483647x2147483647 image is too large to decode";
+        assert_eq!(PNGParser::decode(&data).err().unwrap().to_string(), error);
+        assert!(matches!(&PNGParser::tokenise(&data).children.last().unwrap().kind, TokenKind::Error(error) if error.stage() == "filter"));
+
+        // the Adam7 passes are checked before their tokens are made
+        let interlaced = png(&[&ihdr(0x7fff_ffff, 0x7fff_ffff, 16, 6, 1), &idat]);
+        let error = "filter error at byte 0: A 2147483647x2147483647 interlaced image is too large to decode";
+        assert_eq!(PNGParser::decode(&interlaced).err().unwrap().to_string(), error);
+        assert_eq!(PNGParser::new(interlaced.clone()).err().unwrap().to_string(), error);
+        let png_file = PNGParser::tokenise(&interlaced);
+        assert!(matches!(&png_file.children.last().unwrap().kind, TokenKind::Error(error) if error.stage() == "filter"));
+        assert!(!png_file.flatten().iter().any(|token| token.kind == TokenKind::Adam7Passes));
+    }
+
+    #[test]
+    fn test_truncated_chunk() {
+        // 2x2 truecolour image, cut off inside the IDAT chunk
+        let data = rgb_png()[..47].to_vec();
+
+        let error = PNGParser::new(data).err().unwrap();
+        assert_eq!(error, DecodeError::PngChunk { byte_offset: 33, reason: "IDAT chunk is truncated, length is 22 but only 6 bytes are left".to_string() });
+    }
+
+    #[test]
+    fn test_tokenise_damaged_png() {
+        // 2x2 truecolour image, cut off inside the IDAT chunk
+        let data = rgb_png()[..47].to_vec();
+
+        let png_file = PNGParser::tokenise(&data);
+        let token_types: Vec<&str> = png_file.children.iter().map(|token| token.kind.name()).collect();
+        assert_eq!(token_types, vec!["header", "chunk", "error"]);
+        let ihdr_types: Vec<&str> = png_file.children[1].children.iter().map(|token| token.kind.name()).collect();
+        assert_eq!(ihdr_types, vec![
+            "chunk_length", "chunk_type", "width", "height", "bit_depth", "color_type",
+            "compression_method", "filter_method", "interlace_method", "crc_32"
+        ]);
+        // the error covers the truncated chunk
+        assert_eq!(png_file.children.last().unwrap().bits, vec![0, 0, 0, 22, 73, 68, 65, 84, 120, 218, 99, 228, 18, 145]);
+
+        // the deflate stream is damaged, the error is given as a file offset and tokens before it are kept
+        let mut data = rgb_png();
+        // first block header changed to BTYPE 11
+        data[43] = 0x67;
+
+        let error = PNGParser::new(data.clone()).err().unwrap();
+        assert_eq!(error, DecodeError::DeflateBlock { bit_offset: 43*8+1, reason: "BTYPE has reserved value (11)".to_string() });
+
+        let png_file = PNGParser::tokenise(&data);
+        // the IDAT group keeps the chunk and the part of the zlib stream before the error
+        let idat = &png_file.children[2];
+        assert_eq!(idat.kind, TokenKind::IdatChunks);
+        let zlib_stream = idat.children.last().unwrap();
+        assert_eq!(zlib_stream.kind, TokenKind::ZlibStream);
+        assert_eq!(zlib_stream.children.last().unwrap().children.last().unwrap().kind.name(), "bfinal");
+        let error_token = png_file.children.last().unwrap();
+        assert!(matches!(error_token.kind, TokenKind::Error(_)));
+        assert!(!error_token.using_bytes);
+        // remaining 7 bits of the byte, then the rest of the file
+        assert_eq!(error_token.bits.len(), 7 + (data.len()-44)*8);
+    }
+
+    #[test]
+    fn test_chunk_order() {
+        // 2x2 truecolour image, with another IHDR after IEND, which is ignored
+        let trailing_ihdr = [rgb_png(), ihdr(3, 2, 8, 2, 0)].concat();
+        let (metadata, image_data) = PNGParser::decode(&trailing_ihdr).unwrap();
+        assert_eq!((metadata.width, image_data.data), (2, RGB_PIXELS.to_vec()));
+        let parser = PNGParser::new(trailing_ihdr).unwrap();
+        assert_eq!(parser.token_tree.children.last().unwrap().data, "IEND");
+
+        // a second IHDR is rejected
+        let second_ihdr = png(&[&ihdr(2, 2, 8, 2, 0), &ihdr(2, 2, 8, 2, 0), &chunk("IDAT", &RGB_ZLIB)]);
+        let error = DecodeError::PngChunk { byte_offset: 33, reason: "IHDR must be the first chunk".to_string() };
+        assert_eq!(PNGParser::decode(&second_ihdr).err(), Some(error.clone()));
+        assert_eq!(PNGParser::new(second_ihdr).err(), Some(error));
+    }
+
+    #[test]
+    fn test_idat_not_consecutive() {
+        // a zlib stream in each IDAT chunk, and one stream split over two, with a tEXt chunk between them
+        let header = ihdr(1, 1, 8, 0, 0);
+        let text = chunk("tEXt", b"Author\0Jo");
+        let zlib = stored_zlib(&[0, 0]);
+        for (first_idat, second_idat) in [(&zlib[..], &zlib[..]), (&zlib[..4], &zlib[4..])] {
+            let first_idat = chunk("IDAT", first_idat);
+            let data = png(&[&header, &first_idat, &text, &chunk("IDAT", second_idat)]);
+
+            // the error is at the second IDAT chunk, before either stream is decoded
+            let byte_offset = SIGNATURE.len() + header.len() + first_idat.len() + text.len();
+            let error = DecodeError::PngChunk { byte_offset, reason: "IDAT chunks must be consecutive".to_string() };
+            assert_eq!(PNGParser::decode(&data).err(), Some(error.clone()));
+            let png_file = PNGParser::tokenise(&data);
+            assert_eq!(png_file.children.last().unwrap().kind, TokenKind::Error(error));
+            assert!(!png_file.children.iter().any(|token| token.kind == TokenKind::IdatChunks));
+        }
+    }
+
+    #[test]
+    fn test_decode_without_tokens() {
+        let rgb = rgb_png();
+        let two_idats = rgb_png_two_idats();
+        let indexed = indexed_png();
+        let interlaced = interlaced_png();
+
+        // damaged copies: IEND crc, bit depth, BTYPE 11, truncated IDAT, no IEND and no IDAT
+        let mut bad_crc = interlaced.clone();
+        *bad_crc.last_mut().unwrap() = 0;
+        let mut bad_bit_depth = rgb.clone();
+        bad_bit_depth[24] = 4;
+        let mut bad_btype = rgb.clone();
+        bad_btype[43] = 0x67;
+
+        let files = [
+            rgb.clone(), two_idats, indexed, interlaced, bad_crc, bad_bit_depth, bad_btype,
+            rgb[..47].to_vec(), rgb[..rgb.len()-12].to_vec(), rgb[..33].to_vec(), SIGNATURE[..4].to_vec()
+        ];
+        for data in files {
+            for strict in [false, true] {
+                let parsed = PNGParser::with_strictness(data.clone(), strict).map(|parser| (parser.metadata, parser.image_data));
+                let decoded = PNGParser::decode_with_strictness(&data, strict);
+                match (parsed, decoded) {
+                    (Ok((parsed_metadata, parsed_image)), Ok((metadata, image))) => {
+                        assert_eq!(metadata.to_string(), parsed_metadata.to_string());
+                        assert_eq!((image.color_type, image.data), (parsed_image.color_type, parsed_image.data));
+                    },
+                    (parsed, decoded) => assert_eq!(parsed.err(), decoded.err()),
+                }
             }
-        );
+        }
+    }
+
+    #[test]
+    fn test_tokenise_into_sinks() {
+        // 2x2 truecolour image split over two IDAT chunks, with the Adler-32 checksum changed
+        let mut data = rgb_png_two_idats();
+        data[74] ^= 1;
+        let png_file = PNGParser::tokenise(&data);
+
+        // the stats see every token in the tree
+        let mut stats = TokenStats::new();
+        assert!(PNGParser::tokenise_into(&data, &mut stats).is_err());
+        let tokens = png_file.flatten();
+        assert_eq!(stats.total(), tokens.len());
+        assert_eq!(stats.counts["chunk"], 3);
+        assert_eq!(stats.counts["error"], 1);
+        let literal_bits: usize = tokens.iter().filter(|token| token.kind.name() == "literal").map(|token| token.bit_length).sum();
+        assert_eq!(stats.bits["literal"], literal_bits);
 
-        let image_data = RGBImageData::from_png_stream(&decompressed, width);
+        // the JSON has the same tokens, with the positions of groups after their children
+        let mut writer = JsonWriter::new(Vec::new());
+        assert!(PNGParser::tokenise_into(&data, &mut writer).is_err());
+        let json = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert_eq!(json.lines().count(), 1);
+        assert_eq!(json.matches("\"token_type\"").count(), tokens.len());
+        assert!(json.trim_end().ends_with(&format!("\"start_bit\": 0, \"bit_length\": {}}}", png_file.bit_length)));
+    }
+
+    #[test]
+    fn test_ancillary_chunks() {
+        // 2x2 truecolour image, with gAMA, tEXt and a private chunk after IHDR and tIME before IEND
+        let data = png(&[
+            &ihdr(2, 2, 8, 2, 0), &chunk("gAMA", &[0, 0, 177, 143]), &chunk("tEXt", b"Author\0Jo"), &chunk("prVt", &[1, 2]),
+            &chunk("IDAT", &RGB_ZLIB), &chunk("tIME", &[7, 232, 2, 29, 12, 30, 0]),
+        ]);
+
+        let parser = PNGParser::new_strict(data.clone()).unwrap();
+        let ancillary = &parser.metadata.ancillary;
+        assert_eq!(ancillary.gamma, Some(45455));
+        assert_eq!(ancillary.text, vec![TextChunk::Text { keyword: "Author".to_string(), text: "Jo".to_string() }]);
+        assert_eq!((parser.text_entries()[0].keyword, parser.text_entries()[0].text), ("Author", "Jo"));
+        assert_eq!(ancillary.last_modified.unwrap().year, 2024);
+        assert_eq!(parser.image_data.data, RGB_PIXELS);
+
+        let (metadata, _) = PNGParser::decode_strict(&data).unwrap();
+        assert_eq!(&metadata.ancillary, ancillary);
+
+        // every chunk is shown, with the fields of the standard ones
+        let chunk_types: Vec<&str> = parser.token_tree.children.iter().map(|token| token.data.as_str()).collect();
+        assert_eq!(chunk_types, vec!["png header", "IHDR", "gAMA", "tEXt", "prVt", "IDAT chunks", "tIME", "IEND"]);
+        let text_types: Vec<&str> = parser.token_tree.children[3].children.iter().map(|token| token.kind.name()).collect();
+        assert_eq!(text_types, vec!["chunk_length", "chunk_type", "keyword", "null_separator", "text", "crc_32"]);
+        assert_eq!(parser.token_tree.children[4].children[2].kind, TokenKind::ChunkData);
 
-        (tokens, image_data)
+        let mut covered = vec![false; data.len()*8];
+        for token in parser.token_tree.flatten() {
+            covered[token.start_bit..token.start_bit+token.bit_length].fill(true);
+        }
+        assert!(covered.iter().all(|&bit| bit));
+
+        // a damaged ancillary chunk is ignored, its data is shown followed by the error
+        let damaged = png(&[&ihdr(2, 2, 8, 2, 0), &chunk("tIME", &[7, 232, 13, 29, 12, 30, 0]), &chunk("IDAT", &RGB_ZLIB)]);
+        let error = DecodeError::PngChunk { byte_offset: 43, reason: "Invalid month 13 in tIME chunk, should be 1 to 12".to_string() };
+        let parser = PNGParser::new(damaged.clone()).unwrap();
+        assert_eq!(parser.metadata.ancillary.last_modified, None);
+        assert_eq!(parser.image_data.data, RGB_PIXELS);
+        let time_kinds: Vec<&TokenKind> = parser.token_tree.children[2].children.iter().map(|token| &token.kind).collect();
+        assert_eq!(time_kinds[2..4], [&TokenKind::ChunkData, &TokenKind::Error(error)]);
+        assert_eq!(PNGParser::decode(&damaged).unwrap().0.ancillary.last_modified, None);
     }
+
+    #[test]
+    fn test_animated_png() {
+        // 2x1 truecolour with alpha, the IDAT image is the first frame, then a half transparent green pixel over the right one
+        // the file is also built with the frames miscounted, the sequence numbers swapped, and damaged frame data
+        let frame_data = stored_zlib(&[0, 0, 255, 0, 128]);
+        let file = |actl_frames: u8, sequences: [u32; 2], frame_data: &[u8]| png(&[
+            &ihdr(2, 1, 8, 6, 0), &chunk("acTL", &[0, 0, 0, actl_frames, 0, 0, 0, 0]),
+            &fctl(0, (2, 1), (0, 0), 1, 0, 0), &chunk("IDAT", &stored_zlib(&[0, 255, 0, 0, 255, 0, 0, 255, 255])),
+            &fctl(1, (1, 1), (1, 0), 5, 1, 1), &fdat(sequences[0], &frame_data[..8]), &fdat(sequences[1], &frame_data[8..]),
+        ]);
+        let data = file(2, [2, 3], &frame_data);
+
+        let parser = PNGParser::new_strict(data.clone()).unwrap();
+        assert_eq!(parser.image_data.data, vec![255, 0, 0, 255, 0, 0, 255, 255]);
+        let animation = parser.metadata.animation.as_ref().unwrap();
+        assert_eq!((animation.control.num_frames, animation.default_image_is_first_frame), (2, true));
+        assert_eq!(animation.frames[1].control.dispose_op, crate::apng::DisposeOp::Background);
+        assert_eq!(animation.frames[1].image.data, vec![0, 255, 0, 128]);
+
+        // the frames are composited onto the canvas, the second frame's region is cleared after it is shown
+        let frames: Vec<_> = parser.frames().unwrap().map(|frame| (frame.image.data, frame.delay.as_millis())).collect();
+        assert_eq!(frames, vec![(vec![255, 0, 0, 255, 0, 0, 255, 255], 100), (vec![255, 0, 0, 255, 0, 128, 127, 255], 500)]);
+        let (metadata, _) = PNGParser::decode_strict(&data).unwrap();
+        assert_eq!(metadata.animation.unwrap().composited().count(), 2);
+
+        // the fdAT chunks of a frame are grouped, each shows its sequence number, and the combined data is a zlib stream
+        let chunk_types: Vec<&str> = parser.token_tree.children.iter().map(|token| token.data.as_str()).collect();
+        assert_eq!(chunk_types, vec!["png header", "IHDR", "acTL", "fcTL", "IDAT chunks", "fcTL", "frame 1 fdAT chunks", "IEND"]);
+        let frame_group = &parser.token_tree.children[6];
+        let fdat_types: Vec<&str> = frame_group.children[0].children.iter().map(|token| token.kind.name()).collect();
+        assert_eq!(fdat_types, vec!["chunk_length", "chunk_type", "sequence_number", "crc_32"]);
+        assert_eq!(frame_group.children[2].kind.name(), "zlib_stream");
+        let fctl_types: Vec<&str> = parser.token_tree.children[5].children[2..].iter().map(|token| token.kind.name()).collect();
+        assert_eq!(fctl_types, vec!["sequence_number", "width", "height", "frame_offset", "frame_offset", "frame_delay", "dispose_op", "blend_op", "crc_32"]);
+
+        let mut covered = vec![false; data.len()*8];
+        for token in parser.token_tree.flatten() {
+            covered[token.start_bit..token.start_bit+token.bit_length].fill(true);
+        }
+        assert!(covered.iter().all(|&bit| bit));
+
+        // errors are the same from both paths, and positions in the fdAT data are moved to the file
+        let mut damaged_data = frame_data.clone();
+        *damaged_data.last_mut().unwrap() ^= 1;
+        let errors = [
+            (file(2, [3, 2], &frame_data), "PNG chunk error at byte 169: fdAT sequence number is 3, should be 2"),
+            (file(3, [2, 3], &frame_data), "PNG chunk error at byte 33: acTL chunk has 3 frames, but there are 2 fcTL chunks"),
+            (file(2, [2, 3], &damaged_data), "zlib checksum error at byte 201: stored Adler-32 58851713 doesn't match calculated 58851712"),
+        ];
+        for (damaged, error) in errors {
+            assert_eq!(PNGParser::new(damaged.clone()).err().unwrap().to_string(), error);
+            assert_eq!(PNGParser::decode(&damaged).err().unwrap().to_string(), error);
+        }
+    }
+
 }
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use crate::token::Token;
+
+
+/// Receives tokens as they are decoded, so a large input doesn't need its whole token tree held in memory.
+///
+/// Tokens arrive depth first. A group is opened, its children are pushed, then it is closed. The start_bit and
+/// bit_length of an opened group are only where it starts, as the input it covers isn't known until its children
+/// have been decoded, [`OpenGroups`] works it out for sinks that don't keep the children.
+pub trait TokenSink {
+    /// Adds a token, and any children it already has, to the innermost open group.
+    fn push(&mut self, token: Token);
+
+    /// Starts a group, tokens pushed until the matching [`close_group`](TokenSink::close_group) are its children.
+    fn open_group(&mut self, group: Token);
+
+    /// Ends the innermost open group.
+    fn close_group(&mut self);
+}
+
+
+/// Tracks the input covered by each open group, from the tokens added to it.
+///
+/// A group covers all of its children, or is where it was opened if it has none.
+#[derive(Debug, Default)]
+pub struct OpenGroups {
+    stack: Vec<OpenGroup>,
+}
+
+#[derive(Debug)]
+struct OpenGroup {
+    start_bit: usize,
+    bit_length: usize,
+    children: Option<(usize, usize)>, // first bit the children cover, and the bit after the last
+}
+
+impl OpenGroups {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn open(&mut self, group: &Token) {
+        self.stack.push(OpenGroup { start_bit: group.start_bit, bit_length: group.bit_length, children: None });
+    }
+
+    /// Extends the innermost open group to cover a token.
+    pub fn add(&mut self, token: &Token) {
+        self.cover(token.start_bit, token.start_bit + token.bit_length);
+    }
+
+    /// Ends the innermost open group, returning its start_bit and bit_length.
+    pub fn close(&mut self) -> (usize, usize) {
+        let group = self.stack.pop().expect("no open group to close");
+        let (start_bit, end_bit) = group.children.unwrap_or((group.start_bit, group.start_bit + group.bit_length));
+        self.cover(start_bit, end_bit);
+        (start_bit, end_bit - start_bit)
+    }
+
+    /// Number of open groups.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn cover(&mut self, start_bit: usize, end_bit: usize) {
+        let Some(group) = self.stack.last_mut() else { return };
+        group.children = Some(match group.children {
+            Some((first, last)) => (first.min(start_bit), last.max(end_bit)),
+            None => (start_bit, end_bit),
+        });
+    }
+}
+
+
+/// Builds the token tree in memory.
+#[derive(Debug, Default)]
+pub struct TokenCollector {
+    tokens: Vec<Token>,
+    open: Vec<Token>,
+}
+
+impl TokenCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The tokens that aren't inside a group, with everything else below them.
+    pub fn into_tokens(self) -> Vec<Token> {
+        assert!(self.open.is_empty(), "{} groups haven't been closed", self.open.len());
+        self.tokens
+    }
+}
+
+impl TokenSink for TokenCollector {
+    fn push(&mut self, token: Token) {
+        match self.open.last_mut() {
+            Some(group) => group.children.push(token),
+            None => self.tokens.push(token),
+        }
+    }
+
+    fn open_group(&mut self, group: Token) {
+        self.open.push(group);
+    }
+
+    fn close_group(&mut self) {
+        let mut group = self.open.pop().expect("no open group to close");
+        group.close();
+        self.push(group);
+    }
+}
+
+
+// Drops every token, for decoding without making any.
+pub(crate) struct NoTokens;
+
+impl TokenSink for NoTokens {
+    fn push(&mut self, _token: Token) {}
+
+    fn open_group(&mut self, _group: Token) {}
+
+    fn close_group(&mut self) {}
+}
+
+
+/// Writes each token as JSON as soon as it arrives, with a line for each token that isn't inside a group.
+///
+/// Tokens are written the same as [`Token::to_json`], except that a group's start_bit and bit_length come after
+/// its children, once they are known.
+pub struct JsonWriter<W: Write> {
+    out: W,
+    groups: OpenGroups,
+    has_children: Vec<bool>, // whether each open group has had a child written, for the commas between them
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            groups: OpenGroups::new(),
+            has_children: vec![],
+            error: None,
+        }
+    }
+
+    /// Returns the writer, or the first error from writing to it.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error {
+            return Err(error)
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write(&mut self, json: &str) {
+        // nothing more is written after an error
+        if self.error.is_none() {
+            if let Err(error) = self.out.write_all(json.as_bytes()) {
+                self.error = Some(error);
+            }
+        }
+    }
+
+    fn start_token(&mut self) {
+        let Some(has_children) = self.has_children.last_mut() else { return };
+        if std::mem::replace(has_children, true) {
+            self.write(", ");
+        }
+    }
+
+    fn end_token(&mut self) {
+        if self.has_children.is_empty() {
+            self.write("\n");
+        }
+    }
+}
+
+impl<W: Write> TokenSink for JsonWriter<W> {
+    fn push(&mut self, token: Token) {
+        self.start_token();
+        self.groups.add(&token);
+        self.write(&token.to_json());
+        self.end_token();
+    }
+
+    fn open_group(&mut self, group: Token) {
+        self.start_token();
+        self.groups.open(&group);
+        let mut json = format!("{{\"bits\": {:?}, \"using_bytes\": {}, ", group.bits, group.using_bytes);
+        group.write_json_labels(&mut json);
+        json.push_str(", \"children\": [");
+        self.write(&json);
+        self.has_children.push(false);
+    }
+
+    fn close_group(&mut self) {
+        self.has_children.pop();
+        let (start_bit, bit_length) = self.groups.close();
+        self.write(&format!("], \"start_bit\": {}, \"bit_length\": {}}}", start_bit, bit_length));
+        self.end_token();
+    }
+}
+
+
+/// Counts the tokens of each kind, without keeping any of them.
+#[derive(Debug, Default)]
+pub struct TokenStats {
+    pub counts: BTreeMap<&'static str, usize>, // number of tokens of each kind, by the kind's name
+    pub bits: BTreeMap<&'static str, usize>, // input bits covered by the tokens of each kind, groups aren't counted
+    pub max_depth: usize, // most groups a token is inside
+    depth: usize,
+}
+
+impl TokenStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of tokens of every kind.
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    fn count(&mut self, token: &Token, depth: usize) {
+        *self.counts.entry(token.kind.name()).or_default() += 1;
+        self.max_depth = self.max_depth.max(depth);
+        if token.is_group() {
+            for child in &token.children {
+                self.count(child, depth + 1);
+            }
+        } else {
+            *self.bits.entry(token.kind.name()).or_default() += token.bit_length;
+        }
+    }
+}
+
+impl TokenSink for TokenStats {
+    fn push(&mut self, token: Token) {
+        self.count(&token, self.depth);
+    }
+
+    fn open_group(&mut self, group: Token) {
+        *self.counts.entry(group.kind.name()).or_default() += 1;
+        self.max_depth = self.max_depth.max(self.depth);
+        self.depth += 1;
+    }
+
+    fn close_group(&mut self) {
+        self.depth -= 1;
+    }
+}
+
+
+/// Passes tokens on to another sink after moving them, for tokens decoded from data part way through a larger input.
+pub(crate) struct MovedTokens<'a, F: Fn(&mut Token)> {
+    pub sink: &'a mut dyn TokenSink,
+    pub move_token: F, // moves a token and its children
+}
+
+impl<F: Fn(&mut Token)> TokenSink for MovedTokens<'_, F> {
+    fn push(&mut self, mut token: Token) {
+        (self.move_token)(&mut token);
+        self.sink.push(token);
+    }
+
+    fn open_group(&mut self, mut group: Token) {
+        (self.move_token)(&mut group);
+        self.sink.open_group(group);
+    }
+
+    fn close_group(&mut self) {
+        self.sink.close_group();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::{group_token, literal_token, TokenKind};
+
+    fn send_tokens(sink: &mut dyn TokenSink) {
+        // a block with two literals and an empty group, then a literal outside it
+        sink.open_group(group_token(TokenKind::DeflateBlock { number: 1 }, "block 1".to_string(), "", 0));
+        sink.push(literal_token(1, None, 16));
+        sink.open_group(group_token(TokenKind::Symbols, String::new(), "", 40));
+        sink.close_group();
+        sink.push(literal_token(2, None, 24));
+        sink.close_group();
+        sink.push(literal_token(3, None, 48));
+    }
+
+    #[test]
+    fn test_collector() {
+        let mut collector = TokenCollector::new();
+        send_tokens(&mut collector);
+        let tokens = collector.into_tokens();
+
+        assert_eq!(tokens.len(), 2);
+        assert_eq!((tokens[0].start_bit, tokens[0].bit_length), (16, 24));
+        assert_eq!((tokens[0].children[1].start_bit, tokens[0].children[1].bit_length), (40, 0));
+        assert_eq!(tokens[0].children[2].kind, TokenKind::Literal(2));
+    }
+
+    #[test]
+    fn test_json_writer() {
+        let mut writer = JsonWriter::new(Vec::new());
+        send_tokens(&mut writer);
+        let json = String::from_utf8(writer.finish().unwrap()).unwrap();
+        let lines: Vec<&str> = json.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(r#"{"bits": [], "using_bytes": false, "data": "block 1", "token_type": "deflate_block", "description": "", "children": [{"bits": [1]"#));
+        assert!(lines[0].ends_with(r#""children": []}], "start_bit": 16, "bit_length": 24}"#));
+        assert!(lines[0].contains(r#""children": [], "start_bit": 40, "bit_length": 0}, {"bits": [2]"#));
+        assert_eq!(lines[1], literal_token(3, None, 48).to_json());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = TokenStats::new();
+        send_tokens(&mut stats);
+
+        assert_eq!(stats.total(), 5);
+        assert_eq!(stats.counts["literal"], 3);
+        assert_eq!(stats.bits["literal"], 24);
+        assert!(!stats.bits.contains_key("deflate_block"));
+        assert_eq!(stats.max_depth, 1);
+    }
+
+    #[test]
+    fn test_moved_tokens() {
+        let mut collector = TokenCollector::new();
+        let mut moved = MovedTokens { sink: &mut collector, move_token: |token: &mut Token| token.start_bit += 8 };
+        send_tokens(&mut moved);
+
+        let tokens = collector.into_tokens();
+        assert_eq!((tokens[0].start_bit, tokens[0].bit_length), (24, 24));
+        assert_eq!(tokens[1].start_bit, 56);
+    }
+}
@@ -0,0 +1,78 @@
+use crate::low_level_functions::{adler_32, crc_32};
+use crate::png_parser::SIGNATURE;
+
+// TEST FIXTURES
+// PNG chunks and zlib streams built in tests, so they don't need to be written out byte by byte.
+
+pub fn chunk(chunk_type: &str, data: &[u8]) -> Vec<u8> {
+    // length, type, data and crc of a chunk
+    let mut bytes = (data.len() as u32).to_be_bytes().to_vec();
+    bytes.extend(chunk_type.as_bytes());
+    bytes.extend(data);
+    bytes.extend(crc_32(&bytes[4..]).to_be_bytes());
+    bytes
+}
+
+pub fn stored_zlib(data: &[u8]) -> Vec<u8> {
+    // a zlib stream of stored deflate blocks, so the compressed bytes are easy to find and change
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = if data.is_empty() { vec![data] } else { data.chunks(65535).collect() };
+    for (index, block) in blocks.iter().enumerate() {
+        zlib.push((index == blocks.len() - 1) as u8);
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend(*block);
+    }
+    zlib.extend(adler_32(data).to_be_bytes());
+    zlib
+}
+
+pub fn png(chunks: &[&[u8]]) -> Vec<u8> {
+    // the signature, then the chunks, then IEND
+    let mut file = SIGNATURE.to_vec();
+    for chunk in chunks {
+        file.extend_from_slice(chunk);
+    }
+    file.extend(chunk("IEND", &[]));
+    file
+}
+
+pub fn ihdr(width: u32, height: u32, bit_depth: u8, color_type: u8, interlace_method: u8) -> Vec<u8> {
+    chunk("IHDR", &[&width.to_be_bytes()[..], &height.to_be_bytes(), &[bit_depth, color_type, 0, 0, interlace_method]].concat())
+}
+
+pub fn fctl(sequence_number: u32, (width, height): (u32, u32), (x_offset, y_offset): (u32, u32), delay_tenths: u16, dispose_op: u8, blend_op: u8) -> Vec<u8> {
+    // a frame control chunk, with the delay in tenths of a second
+    let fields = [sequence_number, width, height, x_offset, y_offset].map(u32::to_be_bytes).concat();
+    chunk("fcTL", &[&fields[..], &delay_tenths.to_be_bytes(), &10u16.to_be_bytes(), &[dispose_op, blend_op]].concat())
+}
+
+pub fn fdat(sequence_number: u32, data: &[u8]) -> Vec<u8> {
+    chunk("fdAT", &[&sequence_number.to_be_bytes()[..], data].concat())
+}
+
+// 2x2 8 bit truecolour, first row uses the Sub filter, second row uses Paeth
+pub const RGB_ZLIB: [u8; 22] = [120, 218, 99, 228, 18, 145, 99, 101, 101, 101, 97, 100, 100, 100, 98, 98, 2, 0, 3, 150, 0, 90];
+pub const RGB_PIXELS: [u8; 12] = [10, 20, 30, 15, 25, 35, 11, 21, 31, 17, 27, 37];
+
+pub fn rgb_png() -> Vec<u8> {
+    png(&[&ihdr(2, 2, 8, 2, 0), &chunk("IDAT", &RGB_ZLIB)])
+}
+
+pub fn rgb_png_two_idats() -> Vec<u8> {
+    // the same image with the zlib stream split over two IDAT chunks
+    png(&[&ihdr(2, 2, 8, 2, 0), &chunk("IDAT", &RGB_ZLIB[..5]), &chunk("IDAT", &RGB_ZLIB[5..])])
+}
+
+pub fn indexed_png() -> Vec<u8> {
+    // 3x1 2 bit indexed-colour, palette of red, green, blue, red is fully transparent
+    png(&[
+        &ihdr(3, 1, 2, 3, 0), &chunk("PLTE", &[255, 0, 0, 0, 255, 0, 0, 0, 255]), &chunk("tRNS", &[0]),
+        &chunk("IDAT", &[120, 218, 99, 144, 0, 0, 0, 26, 0, 25]),
+    ])
+}
+
+pub fn interlaced_png() -> Vec<u8> {
+    // 3x3 8 bit greyscale with Adam7 interlacing, the last pass uses the Paeth filter
+    png(&[&ihdr(3, 3, 8, 0, 1), &chunk("IDAT", &[120, 218, 99, 96, 96, 96, 98, 96, 227, 96, 96, 100, 96, 103, 97, 102, 97, 5, 0, 0, 251, 0, 41])])
+}
@@ -1,68 +1,502 @@
-use std::fmt::Debug;
+use std::fmt::Write;
+use crate::ancillary::{Background, SuggestedPaletteEntry};
+use crate::apng::{BlendOp, DisposeOp};
+use crate::error::DecodeError;
+use crate::sink::TokenSink;
 
 
+/// A Huffman code alphabet in a dynamic deflate block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alphabet {
+    CodeLength,
+    LiteralLength,
+    Distance,
+}
+
+/// What a token is, with the values decoded from its bits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    // groups
+    PngFile,
+    ZlibStream,
+    DeflateBlock { number: usize },
+    HuffmanHeader,
+    HuffmanTable(Alphabet),
+    Symbols,
+    Chunk { chunk_type: String, length: u32 },
+    IdatChunks,
+    Adam7Passes,
+    Adam7Pass(usize),
+
+    // zlib
+    Cmf { cm: u8, cinfo: u8 },
+    Flg { flevel: u8, fdict: bool },
+    Dict(u32),
+    Adler32 { stored: u32, calculated: u32 },
+
+    // deflate
+    BlockFinal(bool),
+    BlockType(u8),
+    Padding,
+    StoredLength(u16),
+    StoredLengthComplement(u16),
+    Literal(u8),
+    Reference { length: u16, distance: u16 },
+    EndOfBlock,
+    LiteralLengthCount(usize),
+    DistanceCount(usize),
+    CodeLengthCount(usize),
+    CodeLengthCodelengths,
+    RepeatedZero(u8),
+    RepeatedZeroLong(u8),
+    HuffmanSymbols(Alphabet),
+    HuffmanPrefixes(Alphabet),
+
+    // png
+    Signature,
+    ChunkLength(u32),
+    ChunkType(String),
+    ChunkData,
+    Crc32 { stored: u32, calculated: u32 },
+    Width(u32),
+    Height(u32),
+    BitDepth(u8),
+    ColorType(u8),
+    CompressionMethod(u8),
+    FilterMethod(u8),
+    InterlaceMethod(u8),
+    PaletteEntry { index: usize, rgb: [u8; 3] },
+    PaletteAlpha { index: usize, alpha: u8 },
+    TransparentColour(Vec<u16>),
+    Scanline { row: usize, filter_type: u8 },
+
+    // ancillary chunks
+    Keyword(String),
+    NullSeparator,
+    CompressedData,
+    Gamma(u32),
+    Chromaticity(u32),
+    RenderingIntent(u32),
+    SignificantBits(u8),
+    BackgroundColour(Background),
+    HistogramEntry { index: usize, frequency: u16 },
+    PixelsPerUnit(u32),
+    Unit(u8),
+    SampleDepth(u8),
+    SuggestedPaletteEntry(SuggestedPaletteEntry),
+    Year(u16),
+    Month(u8),
+    Day(u8),
+    Hour(u8),
+    Minute(u8),
+    Second(u8),
+    Text(String),
+    CompressionFlag(bool),
+    LanguageTag(String),
+    TranslatedKeyword(String),
+    ExifData,
+
+    // ICC profiles, inflated from iCCP
+    IccProfile,
+    ProfileSize(u32),
+    PreferredCmm(String),
+    ProfileVersion { major: u8, minor: u8, bug_fix: u8 },
+    DeviceClass(String),
+    ColourSpace(String),
+    IccTagTable,
+    TagCount(usize),
+    IccTag { signature: String, offset: u32, size: u32 },
+
+    // animated PNG
+    FrameCount(u32),
+    PlayCount(u32),
+    SequenceNumber(u32),
+    FrameOffset(u32),
+    FrameDelay { numerator: u16, denominator: u16 },
+    DisposeOp(DisposeOp),
+    BlendOp(BlendOp),
+    FrameDataChunks(usize),
+
+    Error(DecodeError),
+}
 
+impl TokenKind {
+    /// Name of the kind, used as the token_type in JSON. These don't change when the payload does.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenKind::PngFile => "png_file",
+            TokenKind::ZlibStream => "zlib_stream",
+            TokenKind::DeflateBlock { .. } => "deflate_block",
+            TokenKind::HuffmanHeader => "huffman_header",
+            TokenKind::HuffmanTable(Alphabet::CodeLength) => "code_length_table",
+            TokenKind::HuffmanTable(Alphabet::LiteralLength) => "literal_length_table",
+            TokenKind::HuffmanTable(Alphabet::Distance) => "distance_table",
+            TokenKind::Symbols => "symbols",
+            TokenKind::Chunk { .. } => "chunk",
+            TokenKind::IdatChunks => "idat_chunks",
+            TokenKind::Adam7Passes => "adam7_passes",
+            TokenKind::Adam7Pass(_) => "adam7_pass",
+            TokenKind::Cmf { .. } => "CMF",
+            TokenKind::Flg { .. } => "FLG",
+            TokenKind::Dict(_) => "DICT",
+            TokenKind::Adler32 { .. } => "adler_32",
+            TokenKind::BlockFinal(_) => "bfinal",
+            TokenKind::BlockType(_) => "btype",
+            TokenKind::Padding => "padding",
+            TokenKind::StoredLength(_) => "bytes length",
+            TokenKind::StoredLengthComplement(_) => "complement bytes",
+            TokenKind::Literal(_) => "literal",
+            TokenKind::Reference { .. } => "string reference",
+            TokenKind::EndOfBlock => "end of block",
+            TokenKind::LiteralLengthCount(_) => "hlit",
+            TokenKind::DistanceCount(_) => "hdist",
+            TokenKind::CodeLengthCount(_) => "hclen",
+            TokenKind::CodeLengthCodelengths => "clen_codelengths",
+            TokenKind::RepeatedZero(_) => "repeated_0",
+            TokenKind::RepeatedZeroLong(_) => "repeated_0_long",
+            TokenKind::HuffmanSymbols(Alphabet::CodeLength) => "cl_symbols",
+            TokenKind::HuffmanSymbols(Alphabet::LiteralLength) => "literal_length_symbols",
+            TokenKind::HuffmanSymbols(Alphabet::Distance) => "distance_symbols",
+            TokenKind::HuffmanPrefixes(Alphabet::CodeLength) => "cl_prefixes",
+            TokenKind::HuffmanPrefixes(Alphabet::LiteralLength) => "literal_length_prefixes",
+            TokenKind::HuffmanPrefixes(Alphabet::Distance) => "distance_prefixes",
+            TokenKind::Signature => "header",
+            TokenKind::ChunkLength(_) => "chunk_length",
+            TokenKind::ChunkType(_) => "chunk_type",
+            TokenKind::ChunkData => "chunk_data",
+            TokenKind::Crc32 { .. } => "crc_32",
+            TokenKind::Width(_) => "width",
+            TokenKind::Height(_) => "height",
+            TokenKind::BitDepth(_) => "bit_depth",
+            TokenKind::ColorType(_) => "color_type",
+            TokenKind::CompressionMethod(_) => "compression_method",
+            TokenKind::FilterMethod(_) => "filter_method",
+            TokenKind::InterlaceMethod(_) => "interlace_method",
+            TokenKind::PaletteEntry { .. } => "palette_entry",
+            TokenKind::PaletteAlpha { .. } => "palette_alpha",
+            TokenKind::TransparentColour(_) => "transparent_colour",
+            TokenKind::Scanline { .. } => "scanline",
+            TokenKind::Keyword(_) => "keyword",
+            TokenKind::NullSeparator => "null_separator",
+            TokenKind::CompressedData => "compressed_data",
+            TokenKind::Gamma(_) => "gamma",
+            TokenKind::Chromaticity(_) => "chromaticity",
+            TokenKind::RenderingIntent(_) => "rendering_intent",
+            TokenKind::SignificantBits(_) => "significant_bits",
+            TokenKind::BackgroundColour(_) => "background_colour",
+            TokenKind::HistogramEntry { .. } => "histogram_entry",
+            TokenKind::PixelsPerUnit(_) => "pixels_per_unit",
+            TokenKind::Unit(_) => "unit",
+            TokenKind::SampleDepth(_) => "sample_depth",
+            TokenKind::SuggestedPaletteEntry(_) => "suggested_palette_entry",
+            TokenKind::Year(_) => "year",
+            TokenKind::Month(_) => "month",
+            TokenKind::Day(_) => "day",
+            TokenKind::Hour(_) => "hour",
+            TokenKind::Minute(_) => "minute",
+            TokenKind::Second(_) => "second",
+            TokenKind::Text(_) => "text",
+            TokenKind::CompressionFlag(_) => "compression_flag",
+            TokenKind::LanguageTag(_) => "language_tag",
+            TokenKind::TranslatedKeyword(_) => "translated_keyword",
+            TokenKind::ExifData => "exif_data",
+            TokenKind::IccProfile => "icc_profile",
+            TokenKind::ProfileSize(_) => "profile_size",
+            TokenKind::PreferredCmm(_) => "preferred_cmm",
+            TokenKind::ProfileVersion { .. } => "profile_version",
+            TokenKind::DeviceClass(_) => "device_class",
+            TokenKind::ColourSpace(_) => "colour_space",
+            TokenKind::IccTagTable => "icc_tag_table",
+            TokenKind::TagCount(_) => "tag_count",
+            TokenKind::IccTag { .. } => "icc_tag",
+            TokenKind::FrameCount(_) => "frame_count",
+            TokenKind::PlayCount(_) => "play_count",
+            TokenKind::SequenceNumber(_) => "sequence_number",
+            TokenKind::FrameOffset(_) => "frame_offset",
+            TokenKind::FrameDelay { .. } => "frame_delay",
+            TokenKind::DisposeOp(_) => "dispose_op",
+            TokenKind::BlendOp(_) => "blend_op",
+            TokenKind::FrameDataChunks(_) => "frame_data_chunks",
+            TokenKind::Error(_) => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Token {
     pub bits: Vec<u8>,
     pub using_bytes: bool, // if the 'bits' field actually stores byte values instead
-    pub nest_level: u8, // 0 is most nested
+    pub start_bit: usize, // absolute position of the first bit in the input
+    pub bit_length: usize, // number of input bits covered, 0 for tokens that only mark a position
+    pub children: Vec<Token>, // tokens inside this one, like the fields of a chunk or the symbols of a block
     pub data: String,
-    pub token_type: String,
+    pub kind: TokenKind,
     pub description: String,
 }
 
-impl Debug for Token {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // json for token
-        write!(f, "{{\"bits\": {:?}, \"using_bytes\": {}, \"nest_level\": {}, \"data\": \"{}\", \"token_type\": \"{}\", \"description\": \"{}\"}}", self.bits, self.using_bytes, self.nest_level, self.data, self.token_type, self.description)
+impl Token {
+    /// Writes the token and its children as a JSON object, with every string escaped.
+    pub fn write_json(&self, out: &mut String) {
+        let _ = write!(
+            out, "{{\"bits\": {:?}, \"using_bytes\": {}, \"start_bit\": {}, \"bit_length\": {}, ",
+            self.bits, self.using_bytes, self.start_bit, self.bit_length
+        );
+        self.write_json_labels(out);
+        out.push_str(", \"children\": ");
+        write_json_array(&self.children, out);
+        out.push('}');
+    }
+
+    pub(crate) fn write_json_labels(&self, out: &mut String) {
+        // the data, token_type and description fields
+        out.push_str("\"data\": ");
+        write_json_string(&self.data, out);
+        out.push_str(", \"token_type\": ");
+        write_json_string(self.kind.name(), out);
+        out.push_str(", \"description\": ");
+        write_json_string(&self.description, out);
+    }
+
+    /// The token as a JSON object.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    /// The token and all tokens below it, depth first.
+    pub fn flatten(&self) -> Vec<&Token> {
+        let mut tokens = vec![self];
+        for child in &self.children {
+            tokens.extend(child.flatten());
+        }
+        tokens
+    }
+
+    pub fn is_group(&self) -> bool {
+        !self.children.is_empty()
+    }
+
+    pub(crate) fn close(&mut self) {
+        // a group covers the input of all its children
+        let Some(start_bit) = self.children.iter().map(|child| child.start_bit).min() else { return };
+        let end_bit = self.children.iter().map(|child| child.start_bit + child.bit_length).max().unwrap();
+        self.start_bit = start_bit;
+        self.bit_length = end_bit - start_bit;
+    }
+
+    /// Adds a token to the end of a group, such as an error token after the tokens decoded before it.
+    pub fn push_child(&mut self, child: Token) {
+        self.children.push(child);
+        self.close();
+    }
+}
+
+pub fn group_token(kind: TokenKind, data: String, description: &str, start_bit: usize) -> Token {
+    // the position is updated from the children once they are added, start_bit is used if there aren't any
+    Token {
+        bits: vec![],
+        using_bytes: false,
+        start_bit,
+        bit_length: 0,
+        children: vec![],
+        data,
+        kind,
+        description: description.to_string(),
     }
 }
 
-pub fn literal_token(literal: u8, bits: Option<Vec<u8>>, nest_level: u8) -> Token {
-    // under 32 can cause problematic json
-    let data;
-    if literal > 31 {
-        if literal == 92 {
-            // escape \
-            data = (literal.to_string()) + r": \\ ";
-        } else if literal == 34 {
-            // escape "
-            data = (literal.to_string()) + ": " + r"\" + "\"";
-        } else {
-            data = (literal.to_string()) + ": " + &(literal as char).to_string();
+pub fn decode_group<T>(tokens: &mut dyn TokenSink, group: Token, decode: impl FnOnce(&mut dyn TokenSink) -> Result<T, DecodeError>) -> Result<T, DecodeError> {
+    // decode adds the group's children, the group is closed even if there is an error
+    tokens.open_group(group);
+    let result = decode(tokens);
+    tokens.close_group();
+    result
+}
+
+/// Moves tokens and their children along the input, for when they were decoded from data part way through a larger input.
+pub fn shift_tokens(tokens: &mut [Token], bits: usize) {
+    for token in tokens {
+        token.start_bit += bits;
+        shift_tokens(&mut token.children, bits);
+    }
+}
+
+/// Writes the tokens as a JSON array.
+pub fn tokens_to_json(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    write_json_array(tokens, &mut out);
+    out
+}
+
+fn write_json_array(tokens: &[Token], out: &mut String) {
+    out.push('[');
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
         }
-    } else {
-        data = literal.to_string();
+        token.write_json(out);
     }
+    out.push(']');
+}
+
+fn write_json_string(string: &str, out: &mut String) {
+    // quotes, backslashes and control characters must be escaped (RFC 8259 7)
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+pub fn literal_token(literal: u8, bits: Option<Vec<u8>>, start_bit: usize) -> Token {
+    // control characters are only shown as their value
+    let data = if literal > 31 {
+        (literal.to_string()) + ": " + &(literal as char).to_string()
+    } else {
+        literal.to_string()
+    };
 
     if bits.is_none() {
         return Token {
             bits: vec![literal],
             using_bytes: true,
-            nest_level,
+            start_bit,
+            bit_length: 8,
+            children: vec![],
             data,
-            token_type: "literal".to_string(),
+            kind: TokenKind::Literal(literal),
             description: "literal 0-255 value".to_string()
         }
     }
 
+    let bits = bits.unwrap();
     Token {
-        bits: bits.unwrap(),
+        bit_length: bits.len(),
+        bits,
         using_bytes: false,
-        nest_level,
+        start_bit,
+        children: vec![],
         data,
-        token_type: "literal".to_string(),
+        kind: TokenKind::Literal(literal),
         description: "literal 0-255 value".to_string()
     }
 }
 
-pub fn reference_token(bits: Vec<u8>, distance: u16, length: u16, nest_level: u8) -> Token {
+pub fn reference_token(bits: Vec<u8>, distance: u16, length: u16, start_bit: usize) -> Token {
     Token {
+        bit_length: bits.len(),
         bits,
         using_bytes: false,
-        nest_level,
+        start_bit,
+        children: vec![],
         data: format!(r"< len {}, dist {} >", length, distance),
-        token_type: "string reference".to_string(),
+        kind: TokenKind::Reference { length, distance },
         description: "Duplicates a string from the stream".to_string(),
     }
 }
+
+pub fn error_token(error: &DecodeError, input: &[u8]) -> Token {
+    // covers everything from where the error happened to the end of the input, as none of it can be decoded
+    let bit_offset = error.bit_offset().min(input.len()*8);
+    let (bits, using_bytes) = if bit_offset.is_multiple_of(8) {
+        (input[bit_offset/8..].to_vec(), true)
+    } else {
+        let first_byte = input[bit_offset/8];
+        let partial_bits = (bit_offset%8..8).map(|i| (first_byte >> i) & 1);
+        (partial_bits.chain(input[bit_offset/8+1..].iter().flat_map(|byte| (0..8).map(move |i| (byte >> i) & 1))).collect(), false)
+    };
+
+    Token {
+        bits,
+        using_bytes,
+        start_bit: bit_offset,
+        bit_length: input.len()*8 - bit_offset,
+        children: vec![],
+        data: error.to_string(),
+        kind: TokenKind::Error(error.clone()),
+        description: format!("Decoding stopped, the {} couldn't be read", error.stage()),
+    }
+}
+
+pub(crate) fn ignored_error_token(error: &DecodeError) -> Token {
+    // marks where an error was found in data that is skipped, covering none of it so decoding carries on after it
+    Token {
+        bits: vec![],
+        using_bytes: true,
+        start_bit: error.bit_offset(),
+        bit_length: 0,
+        children: vec![],
+        data: error.to_string(),
+        kind: TokenKind::Error(error.clone()),
+        description: format!("The {} couldn't be read, so it is ignored", error.stage()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::TokenCollector;
+
+    #[test]
+    fn test_json_escaping() {
+        let token = Token {
+            bits: vec![1, 0],
+            using_bytes: false,
+            start_bit: 3,
+            bit_length: 2,
+            children: vec![],
+            data: "a \"quote\", a \\ and\na newline\u{1}".to_string(),
+            kind: TokenKind::ChunkType("tEXt".to_string()),
+            description: "café".to_string(),
+        };
+        assert_eq!(
+            token.to_json(),
+            r#"{"bits": [1, 0], "using_bytes": false, "start_bit": 3, "bit_length": 2, "data": "a \"quote\", a \\ and\na newline\u0001", "token_type": "chunk_type", "description": "café", "children": []}"#
+        );
+
+        assert_eq!(literal_token(34, None, 16).to_json(), r#"{"bits": [34], "using_bytes": true, "start_bit": 16, "bit_length": 8, "data": "34: \"", "token_type": "literal", "description": "literal 0-255 value", "children": []}"#);
+        assert_eq!(tokens_to_json(&[]), "[]");
+    }
+
+    #[test]
+    fn test_decode_group() {
+        // the group covers its children, and is kept when decoding fails part way through
+        let mut collector = TokenCollector::new();
+        let result: Result<(), DecodeError> = decode_group(&mut collector, group_token(TokenKind::Symbols, String::new(), "", 0), |tokens| {
+            tokens.push(literal_token(1, None, 16));
+            tokens.push(literal_token(2, None, 24));
+            Err(DecodeError::DeflateBlock { bit_offset: 32, reason: "Unexpected end of data".to_string() })
+        });
+
+        assert!(result.is_err());
+        let mut tokens = collector.into_tokens();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!((tokens[0].start_bit, tokens[0].bit_length), (16, 16));
+        assert_eq!(tokens[0].children.len(), 2);
+        assert_eq!(tokens[0].flatten().len(), 3);
+
+        shift_tokens(&mut tokens, 8);
+        assert_eq!(tokens[0].children[1].start_bit, 32);
+    }
+
+    #[test]
+    fn test_token_kind() {
+        // the payload is typed, and the JSON keeps the kind's name
+        let token = reference_token(vec![1, 0, 1], 4, 10, 0);
+        assert_eq!(token.kind, TokenKind::Reference { length: 10, distance: 4 });
+        assert!(token.to_json().contains(r#""token_type": "string reference""#));
+
+        assert_eq!(TokenKind::HuffmanSymbols(Alphabet::CodeLength).name(), "cl_symbols");
+        assert_eq!(TokenKind::HuffmanTable(Alphabet::Distance).name(), "distance_table");
+    }
+}
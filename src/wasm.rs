@@ -0,0 +1,63 @@
+// exports for the website, the token tree is returned as a JSON string
+use wasm_bindgen::prelude::*;
+use crate::png_parser::PNGParser;
+use crate::sink::{OpenGroups, TokenSink};
+use crate::token::Token;
+use crate::zlib::{tokenise_zlib, tokenise_zlib_into};
+
+extern crate web_sys;
+
+#[wasm_bindgen]
+pub fn decode_png(data: Vec<u8>) -> String {
+    // if the file is damaged, the last token is an error token showing where decoding stopped
+    PNGParser::tokenise(&data).to_json()
+}
+
+#[wasm_bindgen]
+pub fn decode_zlib(data: Vec<u8>) -> String {
+    tokenise_zlib(&data).to_json()
+}
+
+// calls a JS function with each token as it is decoded, instead of building the whole tree
+// the callback gets an event name and JSON:
+//   "token", a token and its children
+//   "open_group", a group without its children, which are sent until the matching "close_group"
+//   "close_group", {"start_bit": .., "bit_length": ..} of the group, which covers all of its children
+struct JsCallback {
+    callback: js_sys::Function,
+    groups: OpenGroups,
+}
+
+impl JsCallback {
+    fn call(&self, event: &str, json: &str) {
+        let _ = self.callback.call2(&JsValue::NULL, &JsValue::from_str(event), &JsValue::from_str(json));
+    }
+}
+
+impl TokenSink for JsCallback {
+    fn push(&mut self, token: Token) {
+        self.groups.add(&token);
+        self.call("token", &token.to_json());
+    }
+
+    fn open_group(&mut self, group: Token) {
+        self.groups.open(&group);
+        self.call("open_group", &group.to_json());
+    }
+
+    fn close_group(&mut self) {
+        let (start_bit, bit_length) = self.groups.close();
+        self.call("close_group", &format!("{{\"start_bit\": {}, \"bit_length\": {}}}", start_bit, bit_length));
+    }
+}
+
+#[wasm_bindgen]
+pub fn tokenise_png_with_callback(data: Vec<u8>, callback: js_sys::Function) {
+    // errors are sent as an error token, the same as decode_png
+    let _ = PNGParser::tokenise_into(&data, &mut JsCallback { callback, groups: OpenGroups::new() });
+}
+
+#[wasm_bindgen]
+pub fn tokenise_zlib_with_callback(data: Vec<u8>, callback: js_sys::Function) {
+    let _ = tokenise_zlib_into(&data, &mut JsCallback { callback, groups: OpenGroups::new() });
+}
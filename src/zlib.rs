@@ -1,7 +1,10 @@
 use std::fmt::Display;
-use crate::deflate::new_parse_deflate;
-use crate::low_level_functions::bytes_vec_to_single;
-use crate::token::Token;
+use crate::deflate::parse_deflate_into;
+use crate::error::DecodeError;
+use crate::inflater::Inflater;
+use crate::low_level_functions::{adler_32, bytes_vec_to_single};
+use crate::sink::{MovedTokens, TokenCollector, TokenSink};
+use crate::token::{decode_group, error_token, group_token, shift_tokens, Token, TokenKind};
 
 
 pub struct ZLibInfo {
@@ -18,74 +21,267 @@ impl Display for ZLibInfo {
     }
 }
 
-pub fn new_parse_zlib(data: &Vec<u8>) -> (Vec<Token>, Vec<u8>) {
-    let mut tokens = Vec::new();
+fn header_error(byte_offset: usize, reason: &str) -> DecodeError {
+    DecodeError::ZlibHeader { byte_offset, reason: reason.to_string() }
+}
+
+fn header_length(data: &[u8]) -> Result<usize, DecodeError> {
+    // checks the CMF and FLG bytes, returns where the deflate stream starts
+    let &cmf = data.first().ok_or(header_error(0, "No ZLib stream found"))?;
+    let &flg = data.get(1).ok_or(header_error(1, "ZLib stream has one byte"))?;
+
+    if cmf & 15 != 8 {
+        return Err(header_error(0, &format!("Compression method {} is not deflate (8)", cmf & 15)))
+    }
+
+    // checksum, when cmf and flg are viewed as a 16 bit int, must be multiple of 31
+    if !(((cmf as u16) << 8) | (flg as u16)).is_multiple_of(31) {
+        return Err(header_error(1, "FCHECK is incorrect, CMF and FLG are not a multiple of 31"))
+    }
+
+    if flg & 32 == 0 {
+        return Ok(2)
+    }
+    if data.len() < 6 {
+        return Err(header_error(2, "Can't get preset dictionary bytes 2-5 in ZLib stream"))
+    }
+    Ok(6)
+}
+
+fn check_length(data: &[u8], deflate_data_start: usize) -> Result<(), DecodeError> {
+    if data.len() < deflate_data_start+4 {
+        return Err(header_error(data.len(), "ZLib stream is too short for the Adler-32 check"))
+    }
+    Ok(())
+}
+
+fn check_adler_32(data: &[u8], decompressed: &[u8]) -> Result<u32, DecodeError> {
+    // adler-32 is calculated over the decompressed data, and stored in the last 4 bytes
+    let adler32_check = bytes_vec_to_single(&data[data.len()-4..].to_vec());
+    let adler32_calculated = adler_32(decompressed);
+
+    if adler32_calculated != adler32_check {
+        return Err(DecodeError::ZlibChecksum {
+            byte_offset: data.len()-4,
+            stored: adler32_check,
+            calculated: adler32_calculated
+        })
+    }
+    Ok(adler32_check)
+}
+
+pub fn decode_zlib(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    // the same checks as new_parse_zlib, without making any tokens
+    let deflate_data_start = header_length(data)?;
+    check_length(data, deflate_data_start)?;
+
+    let mut inflater = Inflater::new();
+    let mut decompressed = Vec::new();
+    inflater.push(&data[deflate_data_start..data.len()-4], &mut decompressed)
+        .and_then(|_| inflater.finish())
+        .map_err(|error| error.shifted(deflate_data_start))?;
+
+    check_adler_32(data, &decompressed)?;
+    Ok(decompressed)
+}
+
+pub fn new_parse_zlib(data: &[u8]) -> Result<(Token, Vec<u8>), DecodeError> {
+    // returns a zlib_stream token with the header, deflate blocks and checksum in it
+    let mut tokens = TokenCollector::new();
+    let decompressed = parse_zlib_into(data, &mut tokens)?;
+    Ok((tokens.into_tokens().pop().unwrap(), decompressed))
+}
+
+pub fn tokenise_zlib(data: &[u8]) -> Token {
+    // tokens for as much of the stream as could be decoded, followed by an error token if it is damaged
+    let mut tokens = TokenCollector::new();
+    let _ = tokenise_zlib_into(data, &mut tokens);
+    tokens.into_tokens().pop().unwrap()
+}
+
+pub fn tokenise_zlib_into(data: &[u8], tokens: &mut dyn TokenSink) -> Result<Vec<u8>, DecodeError> {
+    // same as tokenise_zlib, with the tokens sent to a sink as they are decoded
+    let zlib_stream = group_token(TokenKind::ZlibStream, format!("{} bytes", data.len()), "ZLib stream, a header followed by deflate blocks and a checksum", 0);
+    decode_group(tokens, zlib_stream, |tokens| {
+        let result = parse_zlib_stream(data, tokens);
+        if let Err(error) = &result {
+            tokens.push(error_token(error, data));
+        }
+        result
+    })
+}
+
+pub fn parse_zlib_into(data: &[u8], tokens: &mut dyn TokenSink) -> Result<Vec<u8>, DecodeError> {
+    // adds a zlib_stream token, which keeps the tokens decoded before an error
+    let zlib_stream = group_token(TokenKind::ZlibStream, format!("{} bytes", data.len()), "ZLib stream, a header followed by deflate blocks and a checksum", 0);
+    decode_group(tokens, zlib_stream, |tokens| parse_zlib_stream(data, tokens))
+}
 
-    let &cmf = data.get(0).expect("No ZLib stream found");
+fn parse_zlib_stream(data: &[u8], tokens: &mut dyn TokenSink) -> Result<Vec<u8>, DecodeError> {
+    let &cmf = data.first().ok_or(header_error(0, "No ZLib stream found"))?;
     tokens.push(
         Token {
             bits: vec![cmf],
             using_bytes: true,
-            nest_level: 1,
+            start_bit: 0,
+            bit_length: 8,
+            children: vec![],
             data: format!("CINFO: {}{}{}{} CM: {}{}{}{}", (cmf&128)>>7, (cmf&64)>>6, (cmf&32)>>5, (cmf&16)>>4, (cmf&8)>>3, (cmf&4)>>2, (cmf&2)>>1, cmf&1), // messy way of making binary string
-            token_type: "CMF".to_string(),
+            kind: TokenKind::Cmf { cm: cmf & 15, cinfo: cmf >> 4 },
             description: "0-3 is compression method, 4-7 is compression info".to_string()
         }
     );
 
-    let &flg = data.get(1).expect("ZLib stream has one byte");
+    let &flg = data.get(1).ok_or(header_error(1, "ZLib stream has one byte"))?;
 
     tokens.push(
         Token {
             bits: vec![flg],
             using_bytes: true,
-            nest_level: 1,
+            start_bit: 8,
+            bit_length: 8,
+            children: vec![],
             data: format!("FLEVEL: {}{} FDICT: {} FCHECK: {}{}{}{}{}", (flg&128)>>7, (flg&64)>>6, (flg&32)>>5, (flg&16)>>4, (flg&8)>>3, (flg&4)>>2, (flg&2)>>1, flg&1), // messy way of making binary string
-            token_type: "FLG".to_string(),
+            kind: TokenKind::Flg { flevel: flg >> 6, fdict: flg & 32 != 0 },
             description: "0-4 are check bits, 5 shows if there is preset dictionary, 6-7 is compression level".to_string()
         }
     );
 
-    // checksum, when cmf and flg are viewed as a 16 bit int, must be multiple of 31
-    assert_eq!((((cmf as u16) << 8) | (flg as u16))%31, 0);
-
-    let fdict = (flg & 32u8) >> 5;
-    let deflate_data_start: usize;
-    match fdict {
-        0 => {
-            deflate_data_start = 2;
-        },
-        1 => {
-            let dictdata: [u8; 4] = data[2..6].try_into().expect("Can't get bytes 2-5 in ZLib stream");
-
-            tokens.push(
-                Token {
-                    bits: vec![dictdata[0], dictdata[1], dictdata[2], dictdata[3]],
-                    using_bytes: true,
-                    nest_level: 1,
-                    data: "DICT".to_string(),
-                    token_type: "DICT".to_string(),
-                    description: "Optional preset dictionary".to_string()
-                }
-            );
-
-            deflate_data_start = 6;
-        },
-        _ => panic!("Incorrect fdict bit")
+    let deflate_data_start = header_length(data)?;
+    if deflate_data_start == 6 {
+        let dictdata: [u8; 4] = data[2..6].try_into().unwrap();
+        tokens.push(
+            Token {
+                bits: dictdata.to_vec(),
+                using_bytes: true,
+                start_bit: 16,
+                bit_length: 32,
+                children: vec![],
+                data: "DICT".to_string(),
+                kind: TokenKind::Dict(u32::from_be_bytes(dictdata)),
+                description: "Optional preset dictionary".to_string()
+            }
+        );
     }
-    let adler32_bytes = data[data.len()-4..].to_vec();
-    let adler32_check = bytes_vec_to_single(&adler32_bytes);
+    check_length(data, deflate_data_start)?;
 
-    let (decompressed_tokens, decompressed) = new_parse_deflate(
-        data[deflate_data_start..(data.len()-4)]
-            .try_into()
-            .expect("Can't get deflate compressed data")
-        );
-    tokens.extend(decompressed_tokens);
+    // deflate tokens and errors are moved along to be relative to the start of the zlib stream
+    let mut deflate_tokens = MovedTokens {
+        sink: tokens,
+        move_token: |token: &mut Token| shift_tokens(std::slice::from_mut(token), deflate_data_start*8)
+    };
+    let decompressed = parse_deflate_into(&data[deflate_data_start..(data.len()-4)], &mut deflate_tokens);
+    let decompressed = decompressed.map_err(|error| error.shifted(deflate_data_start))?;
+
+    let adler32_check = check_adler_32(data, &decompressed)?;
 
     tokens.push(
-        Token { bits: adler32_bytes, using_bytes: true, nest_level: 1, data: adler32_check.to_string(), token_type: "adler_32".to_string(), description: "Adler 32 Check".to_string() }
+        Token {
+            bits: data[data.len()-4..].to_vec(),
+            using_bytes: true,
+            start_bit: (data.len()-4)*8,
+            bit_length: 32,
+            children: vec![],
+            data: format!("expected {}, stored {}", adler32_check, adler32_check),
+            kind: TokenKind::Adler32 { stored: adler32_check, calculated: adler32_check },
+            description: "Adler 32 Check".to_string()
+        }
     );
 
-    (tokens, decompressed)
+    Ok(decompressed)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adler_32_check() {
+        // "hello hello hello"
+        let data = vec![120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 125];
+
+        let (zlib_stream, decompressed) = new_parse_zlib(&data).unwrap();
+
+        assert_eq!(decompressed, b"hello hello hello".to_vec());
+        assert_eq!(zlib_stream.children.last().unwrap().data, "expected 976094845, stored 976094845");
+
+        let children: Vec<&str> = zlib_stream.children.iter().map(|token| token.kind.name()).collect();
+        assert_eq!(children, vec!["CMF", "FLG", "deflate_block", "padding", "adler_32"]);
+        let block: Vec<&str> = zlib_stream.children[2].children.iter().map(|token| token.kind.name()).collect();
+        assert_eq!(block, vec!["bfinal", "btype", "symbols"]);
+        assert_eq!((zlib_stream.start_bit, zlib_stream.bit_length), (0, data.len()*8));
+
+        // every bit of the stream is covered by exactly one token without children, in order
+        let mut next_bit = 0;
+        for token in zlib_stream.flatten().into_iter().filter(|token| !token.is_group() && token.bit_length > 0) {
+            assert_eq!(token.start_bit, next_bit, "{:?}", token);
+            next_bit += token.bit_length;
+        }
+        assert_eq!(next_bit, data.len()*8);
+    }
+
+    #[test]
+    fn test_adler_32_mismatch() {
+        let data = vec![120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 126];
+        assert_eq!(
+            new_parse_zlib(&data).unwrap_err(),
+            DecodeError::ZlibChecksum { byte_offset: 12, stored: 976094846, calculated: 976094845 }
+        );
+    }
+
+    #[test]
+    fn test_header_errors() {
+        assert_eq!(new_parse_zlib(&[]).unwrap_err().stage(), "zlib header");
+
+        // FCHECK changed
+        let error = new_parse_zlib(&[120, 219, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 125]).unwrap_err();
+        assert_eq!(error, DecodeError::ZlibHeader { byte_offset: 1, reason: "FCHECK is incorrect, CMF and FLG are not a multiple of 31".to_string() });
+
+        // truncated in the middle of the deflate stream, offset is from the start of the zlib stream
+        let error = new_parse_zlib(&[120, 218, 203, 72, 205, 201, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(error.stage(), "deflate block");
+        assert!(error.bit_offset() >= 16);
+    }
+
+    #[test]
+    fn test_tokenise_damaged_stream() {
+        // checksum is wrong, every deflate token is kept and the error covers the 4 checksum bytes
+        let zlib_stream = tokenise_zlib(&[120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 126]);
+        let (good_stream, _) = new_parse_zlib(&[120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 125]).unwrap();
+        assert_eq!(zlib_stream.children.len(), good_stream.children.len());
+        let error = zlib_stream.children.last().unwrap();
+        assert!(matches!(error.kind, TokenKind::Error(DecodeError::ZlibChecksum { .. })));
+        assert_eq!(error.bits, vec![58, 46, 6, 126]);
+        assert!(error.using_bytes);
+
+        // truncated, tokens before the break are kept
+        let zlib_stream = tokenise_zlib(&[120, 218, 203, 72, 205, 201, 0, 0, 0, 0]);
+        assert_eq!(zlib_stream.children[0].kind, TokenKind::Cmf { cm: 8, cinfo: 7 });
+        assert_eq!(zlib_stream.children[2].kind, TokenKind::DeflateBlock { number: 1 });
+        assert!(matches!(zlib_stream.children[2].children[0].kind, TokenKind::BlockFinal(_)));
+        assert!(zlib_stream.flatten().iter().any(|token| matches!(token.kind, TokenKind::Literal(_))));
+        assert_eq!(zlib_stream.children.last().unwrap().kind.name(), "error");
+    }
+
+    #[test]
+    fn test_decode_without_tokens() {
+        let streams: [&[u8]; 6] = [
+            &[120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 125],
+            &[120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 126],
+            &[120, 219, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 58, 46, 6, 125],
+            &[120, 218, 203, 72, 205, 201, 0, 0, 0, 0],
+            &[120, 218, 0, 0],
+            &[120, 94, 0],
+        ];
+        for data in streams {
+            let parsed = new_parse_zlib(data).map(|(_tokens, decompressed)| decompressed);
+            match (parsed, decode_zlib(data)) {
+                (Ok(parsed), Ok(decoded)) => assert_eq!(parsed, decoded),
+                (parsed, decoded) => assert_eq!(parsed.unwrap_err().stage(), decoded.unwrap_err().stage()),
+            }
+        }
+        assert_eq!(decode_zlib(streams[0]).unwrap(), b"hello hello hello");
+        assert_eq!(decode_zlib(streams[1]), new_parse_zlib(streams[1]).map(|(_tokens, decompressed)| decompressed));
+    }
 }